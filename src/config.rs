@@ -0,0 +1,269 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use log::LevelFilter;
+use serde_derive::Deserialize;
+
+use crate::core::transaction::DEFAULT_CHAIN_ID;
+use crate::crypto::keystore::{self, KeystoreError};
+use crate::network::rpc::default_rpc_decode_func;
+use crate::network::server::{ServerOpts, DEFAULT_OUTBOUND_PEERS};
+use crate::network::tcp_transport::TcpTransport;
+use crate::types::address::Address;
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse(toml::de::Error),
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "could not read {}: {}", path.display(), e),
+            ConfigError::Parse(e) => write!(f, "invalid config: {}", e),
+            ConfigError::Invalid(e) => write!(f, "invalid config: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Settings of a node, read from a TOML file. Relative paths are taken
+/// relative to the directory of the file.
+#[derive(Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct NodeConfig {
+    // Address the peer-to-peer transport listens on.
+    pub listen_addr: String,
+    // Address other nodes reach us at, which identifies the node to its
+    // peers. Defaults to `listen_addr` and is required if that is a wildcard
+    // address like 0.0.0.0.
+    pub advertise_addr: Option<String>,
+    // Peers dialed at start.
+    #[serde(default)]
+    pub seeds: Vec<String>,
+    // Blocks are only kept in memory without a data directory.
+    pub data_dir: Option<PathBuf>,
+    // Keystore or PEM file with the validator key. Nodes without one follow
+    // the chain but do not create blocks.
+    pub key_file: Option<PathBuf>,
+    // File holding the keystore password, asked for at start without one.
+    pub password_file: Option<PathBuf>,
+    // Seconds between blocks.
+    #[serde(default = "default_block_time")]
+    pub block_time: u64,
+    #[serde(default = "default_log_level")]
+    pub log_level: String,
+    #[serde(default = "default_chain_id")]
+    pub chain_id: u32,
+    #[serde(default = "default_outbound_peers")]
+    pub outbound_peers: usize,
+    pub api_addr: Option<String>,
+    pub ws_addr: Option<String>,
+    // Hex addresses and the balances the genesis block mints for them. It
+    // has to be the same on every node of the chain.
+    #[serde(default)]
+    pub genesis_alloc: BTreeMap<String, u64>,
+}
+
+fn default_block_time() -> u64 {
+    5
+}
+
+fn default_log_level() -> String {
+    "info".to_owned()
+}
+
+fn default_chain_id() -> u32 {
+    DEFAULT_CHAIN_ID
+}
+
+fn default_outbound_peers() -> usize {
+    DEFAULT_OUTBOUND_PEERS
+}
+
+impl FromStr for NodeConfig {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let config: NodeConfig = toml::from_str(s).map_err(ConfigError::Parse)?;
+        config.log_level()?;
+        let wildcard = matches!(config.listen_addr.parse::<SocketAddr>(), Ok(addr) if addr.ip().is_unspecified());
+        if wildcard && config.advertise_addr.is_none() {
+            return Err(ConfigError::Invalid(format!(
+                "listen_addr {} is not an address peers can reach, set advertise_addr",
+                config.listen_addr
+            )));
+        }
+        if config.block_time == 0 {
+            return Err(ConfigError::Invalid("block_time must be at least one second".to_owned()));
+        }
+        config.genesis_alloc()?;
+        Ok(config)
+    }
+}
+
+impl NodeConfig {
+    pub fn load(path: &Path) -> Result<NodeConfig, ConfigError> {
+        let text = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_owned(), e))?;
+        let mut config: NodeConfig = text.parse()?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        config.data_dir = config.data_dir.map(|data_dir| dir.join(data_dir));
+        config.key_file = config.key_file.map(|key_file| dir.join(key_file));
+        config.password_file = config.password_file.map(|password_file| dir.join(password_file));
+        Ok(config)
+    }
+
+    pub fn log_level(&self) -> Result<LevelFilter, ConfigError> {
+        self.log_level
+            .parse()
+            .map_err(|_| ConfigError::Invalid(format!("unknown log level {}", self.log_level)))
+    }
+
+    /// Genesis balances by address. The total supply has to fit into a
+    /// balance.
+    pub fn genesis_alloc(&self) -> Result<BTreeMap<Address, u64>, ConfigError> {
+        let mut supply: u64 = 0;
+        let mut alloc = BTreeMap::new();
+        for (address, amount) in &self.genesis_alloc {
+            let invalid = |e: String| ConfigError::Invalid(format!("genesis_alloc address {}: {}", address, e));
+            let bytes = hex::decode(address.trim_start_matches("0x")).map_err(|e| invalid(e.to_string()))?;
+            alloc.insert(Address::from_bytes(&bytes).map_err(invalid)?, *amount);
+            supply = supply
+                .checked_add(*amount)
+                .ok_or_else(|| ConfigError::Invalid("genesis_alloc total supply overflows".to_owned()))?;
+        }
+        Ok(alloc)
+    }
+
+    /// Options for a server with these settings. Reads the validator key and
+    /// starts listening for peers.
+    pub fn server_opts(&self) -> Result<ServerOpts, ConfigError> {
+        let key = match &self.key_file {
+            Some(path) => {
+                let prompt = format!("Password for {}: ", path.display());
+                let key = keystore::read_key(path, || keystore::read_password(self.password_file.as_deref(), &prompt));
+                Some(key.map_err(|e| match e {
+                    KeystoreError::Io(e) => ConfigError::Io(path.clone(), e),
+                    e => ConfigError::Invalid(format!("{}: {}", path.display(), e)),
+                })?)
+            }
+            None => None,
+        };
+        let transport = match &self.advertise_addr {
+            Some(advertise) => TcpTransport::listen_as(&self.listen_addr, advertise),
+            None => TcpTransport::listen(&self.listen_addr),
+        }
+            .map_err(|e| ConfigError::Invalid(format!("could not listen on {}: {}", self.listen_addr, e)))?;
+        Ok(ServerOpts {
+            transports: vec![Box::new(transport)],
+            block_time: Some(Duration::from_secs(self.block_time)),
+            key,
+            rpc_decode_func: default_rpc_decode_func,
+            data_dir: self.data_dir.clone(),
+            chain_id: self.chain_id,
+            bootstrap: self.seeds.clone(),
+            outbound_peers: self.outbound_peers,
+            api_addr: self.api_addr.clone(),
+            ws_addr: self.ws_addr.clone(),
+            genesis_alloc: self.genesis_alloc()?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::keypair::PrivateKey;
+    use crate::crypto::keystore::{Keystore, TEST_LOG_N};
+    use crate::types::hash::Hash;
+
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config: NodeConfig = r#"
+            listen_addr = "0.0.0.0:3000"
+            advertise_addr = "203.0.113.5:3000"
+            seeds = ["10.0.0.1:3000", "10.0.0.2:3000"]
+            data_dir = "data"
+            key_file = "validator.pem"
+            block_time = 2
+            log_level = "debug"
+            api_addr = "127.0.0.1:8545"
+
+            [genesis_alloc]
+            "0x0101010101010101010101010101010101010101" = 1000
+            "0202020202020202020202020202020202020202" = 500
+        "#.parse().unwrap();
+        assert_eq!(config.seeds.len(), 2);
+        let alloc = config.genesis_alloc().unwrap();
+        assert_eq!(alloc.get(&Address::from_bytes(&[1; 20]).unwrap()), Some(&1000));
+        assert_eq!(alloc.values().sum::<u64>(), 1500);
+        assert_eq!(config.block_time, 2);
+        assert_eq!(config.log_level().unwrap(), LevelFilter::Debug);
+        assert_eq!(config.chain_id, DEFAULT_CHAIN_ID);
+        assert_eq!(config.ws_addr, None);
+
+        let minimal: NodeConfig = r#"listen_addr = "10.0.0.1:3000""#.parse().unwrap();
+        assert_eq!(minimal.block_time, 5);
+        assert!(minimal.genesis_alloc().unwrap().is_empty());
+        assert_eq!(minimal.key_file, None);
+        assert_eq!(minimal.advertise_addr, None);
+
+        // Peers could not tell nodes listening on a wildcard address apart.
+        let wildcard = r#"listen_addr = "0.0.0.0:3000""#;
+        assert!(matches!(wildcard.parse::<NodeConfig>(), Err(ConfigError::Invalid(_))));
+
+        assert!(matches!("".parse::<NodeConfig>(), Err(ConfigError::Parse(_))));
+        let typo = "listen_addr = \"0.0.0.0:3000\"\nseed = []";
+        assert!(matches!(typo.parse::<NodeConfig>(), Err(ConfigError::Parse(_))));
+        let level = "listen_addr = \"0.0.0.0:3000\"\nlog_level = \"loud\"";
+        assert!(matches!(level.parse::<NodeConfig>(), Err(ConfigError::Invalid(_))));
+        let short = "listen_addr = \"10.0.0.1:3000\"\n[genesis_alloc]\n\"0x0101\" = 1";
+        assert!(matches!(short.parse::<NodeConfig>(), Err(ConfigError::Invalid(_))));
+        let overflow: String = (1..=3u8).map(|i| format!("\"{}\" = {}\n", hex::encode([i; 20]), i64::MAX)).collect();
+        let overflow = format!("listen_addr = \"10.0.0.1:3000\"\n[genesis_alloc]\n{}", overflow);
+        assert!(matches!(overflow.parse::<NodeConfig>(), Err(ConfigError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_server_opts() {
+        let dir = std::env::temp_dir().join(format!("rustchain-config-{}", Hash::random()));
+        fs::create_dir_all(&dir).unwrap();
+        let key = PrivateKey::generate_key();
+        fs::write(dir.join("validator.pem"), key.to_pem()).unwrap();
+        let path = dir.join("node.toml");
+        fs::write(&path, "listen_addr = \"127.0.0.1:0\"\nseeds = [\"127.0.0.1:1\"]\nkey_file = \"validator.pem\"\ndata_dir = \"data\"\n").unwrap();
+
+        let config = NodeConfig::load(&path).unwrap();
+        assert_eq!(config.data_dir, Some(dir.join("data")));
+        let opts = config.server_opts().unwrap();
+        assert_eq!(opts.key, Some(key));
+        assert_eq!(opts.bootstrap, vec!["127.0.0.1:1".to_owned()]);
+        assert_eq!(opts.block_time, Some(Duration::from_secs(5)));
+        assert_eq!(opts.transports.len(), 1);
+
+        let advertised: NodeConfig = "listen_addr = \"127.0.0.1:0\"\nadvertise_addr = \"node.example:3000\"".parse().unwrap();
+        assert_eq!(advertised.server_opts().unwrap().transports[0].addr(), "node.example:3000");
+
+        fs::write(dir.join("validator.pem"), "garbage").unwrap();
+        assert!(matches!(config.server_opts(), Err(ConfigError::Invalid(_))));
+
+        let encrypted = PrivateKey::generate_key();
+        Keystore::encrypt_with_cost(&encrypted, "secret", TEST_LOG_N).unwrap().save(&dir.join("validator.json")).unwrap();
+        fs::write(dir.join("password"), "secret\n").unwrap();
+        fs::write(&path, "listen_addr = \"127.0.0.1:0\"\nkey_file = \"validator.json\"\npassword_file = \"password\"\n").unwrap();
+        assert_eq!(NodeConfig::load(&path).unwrap().server_opts().unwrap().key, Some(encrypted));
+        fs::write(dir.join("password"), "wrong").unwrap();
+        assert!(matches!(NodeConfig::load(&path).unwrap().server_opts(), Err(ConfigError::Invalid(_))));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,7 +1,12 @@
 pub mod block;
 pub mod transaction;
 pub mod encoding;
+pub mod events;
 pub mod hasher;
 pub mod storage;
 pub mod validator;
-pub mod blockchain;
\ No newline at end of file
+pub mod blockchain;
+pub mod merkle;
+pub mod forkchoice;
+pub mod state;
+pub mod vm;
@@ -5,9 +5,10 @@ use p256::ecdsa::Signature;
 use serde_derive::{Serialize, Deserialize};
 use sha2::{Sha256, Digest};
 use crate::core::encoding::Encode;
+use crate::error::Error;
 use crate::{types::hash::Hash, crypto::keypair::{PublicKey, PrivateKey}};
 
-use super::{transaction::{Transaction}, encoding::{Encoder}, hasher::{Hasher, Bytes}};
+use super::{transaction::{Transaction}, encoding::{Encoder}, hasher::{Hasher, Bytes}, merkle::{MerkleTree, MerkleProof}};
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
 pub struct Header {
@@ -19,34 +20,46 @@ pub struct Header {
 }
 
 impl Bytes for Header {
-    fn as_bytes(&self) -> Vec<u8> {
+    fn as_bytes(&self) -> Result<Vec<u8>, Error> {
         let mut writer = Cursor::new(vec![]);
         let mut encoder = Encoder::new(&mut writer);
 
-        encoder.encode(self);
+        encoder.encode(self)?;
         writer.set_position(0);
-        writer.into_inner()
+        Ok(writer.into_inner())
     }
 }
 
 
-#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Block {
     pub header: Header,
     pub transactions: Vec<Transaction>,
     pub signature: Option<Signature>,
     pub validator: Option<PublicKey>,
-    pub hash: Option<Hash>, // Cached version of the header hash
-    pub prev_hash: Option<Hash>,
+    // Local cache of the header hash, not part of the encoded block so a
+    // peer cannot claim a hash the header does not have.
+    #[serde(skip)]
+    pub hash: Option<Hash>,
+}
+
+// Blocks are equal whether or not their hashes are cached yet.
+impl PartialEq for Block {
+    fn eq(&self, other: &Self) -> bool {
+        self.header == other.header
+            && self.transactions == other.transactions
+            && self.signature == other.signature
+            && self.validator == other.validator
+    }
 }
 
 impl Bytes for Block {
-    fn as_bytes(&self) -> Vec<u8> {
+    fn as_bytes(&self) -> Result<Vec<u8>, Error> {
         let mut writer = vec![];
         let mut encoder = Encoder::new(&mut writer);
 
-        encoder.encode(self);
-        writer
+        encoder.encode(self)?;
+        Ok(writer)
     }
 }
 
@@ -58,22 +71,55 @@ impl Block {
             hash: None,
             signature: None,
             validator: None,
-            prev_hash: None,
         }
     }
 
+    // Builds an unsigned block on top of `prev`, committing to `transactions`
+    // in the header data.
+    pub fn from_prev_header(prev: &Header, transactions: Vec<Transaction>) -> Block {
+        let header = Header {
+            version: 1,
+            data: Block::data_hash(&transactions),
+            prev_block: Hasher::new().hash(prev).expect("could not hash"),
+            timestamp: Utc::now().timestamp_nanos(),
+            height: prev.height + 1,
+        };
+        Block::new(header, transactions)
+    }
+
     pub fn add_transaction(&mut self, t: &Transaction) -> Result<(), ()> {
         self.transactions.push(t.clone());
+        self.header.data = Block::data_hash(&self.transactions);
+        self.hash = None;
         Ok(())
     }
 
+    // Merkle root over the hashes of `transactions`.
+    pub fn data_hash(transactions: &[Transaction]) -> Hash {
+        Self::merkle_tree(transactions).root()
+    }
+
+    fn merkle_tree(transactions: &[Transaction]) -> MerkleTree {
+        let hasher = Hasher::new();
+        let leaves: Vec<Hash> = transactions
+            .iter()
+            .map(|tx| hasher.hash(tx).expect("could not hash"))
+            .collect();
+        MerkleTree::new(&leaves)
+    }
+
+    // Proof that the transaction at `index` is committed in `header.data`.
+    pub fn transaction_proof(&self, index: usize) -> Option<MerkleProof> {
+        Self::merkle_tree(&self.transactions).proof(index)
+    }
+
 
     pub fn random_block(h: u32) -> Self {
         let header = Header {
             version: 1,
-            data: Hash::random(),
+            data: Block::data_hash(&[]),
             prev_block: Hash::random(),
-            timestamp: Utc::now().timestamp(),
+            timestamp: Utc::now().timestamp_nanos(),
             height: h,
         };
 
@@ -87,6 +133,14 @@ impl Block {
     
         b
     }
+
+    // Signed random block that extends `parent`.
+    pub fn random_block_with_parent(parent: &Header) -> Self {
+        let mut b = Self::random_block(parent.height + 1);
+        b.header.prev_block = Hasher::new().hash(parent).expect("could not hash");
+        assert!(b.sign(PrivateKey::generate_key()).is_ok());
+        b
+    }
     
 
     pub fn hash(&mut self, hasher: Hasher) -> Hash {
@@ -104,7 +158,7 @@ impl Block {
     // }
 
     pub fn sign(&mut self, key: PrivateKey) -> Result<(), String> {
-        let header = self.header.as_bytes();
+        let header = self.header.as_bytes().map_err(|e| e.to_string())?;
         self.signature = Some(key.sign(&header).expect("could not sign"));
         self.validator = Some(key.generate_public());
         Ok(())
@@ -115,15 +169,21 @@ impl Block {
             return Err("no signature".to_string());
         }
 
-        let validator = self.validator.as_ref().unwrap();
+        let validator = match self.validator.as_ref() {
+            Some(v) => v,
+            None => return Err("no validator".to_string()),
+        };
         let signature = self.signature.unwrap();
-        let res = validator.verify(&self.header.as_bytes(), &signature);
+        let header = self.header.as_bytes().map_err(|e| e.to_string())?;
+        let res = validator.verify(&header, &signature);
         if res.is_err() {
             return Err("Could not verify".to_owned());
         }
 
         for t in &self.transactions {
-            assert!(t.verify().is_ok());
+            if t.verify().is_err() {
+                return Err("could not verify transaction".to_owned());
+            }
         }
         Ok(())
     }
@@ -198,6 +258,7 @@ mod test {
     
 
     use crate::{crypto::{keypair::PrivateKey}};
+    use crate::core::{hasher::Hasher, transaction::Transaction};
 
     use super::{Block};
 
@@ -212,6 +273,25 @@ mod test {
         assert!(b.signature.is_some());
     }
 
+    #[test]
+    fn test_transaction_proof() {
+        let key = PrivateKey::generate_key();
+        let mut b = Block::random_block(0);
+        for i in 0..5u8 {
+            let mut tx = Transaction::new(vec![i]).unwrap();
+            assert!(tx.sign(&key).is_ok());
+            assert!(b.add_transaction(&tx).is_ok());
+        }
+        assert_eq!(b.header.data, Block::data_hash(&b.transactions));
+
+        for (i, tx) in b.transactions.iter().enumerate() {
+            let leaf = Hasher::new().hash(tx).unwrap();
+            let proof = b.transaction_proof(i).unwrap();
+            assert!(proof.verify(&b.header.data, &leaf));
+        }
+        assert!(b.transaction_proof(5).is_none());
+    }
+
     #[test]
     fn test_verify_block() {
         let key = PrivateKey::generate_key();
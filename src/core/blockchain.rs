@@ -1,10 +1,71 @@
 
 
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::{RwLock, Arc};
 
 use crate::core::hasher::Hasher;
+use crate::types::{address::Address, hash::Hash};
 
-use super::{storage::{Storage, MemoryStore}, block::{Header, Block}, validator::{Validator, BlockValidator}};
+use super::{events::{ChainEvent, EventBus}, storage::{Storage, MemoryStore, StorageError}, block::{Header, Block}, validator::{Validator, BlockValidator, ValidationError}, forkchoice::ForkChoice, state::{Account, State, StateError}, transaction::{Transaction, DEFAULT_CHAIN_ID}};
+
+#[derive(Debug)]
+pub enum BlockchainError {
+    Validation(ValidationError),
+    Storage(StorageError),
+    State(StateError),
+}
+
+impl fmt::Display for BlockchainError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockchainError::Validation(e) => write!(f, "block rejected: {}", e),
+            BlockchainError::Storage(e) => write!(f, "{}", e),
+            BlockchainError::State(e) => write!(f, "block rejected: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for BlockchainError {}
+
+impl From<ValidationError> for BlockchainError {
+    fn from(e: ValidationError) -> Self {
+        BlockchainError::Validation(e)
+    }
+}
+
+impl From<StorageError> for BlockchainError {
+    fn from(e: StorageError) -> Self {
+        BlockchainError::Storage(e)
+    }
+}
+
+impl From<StateError> for BlockchainError {
+    fn from(e: StateError) -> Self {
+        BlockchainError::State(e)
+    }
+}
+
+// Side blocks kept in memory at most. Beyond that the lowest ones are
+// dropped, together with the blocks built on them.
+pub const MAX_SIDE_BLOCKS: usize = 1024;
+// The state is snapshotted every this many canonical blocks, and the most
+// recent snapshots are kept, so forks are replayed from a nearby snapshot
+// rather than from genesis.
+pub const SNAPSHOT_INTERVAL: u32 = 64;
+pub const MAX_SNAPSHOTS: usize = 16;
+
+/// Sent to subscribers when the canonical chain switches branches.
+#[derive(Clone, Debug)]
+pub struct Reorg {
+    // Height of the last block both branches share.
+    pub common_ancestor: u32,
+    // Blocks rolled back from the old branch, in ascending height order.
+    pub dropped: Vec<Block>,
+    // Blocks applied from the new branch, in ascending height order.
+    pub added: Vec<Block>,
+}
 
 pub struct Blockchain {
     data: Arc<RwLock<BlockchainData>>
@@ -12,47 +73,444 @@ pub struct Blockchain {
 
 pub struct BlockchainData {
     store: Box<dyn Storage>,
+    // Canonical chain, indexed by height.
     headers: Vec<Header>,
-    validator:Box<dyn Validator>,
+    hashes: HashMap<Hash, u32>,
+    // Height of the canonical block holding each transaction.
+    txs: HashMap<Hash, u32>,
+    // Valid blocks that are not on the canonical chain. They are only kept
+    // in memory.
+    side: HashMap<Hash, Block>,
+    max_side_blocks: usize,
+    // State defined by the genesis block and state at the canonical tip.
+    genesis_state: State,
+    state: State,
+    // States after canonical blocks at multiples of `snapshot_interval`.
+    snapshots: BTreeMap<u32, State>,
+    snapshot_interval: u32,
+    validator: Arc<dyn Validator>,
+    fork_choice: ForkChoice,
+    // Transactions signed for another chain are rejected.
+    chain_id: u32,
+    reorg_subs: Vec<Sender<Reorg>>,
+    events: EventBus,
+}
+
+impl BlockchainData {
+    fn tip_hash(&self) -> Hash {
+        Hasher::new().hash(self.headers.last().unwrap()).expect("could not hash")
+    }
+
+    fn append(&mut self, b: &mut Block) -> Result<(), StorageError> {
+        let height = b.header.height;
+        let hash = Hasher::new().hash(&b.header).expect("could not hash");
+        log::info!("Adding block - height: {}, hash: {}", height, hash);
+
+        self.store.put(b)?;
+        self.hashes.insert(hash, height);
+        for tx in b.transactions.iter_mut() {
+            self.txs.insert(tx.hash(Hasher::new()), height);
+        }
+        self.headers.push(b.header);
+        Ok(())
+    }
+
+    // Walks back from the side block `hash` to the canonical chain and
+    // returns the common ancestor height together with the side branch.
+    fn side_branch(&self, hash: &Hash) -> Result<(u32, Vec<Block>), BlockchainError> {
+        let mut branch = vec![];
+        let mut next = *hash;
+        while let Some(b) = self.side.get(&next) {
+            next = b.header.prev_block;
+            branch.push(b.clone());
+        }
+        branch.reverse();
+        let ancestor = *self.hashes.get(&next).ok_or(ValidationError::UnknownParent(next))?;
+        Ok((ancestor, branch))
+    }
+
+    // Keeps `state` as the state after the canonical block at `height` if a
+    // snapshot is due there, dropping the oldest beyond `MAX_SNAPSHOTS`.
+    fn snapshot(&mut self, height: u32, state: &State) {
+        if height == 0 || !height.is_multiple_of(self.snapshot_interval) {
+            return;
+        }
+        self.snapshots.insert(height, state.clone());
+        while self.snapshots.len() > MAX_SNAPSHOTS {
+            self.snapshots.pop_first();
+        }
+    }
+
+    fn set_state(&mut self, state: State) {
+        let tip = self.headers.len() as u32 - 1;
+        self.snapshot(tip, &state);
+        self.state = state;
+    }
+
+    // State after the canonical block at `height`, replayed from the closest
+    // snapshot below it, or from genesis if there is none.
+    fn state_at(&self, height: u32) -> Result<State, BlockchainError> {
+        if height == self.headers.len() as u32 - 1 {
+            return Ok(self.state.clone());
+        }
+        let (from, mut state) = match self.snapshots.range(..=height).next_back() {
+            Some((from, state)) => (*from, state.clone()),
+            None => (0, self.genesis_state.clone()),
+        };
+        if height > from {
+            for b in self.store.blocks(from + 1, height) {
+                state.apply_block(&b?)?;
+            }
+        }
+        Ok(state)
+    }
+
+    // State after applying `branch` on top of the canonical block at
+    // `ancestor`, and the snapshots due along the branch.
+    fn branch_state(&self, ancestor: u32, branch: &[Block]) -> Result<(State, Vec<(u32, State)>), BlockchainError> {
+        let mut state = self.state_at(ancestor)?;
+        let mut snapshots = vec![];
+        for b in branch {
+            state.apply_block(b)?;
+            if b.header.height.is_multiple_of(self.snapshot_interval) {
+                snapshots.push((b.header.height, state.clone()));
+            }
+        }
+        Ok((state, snapshots))
+    }
+
+    fn reorg(&mut self, ancestor: u32, branch: Vec<Block>) -> Result<Reorg, StorageError> {
+        let tip = self.headers.len() as u32 - 1;
+        let dropped = self.store.blocks(ancestor + 1, tip).collect::<Result<Vec<Block>, StorageError>>()?;
+        log::warn!(
+            "Reorganising chain - ancestor: {}, dropped: {}, added: {}",
+            ancestor, dropped.len(), branch.len()
+        );
+
+        self.store.truncate(ancestor)?;
+        self.headers.truncate(ancestor as usize + 1);
+        self.snapshots.split_off(&(ancestor + 1));
+        for mut b in dropped.iter().cloned() {
+            let hash = b.hash(Hasher::new());
+            self.hashes.remove(&hash);
+            for tx in b.transactions.iter_mut() {
+                self.txs.remove(&tx.hash(Hasher::new()));
+            }
+            self.side.insert(hash, b);
+        }
+
+        let mut added = vec![];
+        for mut b in branch {
+            let hash = b.hash(Hasher::new());
+            self.side.remove(&hash);
+            self.append(&mut b)?;
+            added.push(b);
+        }
+
+        Ok(Reorg { common_ancestor: ancestor, dropped, added })
+    }
+
+    fn notify(&mut self, reorg: &Reorg) {
+        self.reorg_subs.retain(|sub| sub.send(reorg.clone()).is_ok());
+        self.events.publish(ChainEvent::Reorg(reorg.clone()));
+        for b in &reorg.added {
+            self.events.publish(ChainEvent::NewHead(b.header));
+        }
+    }
+
+    // Drops side blocks at or below the finalized height, and the lowest
+    // ones if there are more than `max_side_blocks`. Blocks that no longer
+    // connect to the canonical chain go with them.
+    fn prune_side(&mut self) {
+        let tip = self.headers.len() as u32 - 1;
+        let mut floor = self.fork_choice.finalized_height(tip);
+        if self.side.len() > self.max_side_blocks {
+            let mut heights: Vec<u32> = self.side.values().map(|b| b.header.height).collect();
+            heights.sort_unstable();
+            floor = floor.max(heights[heights.len() - self.max_side_blocks - 1]);
+        }
+        self.side.retain(|_, b| b.header.height > floor);
+
+        loop {
+            let detached: Vec<Hash> = self
+                .side
+                .iter()
+                .filter(|(_, b)| {
+                    let parent = &b.header.prev_block;
+                    !self.hashes.contains_key(parent) && !self.side.contains_key(parent)
+                })
+                .map(|(hash, _)| *hash)
+                .collect();
+            if detached.is_empty() {
+                break;
+            }
+            for hash in detached {
+                self.side.remove(&hash);
+            }
+        }
+    }
 }
 
 impl Blockchain {
     pub fn new(genesis: &mut Block) -> Result<Blockchain, ()> {
-            let mut blockchain = Blockchain{
-                data: Arc::new(RwLock::new(BlockchainData { 
-                store: Box::new(MemoryStore::new()), 
-                headers: vec![], 
-                validator: Box::new(BlockValidator::new_validator()),
-                }))
-            };
-            // blockchain.set_validator(validator);
-            assert!(blockchain.add_block_without_validation(genesis).is_ok());
-            Ok(blockchain)
-        
+        Blockchain::new_with_store(Box::new(MemoryStore::new()), genesis).map_err(|e| {
+            log::error!("could not create blockchain: {}", e);
+        })
+    }
+
+    /// Opens a chain on top of `store`. An empty store is initialised with
+    /// `genesis`, otherwise the headers it holds are loaded and the chain
+    /// continues at the last stored height.
+    pub fn new_with_store(store: Box<dyn Storage>, genesis: &mut Block) -> Result<Blockchain, StorageError> {
+        let corrupt = |e: StateError| StorageError::Corrupt(e.to_string());
+        let genesis_state = State::genesis(genesis).map_err(corrupt)?;
+        let mut state = genesis_state.clone();
+        let mut headers = vec![];
+        let mut hashes = HashMap::new();
+        let mut txs = HashMap::new();
+        let mut snapshots = BTreeMap::new();
+        if let Some(height) = store.height() {
+            for b in store.blocks(0, height) {
+                let mut b = b?;
+                if b.header.height > 0 {
+                    state.apply_block(&b).map_err(corrupt)?;
+                    if b.header.height.is_multiple_of(SNAPSHOT_INTERVAL) {
+                        snapshots.insert(b.header.height, state.clone());
+                        if snapshots.len() > MAX_SNAPSHOTS {
+                            snapshots.pop_first();
+                        }
+                    }
+                }
+                for tx in b.transactions.iter_mut() {
+                    txs.insert(tx.hash(Hasher::new()), b.header.height);
+                }
+                let header = b.header;
+                hashes.insert(Hasher::new().hash(&header)?, header.height);
+                headers.push(header);
+            }
+            if headers[0] != genesis.header {
+                return Err(StorageError::Corrupt("stored genesis block does not match".to_owned()));
+            }
+            log::info!("Loaded blockchain from storage - height: {}", height);
+        }
+
+        let empty = headers.is_empty();
+        let blockchain = Blockchain {
+            data: Arc::new(RwLock::new(BlockchainData {
+                store,
+                headers,
+                hashes,
+                txs,
+                side: HashMap::new(),
+                max_side_blocks: MAX_SIDE_BLOCKS,
+                genesis_state,
+                state,
+                snapshots,
+                snapshot_interval: SNAPSHOT_INTERVAL,
+                validator: Arc::new(BlockValidator::new_validator()),
+                fork_choice: ForkChoice::default(),
+                chain_id: DEFAULT_CHAIN_ID,
+                reorg_subs: vec![],
+                events: EventBus::new(),
+            }))
+        };
+        if empty {
+            let mut bc = blockchain.data.write().unwrap();
+            bc.store.put(genesis)?;
+            bc.hashes.insert(genesis.hash(Hasher::new()), 0);
+            for tx in genesis.transactions.iter_mut() {
+                bc.txs.insert(tx.hash(Hasher::new()), 0);
+            }
+            bc.headers.push(genesis.header);
+        }
+        Ok(blockchain)
     }
 
     pub fn set_validator(&mut self, v: Box<dyn Validator>) {
         let mut bc = self.data.write().unwrap();
-        bc.validator = v
+        bc.validator = v.into()
     }
 
-    pub fn add_block(&mut self, b: &mut Block) -> Result<(), ()> {
+    pub fn set_fork_choice(&mut self, fork_choice: ForkChoice) {
+        let mut bc = self.data.write().unwrap();
+        bc.fork_choice = fork_choice;
+    }
+
+    // Lets tests reach the side block cap without building a thousand blocks.
+    #[cfg(test)]
+    pub fn set_max_side_blocks(&mut self, max: usize) {
+        let mut bc = self.data.write().unwrap();
+        bc.max_side_blocks = max;
+    }
+
+    // Lets tests cross several snapshots with a short chain.
+    #[cfg(test)]
+    pub fn set_snapshot_interval(&mut self, interval: u32) {
+        let mut bc = self.data.write().unwrap();
+        bc.snapshot_interval = interval;
+    }
+
+    pub fn set_chain_id(&mut self, chain_id: u32) {
+        let mut bc = self.data.write().unwrap();
+        bc.chain_id = chain_id;
+    }
+
+    pub fn chain_id(&self) -> u32 {
         let bc = self.data.read().unwrap();
-        assert!(bc.validator.as_ref().validate_block(self, b).is_ok());
-        std::mem::drop(bc);
-        self.add_block_without_validation(b)
+        bc.chain_id
+    }
+
+    /// Receives a `Reorg` every time the canonical chain switches branches.
+    pub fn subscribe_reorgs(&self) -> Receiver<Reorg> {
+        let (send, recv) = mpsc::channel();
+        self.data.write().unwrap().reorg_subs.push(send);
+        recv
     }
 
-    pub fn get_header(&self, h: u32) -> Header {
-        assert!(self.height() <= h);
+    /// Bus the chain publishes new heads and reorgs to.
+    pub fn events(&self) -> EventBus {
+        self.data.read().unwrap().events.clone()
+    }
+
+    /// Validates `b` and adds it to the block tree. A block that does not
+    /// extend the tip is kept on a side branch, which becomes canonical if the
+    /// fork choice rule prefers it over the current chain.
+    pub fn add_block(&mut self, b: &mut Block) -> Result<(), BlockchainError> {
+        // The validator reads the chain itself, so the lock must not be held
+        // while it runs.
+        let validator = self.data.read().unwrap().validator.clone();
+        validator.validate_block(self, b)?;
+
+        let mut bc = self.data.write().unwrap();
+        if b.header.prev_block == bc.tip_hash() {
+            let mut state = bc.state.clone();
+            state.apply_block(b)?;
+            bc.append(b)?;
+            bc.set_state(state);
+            bc.prune_side();
+            bc.events.publish(ChainEvent::NewHead(b.header));
+            return Ok(());
+        }
+
+        let hash = b.hash(Hasher::new());
+        log::info!("Adding side block - height: {}, hash: {}", b.header.height, hash);
+        bc.side.insert(hash, b.clone());
+
+        let (ancestor, branch) = match bc.side_branch(&hash) {
+            Ok(found) => found,
+            Err(e) => {
+                bc.side.remove(&hash);
+                return Err(e);
+            }
+        };
+        let tip = bc.headers.len() as u32 - 1;
+        let current = bc.store.blocks(ancestor + 1, tip).collect::<Result<Vec<Block>, StorageError>>()?;
+        if bc.fork_choice.prefers(ancestor, &current, &branch) {
+            // A branch that does not apply cleanly never becomes canonical.
+            let (state, snapshots) = match bc.branch_state(ancestor, &branch) {
+                Ok(found) => found,
+                Err(e) => {
+                    bc.side.remove(&hash);
+                    return Err(e);
+                }
+            };
+            let reorg = bc.reorg(ancestor, branch)?;
+            for (height, snapshot) in snapshots {
+                bc.snapshot(height, &snapshot);
+            }
+            bc.set_state(state);
+            bc.notify(&reorg);
+        }
+        bc.prune_side();
+        Ok(())
+    }
+
+    // Header of any known block, whether it is on the canonical chain or on
+    // a side branch.
+    pub fn lookup_header(&self, hash: &Hash) -> Option<Header> {
+        let bc = self.data.read().unwrap();
+        match bc.hashes.get(hash) {
+            Some(h) => bc.headers.get(*h as usize).cloned(),
+            None => bc.side.get(hash).map(|b| b.header),
+        }
+    }
+
+    pub fn get_account(&self, address: &Address) -> Account {
         let bc = self.data.read().unwrap();
-        let header = bc.headers.get(h as usize).cloned();
-        header.unwrap()
+        bc.state.get_account(address)
+    }
+
+    pub fn get_storage(&self, contract: &Address, key: i64) -> i64 {
+        let bc = self.data.read().unwrap();
+        bc.state.get_storage(contract, key)
+    }
+
+    // Copy of the state at the canonical tip.
+    pub fn state(&self) -> State {
+        let bc = self.data.read().unwrap();
+        bc.state.clone()
+    }
+
+    // Writes pending blocks of the store to disk.
+    pub fn flush(&self) -> Result<(), StorageError> {
+        let mut bc = self.data.write().unwrap();
+        bc.store.flush()
+    }
+
+    pub fn finalized_height(&self) -> u32 {
+        let bc = self.data.read().unwrap();
+        bc.fork_choice.finalized_height(bc.headers.len() as u32 - 1)
+    }
+
+    pub fn get_header(&self, h: u32) -> Option<Header> {
+        let bc = self.data.read().unwrap();
+        bc.headers.get(h as usize).cloned()
+    }
+
+    pub fn get_header_by_hash(&self, hash: &Hash) -> Option<Header> {
+        let bc = self.data.read().unwrap();
+        let h = bc.hashes.get(hash)?;
+        bc.headers.get(*h as usize).cloned()
+    }
+
+    pub fn get_height_by_hash(&self, hash: &Hash) -> Option<u32> {
+        let bc = self.data.read().unwrap();
+        bc.hashes.get(hash).cloned()
+    }
+
+    pub fn get_block_by_height(&self, h: u32) -> Result<Block, StorageError> {
+        let bc = self.data.read().unwrap();
+        bc.store.get_block_by_height(h)
+    }
+
+    pub fn get_block_by_hash(&self, hash: &Hash) -> Result<Block, StorageError> {
+        let bc = self.data.read().unwrap();
+        bc.store.get_block_by_hash(hash)
+    }
+
+    /// Looks up a transaction on the canonical chain, together with the
+    /// height of the block that holds it.
+    pub fn get_transaction(&self, hash: &Hash) -> Result<Option<(Transaction, u32)>, StorageError> {
+        let bc = self.data.read().unwrap();
+        let height = match bc.txs.get(hash) {
+            Some(height) => *height,
+            None => return Ok(None),
+        };
+        let b = bc.store.get_block_by_height(height)?;
+        Ok(b.transactions.into_iter().find_map(|mut tx| (tx.hash(Hasher::new()) == *hash).then_some((tx, height))))
+    }
+
+    /// Returns the blocks from height `from` to `to`, inclusive. `to` is
+    /// clamped to the current height.
+    pub fn get_blocks(&self, from: u32, to: u32) -> Result<Vec<Block>, StorageError> {
+        let bc = self.data.read().unwrap();
+        let to = to.min(bc.headers.len() as u32 - 1);
+        bc.store.blocks(from, to).collect()
     }
 
     pub fn has_block(&self, h: u32) -> Result<(), ()> {
         let bc = self.data.read().unwrap();
-        if h <= self.height() {
+        if (h as usize) < bc.headers.len() {
             return Ok(());
         }
         Err(())
@@ -63,22 +521,36 @@ impl Blockchain {
         bc.headers.len() as u32 - 1
     }
 
-    pub fn add_block_without_validation(&mut self, b: &mut Block) -> Result<(), ()> {
+    pub fn add_block_without_validation(&mut self, b: &mut Block) -> Result<(), BlockchainError> {
         let mut bc = self.data.write().unwrap();
-        let height = b.header.height;
-        log::info!("Adding block - height: {}, hash: {}", height, b.hash(Hasher::new()));
-
-        bc.headers.push(b.header);
-        bc.store.put(b)
+        let mut state = bc.state.clone();
+        state.apply_block(b)?;
+        bc.append(b)?;
+        bc.set_state(state);
+        bc.events.publish(ChainEvent::NewHead(b.header));
+        Ok(())
     }
  }
 
 
 #[cfg(test)]
 mod test {
-    use crate::core::block::Block;
+    use crate::core::block::{Block, Header};
+    use crate::core::encoding::{Decode, Decoder};
+    use crate::core::events::ChainEvent;
+    use crate::core::hasher::Bytes;
+    use crate::core::forkchoice::{transaction_weight, ForkChoice};
+    use crate::core::hasher::Hasher;
+    use crate::core::transaction::Transaction;
+    use crate::crypto::keypair::PrivateKey;
+    use crate::core::storage::DiskStore;
+    use crate::core::state::StateError;
+    use crate::core::transaction::Transfer;
+    use crate::core::validator::ValidationError;
+    use crate::types::address::Address;
+    use crate::types::hash::Hash;
 
-    use super::Blockchain;
+    use super::{Blockchain, BlockchainError};
 
     fn new_blockchain_with_genesis() -> Blockchain {
         let bc = Blockchain::new(&mut Block::random_block(0));
@@ -91,9 +563,344 @@ mod test {
         let mut bc = new_blockchain_with_genesis();
 
         let len = 1000;
-        for i in 1..len+1 {
-            let mut rand = Block::random_block_with_signature(i);
+        for _ in 1..len+1 {
+            let mut rand = Block::random_block_with_parent(&bc.get_header(bc.height()).unwrap());
             assert!(bc.add_block(&mut rand).is_ok());
         }
+        assert_eq!(bc.height(), len);
+    }
+
+    #[test]
+    fn test_add_block_rejected() {
+        let mut bc = new_blockchain_with_genesis();
+
+        let mut disconnected = Block::random_block_with_signature(1);
+        assert!(matches!(
+            bc.add_block(&mut disconnected),
+            Err(BlockchainError::Validation(ValidationError::UnknownParent(_)))
+        ));
+        assert_eq!(bc.height(), 0);
+    }
+
+    #[test]
+    fn test_claimed_hash_is_ignored() {
+        let mut bc = new_blockchain_with_genesis();
+
+        // A peer's block arrives with whatever hash it claims cached.
+        let mut b = Block::random_block_with_parent(&bc.get_header(0).unwrap());
+        b.hash = Some(Hash::random());
+        let bytes = b.as_bytes().unwrap();
+        let mut decoded: Block = Decoder::new(&mut bytes.as_slice()).decode().unwrap();
+        assert_eq!(decoded.hash, None);
+
+        b.hash = Some(Hash::random());
+        assert!(bc.add_block(&mut b).is_ok());
+        let hash = Hasher::new().hash(&b.header).unwrap();
+        assert!(bc.get_block_by_hash(&hash).is_ok());
+        assert!(bc.add_block(&mut Block::random_block_with_parent(&b.header)).is_ok());
+        assert_eq!(decoded.hash(Hasher::new()), hash);
+    }
+
+    #[test]
+    fn test_reopen_from_disk() {
+        let dir = std::env::temp_dir().join(format!("rustchain-chain-{}", Hash::random()));
+        let mut genesis = Block::random_block(0);
+
+        let mut bc = Blockchain::new_with_store(Box::new(DiskStore::open(&dir).unwrap()), &mut genesis).unwrap();
+        for _ in 1..11 {
+            assert!(bc.add_block(&mut Block::random_block_with_parent(&bc.get_header(bc.height()).unwrap())).is_ok());
+        }
+        drop(bc);
+
+        let mut bc = Blockchain::new_with_store(Box::new(DiskStore::open(&dir).unwrap()), &mut genesis).unwrap();
+        assert_eq!(bc.height(), 10);
+        assert!(bc.add_block(&mut Block::random_block_with_parent(&bc.get_header(10).unwrap())).is_ok());
+        assert_eq!(bc.height(), 11);
+
+        let mut other = Block::random_block(0);
+        assert!(Blockchain::new_with_store(Box::new(DiskStore::open(&dir).unwrap()), &mut other).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_get_block() {
+        let mut bc = new_blockchain_with_genesis();
+
+        let mut blocks = vec![];
+        for _ in 1..11 {
+            let mut b = Block::random_block_with_parent(&bc.get_header(bc.height()).unwrap());
+            assert!(bc.add_block(&mut b).is_ok());
+            blocks.push(b);
+        }
+
+        for b in blocks.iter_mut() {
+            let hash = b.hash(Hasher::new());
+            assert_eq!(&bc.get_block_by_height(b.header.height).unwrap(), b);
+            assert_eq!(&bc.get_block_by_hash(&hash).unwrap(), b);
+            assert_eq!(bc.get_header_by_hash(&hash), Some(b.header));
+            assert_eq!(bc.get_header(b.header.height), Some(b.header));
+        }
+        assert!(bc.get_block_by_height(11).is_err());
+        assert!(bc.get_block_by_hash(&Hash::random()).is_err());
+        assert_eq!(bc.get_header_by_hash(&Hash::random()), None);
+
+        let range = bc.get_blocks(3, 100).unwrap();
+        assert_eq!(range.len(), 8);
+        assert_eq!(range[0].header.height, 3);
+        assert_eq!(range[7].header.height, 10);
+    }
+
+    fn add_branch(bc: &mut Blockchain, parent: &Header, len: usize) -> Vec<Block> {
+        let mut branch: Vec<Block> = vec![];
+        for _ in 0..len {
+            let parent = branch.last().map(|b| b.header).unwrap_or(*parent);
+            let mut b = Block::random_block_with_parent(&parent);
+            assert!(bc.add_block(&mut b).is_ok());
+            branch.push(b);
+        }
+        branch
     }
- }
\ No newline at end of file
+
+    #[test]
+    fn test_reorg_longest_chain() {
+        let mut bc = new_blockchain_with_genesis();
+        let reorgs = bc.subscribe_reorgs();
+        let genesis = bc.get_header(0).unwrap();
+        let a = add_branch(&mut bc, &genesis, 3);
+
+        // Same length as the canonical branch, so the chain stays put.
+        let b = add_branch(&mut bc, &a[0].header, 2);
+        assert_eq!(bc.height(), 3);
+        assert_eq!(bc.get_header(3), Some(a[2].header));
+        assert!(reorgs.try_recv().is_err());
+
+        let events = bc.events().subscribe(8);
+        let b4 = add_branch(&mut bc, &b[1].header, 1);
+        assert_eq!(bc.height(), 4);
+        // The reorg is published before the heads it added.
+        assert!(matches!(events.try_recv(), Ok(ChainEvent::Reorg(reorg)) if reorg.common_ancestor == 1));
+        let heads: Vec<Header> = events
+            .try_iter()
+            .filter_map(|event| match event {
+                ChainEvent::NewHead(header) => Some(header),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(heads, vec![b[0].header, b[1].header, b4[0].header]);
+        assert_eq!(bc.get_header(2), Some(b[0].header));
+        assert_eq!(bc.get_header(4), Some(b4[0].header));
+        assert_eq!(bc.get_block_by_height(3).unwrap().header, b[1].header);
+
+        let reorg = reorgs.try_recv().unwrap();
+        assert_eq!(reorg.common_ancestor, 1);
+        assert_eq!(reorg.dropped.iter().map(|b| b.header).collect::<Vec<_>>(), vec![a[1].header, a[2].header]);
+        assert_eq!(reorg.added.len(), 3);
+
+        // The dropped blocks are still known and can win the chain back.
+        let a3 = Hasher::new().hash(&a[2].header).unwrap();
+        assert_eq!(bc.lookup_header(&a3), Some(a[2].header));
+        assert_eq!(bc.get_header_by_hash(&a3), None);
+        add_branch(&mut bc, &a[2].header, 2);
+        assert_eq!(bc.height(), 5);
+        assert_eq!(bc.get_header(3), Some(a[2].header));
+    }
+
+    #[test]
+    fn test_reorg_heaviest_chain() {
+        let mut bc = new_blockchain_with_genesis();
+        bc.set_fork_choice(ForkChoice::HeaviestChain(transaction_weight));
+        let reorgs = bc.subscribe_reorgs();
+        let genesis = bc.get_header(0).unwrap();
+        add_branch(&mut bc, &genesis, 2);
+
+        let key = PrivateKey::generate_key();
+        let txs = (0..3u8).map(|i| {
+            let mut tx = Transaction::new(vec![i]).unwrap();
+            tx.nonce = i as u64;
+            assert!(tx.sign(&key).is_ok());
+            tx
+        }).collect();
+        let mut heavy = Block::from_prev_header(&genesis, txs);
+        assert!(heavy.sign(key).is_ok());
+        assert!(bc.add_block(&mut heavy).is_ok());
+
+        assert_eq!(bc.height(), 1);
+        assert_eq!(bc.get_header(1), Some(heavy.header));
+        assert_eq!(reorgs.try_recv().unwrap().dropped.len(), 2);
+    }
+
+    #[test]
+    fn test_finalized_checkpoint() {
+        let mut bc = new_blockchain_with_genesis();
+        bc.set_fork_choice(ForkChoice::FinalizedCheckpoint { depth: 2 });
+        let genesis = bc.get_header(0).unwrap();
+        let a = add_branch(&mut bc, &genesis, 4);
+        assert_eq!(bc.finalized_height(), 2);
+
+        let mut b = Block::random_block_with_parent(&a[0].header);
+        assert!(matches!(
+            bc.add_block(&mut b),
+            Err(BlockchainError::Validation(ValidationError::BelowFinalized { finalized: 2, got: 2 }))
+        ));
+
+        let b = add_branch(&mut bc, &a[1].header, 3);
+        assert_eq!(bc.height(), 5);
+        assert_eq!(bc.get_header(3), Some(b[0].header));
+    }
+
+    #[test]
+    fn test_prune_detached_side_blocks() {
+        let mut bc = new_blockchain_with_genesis();
+        bc.set_fork_choice(ForkChoice::FinalizedCheckpoint { depth: 2 });
+        let genesis = bc.get_header(0).unwrap();
+        let a = add_branch(&mut bc, &genesis, 3);
+        let b = add_branch(&mut bc, &a[0].header, 2);
+        assert!(bc.add_block(&mut Block::random_block_with_parent(&a[2].header)).is_ok());
+
+        // Finalizing height 2 drops b2, and b3 with it since it no longer
+        // connects to the chain.
+        assert_eq!(bc.finalized_height(), 2);
+        assert!(bc.lookup_header(&Hasher::new().hash(&b[1].header).unwrap()).is_none());
+        let mut b4 = Block::random_block_with_parent(&b[1].header);
+        assert!(matches!(
+            bc.add_block(&mut b4),
+            Err(BlockchainError::Validation(ValidationError::UnknownParent(_)))
+        ));
+        assert_eq!(bc.height(), 4);
+    }
+
+    #[test]
+    fn test_side_blocks_are_bounded() {
+        let mut bc = new_blockchain_with_genesis();
+        bc.set_max_side_blocks(8);
+        let genesis = bc.get_header(0).unwrap();
+        let a = add_branch(&mut bc, &genesis, 3);
+        let mut high = Block::random_block_with_parent(&a[0].header);
+        assert!(bc.add_block(&mut high).is_ok());
+
+        // The lowest side blocks are dropped first.
+        for _ in 0..8 {
+            assert!(bc.add_block(&mut Block::random_block_with_parent(&genesis)).is_ok());
+        }
+        assert!(bc.data.read().unwrap().side.len() <= 8);
+        assert_eq!(bc.lookup_header(&high.hash(Hasher::new())), Some(high.header));
+        assert_eq!(bc.get_header(3), Some(a[2].header));
+    }
+ 
+    fn transfer_block(parent: &Header, key: &PrivateKey, to: Address, amount: u64, nonce: u64) -> Block {
+        let from = key.generate_public().address().unwrap();
+        let mut tx = Transaction::new_transfer(Transfer { from, to, amount });
+        tx.nonce = nonce;
+        assert!(tx.sign(key).is_ok());
+        let mut b = Block::from_prev_header(parent, vec![tx]);
+        assert!(b.sign(PrivateKey::generate_key()).is_ok());
+        b
+    }
+
+    #[test]
+    fn test_transfers() {
+        let alice = PrivateKey::generate_key();
+        let address = |key: &PrivateKey| key.generate_public().address().unwrap();
+        let (bob, carol) = (address(&PrivateKey::generate_key()), address(&PrivateKey::generate_key()));
+
+        let mut genesis = Block::random_block(0);
+        let mint = Transaction::new_transfer(Transfer { from: address(&alice), to: address(&alice), amount: 100 });
+        assert!(genesis.add_transaction(&mint).is_ok());
+        let mut bc = Blockchain::new(&mut genesis).unwrap();
+        assert_eq!(bc.get_account(&address(&alice)).balance, 100);
+
+        let mut a1 = transfer_block(&genesis.header, &alice, bob, 60, 0);
+        assert!(bc.add_block(&mut a1).is_ok());
+        assert_eq!(bc.get_account(&address(&alice)).balance, 40);
+        assert_eq!(bc.get_account(&address(&alice)).nonce, 1);
+        assert_eq!(bc.get_account(&bob).balance, 60);
+
+        // Overdrawing blocks are rejected.
+        let mut overdraw = transfer_block(&a1.header, &alice, bob, 60, 1);
+        assert!(matches!(
+            bc.add_block(&mut overdraw),
+            Err(BlockchainError::State(StateError::InsufficientBalance { .. }))
+        ));
+        assert_eq!(bc.height(), 1);
+
+        // Switching branches replays the state of the new branch.
+        let b1 = transfer_block(&genesis.header, &alice, carol, 100, 0);
+        assert!(bc.add_block(&mut b1.clone()).is_ok());
+        add_branch(&mut bc, &b1.header, 1);
+        assert_eq!(bc.height(), 2);
+        assert_eq!(bc.get_account(&address(&alice)).balance, 0);
+        assert_eq!(bc.get_account(&bob).balance, 0);
+        assert_eq!(bc.get_account(&carol).balance, 100);
+
+        // A longer branch that does not apply never becomes canonical.
+        let mut c1 = transfer_block(&genesis.header, &alice, bob, 200, 0);
+        assert!(bc.add_block(&mut c1).is_ok());
+        let c2 = add_branch(&mut bc, &c1.header, 1);
+        let mut c3 = Block::random_block_with_parent(&c2[0].header);
+        assert!(matches!(bc.add_block(&mut c3), Err(BlockchainError::State(_))));
+        assert_eq!(bc.height(), 2);
+        assert_eq!(bc.get_header(1), Some(b1.header));
+        assert_eq!(bc.get_account(&carol).balance, 100);
+    }
+
+    #[test]
+    fn test_state_snapshots() {
+        let alice = PrivateKey::generate_key();
+        let address = |key: &PrivateKey| key.generate_public().address().unwrap();
+        let (bob, carol) = (address(&PrivateKey::generate_key()), address(&PrivateKey::generate_key()));
+
+        let mut genesis = Block::random_block(0);
+        let mint = Transaction::new_transfer(Transfer { from: address(&alice), to: address(&alice), amount: 100 });
+        assert!(genesis.add_transaction(&mint).is_ok());
+        let mut bc = Blockchain::new(&mut genesis).unwrap();
+        bc.set_snapshot_interval(2);
+
+        let mut blocks = vec![genesis.clone()];
+        for nonce in 0..8 {
+            let mut b = transfer_block(&blocks.last().unwrap().header, &alice, bob, 1, nonce);
+            assert!(bc.add_block(&mut b).is_ok());
+            blocks.push(b);
+        }
+        let keys = |bc: &Blockchain| bc.data.read().unwrap().snapshots.keys().cloned().collect::<Vec<u32>>();
+        assert_eq!(keys(&bc), vec![2, 4, 6, 8]);
+        let state_at = |bc: &Blockchain, height| bc.data.read().unwrap().state_at(height).unwrap();
+        assert_eq!(state_at(&bc, 3).balance(&bob), 3);
+        assert_eq!(state_at(&bc, 8), bc.state());
+
+        // A reorg replaces the snapshots of the dropped blocks with ones of
+        // the new branch.
+        let mut c6 = transfer_block(&blocks[5].header, &alice, carol, 10, 5);
+        assert!(bc.add_block(&mut c6).is_ok());
+        add_branch(&mut bc, &c6.header, 3);
+        assert_eq!(bc.height(), 9);
+        assert_eq!(keys(&bc), vec![2, 4, 6, 8]);
+        assert_eq!(state_at(&bc, 6).balance(&bob), 5);
+        assert_eq!(state_at(&bc, 7).balance(&carol), 10);
+        assert_eq!(bc.get_account(&address(&alice)).balance, 85);
+    }
+
+    #[test]
+    fn test_get_transaction() {
+        let alice = PrivateKey::generate_key();
+        let from = alice.generate_public().address().unwrap();
+        let mut genesis = Block::random_block(0);
+        let mint = Transaction::new_transfer(Transfer { from, to: from, amount: 100 });
+        assert!(genesis.add_transaction(&mint).is_ok());
+        let mut bc = Blockchain::new(&mut genesis).unwrap();
+
+        let mut a1 = transfer_block(&genesis.header, &alice, from, 10, 0);
+        assert!(bc.add_block(&mut a1).is_ok());
+        let a_tx = a1.transactions[0].hash(Hasher::new());
+        assert_eq!(bc.get_transaction(&a_tx).unwrap(), Some((a1.transactions[0].clone(), 1)));
+        assert_eq!(bc.get_transaction(&Hash::random()).unwrap(), None);
+
+        // Transactions of blocks rolled back are no longer found.
+        let mut b1 = transfer_block(&genesis.header, &alice, from, 20, 0);
+        assert!(bc.add_block(&mut b1).is_ok());
+        add_branch(&mut bc, &b1.header, 1);
+        let b_tx = b1.transactions[0].hash(Hasher::new());
+        assert_eq!(bc.get_transaction(&a_tx).unwrap(), None);
+        assert_eq!(bc.get_transaction(&b_tx).unwrap().map(|(_, height)| height), Some(1));
+    }
+}
@@ -1,7 +1,8 @@
 use std::io::{Write, Read};
 use serde::Deserialize;
 
-use crate::network::rpc::Message;
+use crate::error::Error;
+use crate::network::rpc::{BlocksMessage, GetBlocksMessage, HandshakeMessage, Message, PeersMessage, StatusMessage};
 
 use super::{block::{Block, Header}, transaction::Transaction};
 
@@ -18,30 +19,60 @@ impl <'a, W: Write>Encoder<'a, W> {
 }
 
 pub trait Encode<T> {
-    fn encode(&mut self, obj: &T);
+    fn encode(&mut self, obj: &T) -> Result<(), Error>;
 }
 
 impl <'a, W: Write>Encode<Block> for Encoder<'a, W> {
-    fn encode(&mut self, obj: &Block) {
-        ciborium::ser::into_writer( obj, &mut self.writer);
+    fn encode(&mut self, obj: &Block) -> Result<(), Error> {
+        ciborium::ser::into_writer(obj, &mut self.writer).map_err(|e| Error::Encode(format!("{:?}", e)))
     }
 }
 
 impl <'a, W: Write>Encode<Header> for Encoder<'a, W> {
-    fn encode(&mut self, obj: &Header) {
-        ciborium::ser::into_writer( obj, &mut self.writer);
+    fn encode(&mut self, obj: &Header) -> Result<(), Error> {
+        ciborium::ser::into_writer(obj, &mut self.writer).map_err(|e| Error::Encode(format!("{:?}", e)))
     }
 }
 
 impl <'a, W: Write>Encode<Transaction> for Encoder<'a, W> {
-    fn encode(&mut self, obj: &Transaction) {
-        ciborium::ser::into_writer( obj, &mut self.writer);
+    fn encode(&mut self, obj: &Transaction) -> Result<(), Error> {
+        ciborium::ser::into_writer(obj, &mut self.writer).map_err(|e| Error::Encode(format!("{:?}", e)))
     }
 }
 
 impl <'a, W: Write>Encode<Message> for Encoder<'a, W> {
-    fn encode(&mut self, obj: &Message) {
-        ciborium::ser::into_writer( obj, &mut self.writer);
+    fn encode(&mut self, obj: &Message) -> Result<(), Error> {
+        ciborium::ser::into_writer(obj, &mut self.writer).map_err(|e| Error::Encode(format!("{:?}", e)))
+    }
+}
+
+impl <'a, W: Write>Encode<StatusMessage> for Encoder<'a, W> {
+    fn encode(&mut self, obj: &StatusMessage) -> Result<(), Error> {
+        ciborium::ser::into_writer(obj, &mut self.writer).map_err(|e| Error::Encode(format!("{:?}", e)))
+    }
+}
+
+impl <'a, W: Write>Encode<GetBlocksMessage> for Encoder<'a, W> {
+    fn encode(&mut self, obj: &GetBlocksMessage) -> Result<(), Error> {
+        ciborium::ser::into_writer(obj, &mut self.writer).map_err(|e| Error::Encode(format!("{:?}", e)))
+    }
+}
+
+impl <'a, W: Write>Encode<BlocksMessage> for Encoder<'a, W> {
+    fn encode(&mut self, obj: &BlocksMessage) -> Result<(), Error> {
+        ciborium::ser::into_writer(obj, &mut self.writer).map_err(|e| Error::Encode(format!("{:?}", e)))
+    }
+}
+
+impl <'a, W: Write>Encode<HandshakeMessage> for Encoder<'a, W> {
+    fn encode(&mut self, obj: &HandshakeMessage) -> Result<(), Error> {
+        ciborium::ser::into_writer(obj, &mut self.writer).map_err(|e| Error::Encode(format!("{:?}", e)))
+    }
+}
+
+impl <'a, W: Write>Encode<PeersMessage> for Encoder<'a, W> {
+    fn encode(&mut self, obj: &PeersMessage) -> Result<(), Error> {
+        ciborium::ser::into_writer(obj, &mut self.writer).map_err(|e| Error::Encode(format!("{:?}", e)))
     }
 }
 
@@ -58,14 +89,13 @@ impl <'a, R: Read>Decoder<'a, R> {
 }
 
 pub trait Decode<'de, T: Deserialize<'de>> {
-    fn decode(&mut self) -> T;
+    fn decode(&mut self) -> Result<T, Error>;
 }
 
 
 impl <'de, R: Read, T: Deserialize<'de>>Decode<'de, T> for Decoder<'de, R> {
-    fn decode(&mut self) -> T {
-        let obj: T = ciborium::de::from_reader(&mut self.reader).unwrap();
-        obj
+    fn decode(&mut self) -> Result<T, Error> {
+        ciborium::de::from_reader(&mut self.reader).map_err(|e| Error::Decode(format!("{:?}", e)))
     }
 }
 
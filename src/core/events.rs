@@ -0,0 +1,74 @@
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+
+use super::block::Header;
+use super::blockchain::Reorg;
+use super::transaction::Transaction;
+
+#[derive(Clone, Debug)]
+pub enum ChainEvent {
+    // A block was added to the canonical chain.
+    NewHead(Header),
+    // A transaction was admitted to the pool.
+    PendingTransaction(Box<Transaction>),
+    Reorg(Reorg),
+}
+
+/// Hands every published event to all subscribers. Each subscriber has a
+/// bounded buffer, one that falls further behind is dropped, so publishing
+/// never blocks.
+#[derive(Clone, Default)]
+pub struct EventBus {
+    subscribers: Arc<Mutex<Vec<SyncSender<ChainEvent>>>>,
+}
+
+impl EventBus {
+    pub fn new() -> EventBus {
+        EventBus::default()
+    }
+
+    /// Receives the events published from now on. The receiver disconnects
+    /// once more than `capacity` events are waiting in it.
+    pub fn subscribe(&self, capacity: usize) -> Receiver<ChainEvent> {
+        let (send, recv) = mpsc::sync_channel(capacity);
+        self.subscribers.lock().unwrap().push(send);
+        recv
+    }
+
+    pub fn publish(&self, event: ChainEvent) {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|sub| match sub.try_send(event.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                log::warn!("dropping event subscriber that fell behind");
+                false
+            }
+            Err(TrySendError::Disconnected(_)) => false,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::block::Block;
+
+    use super::*;
+
+    #[test]
+    fn test_slow_subscriber_is_dropped() {
+        let bus = EventBus::new();
+        let fast = bus.subscribe(1);
+        let slow = bus.subscribe(1);
+        let head = || ChainEvent::NewHead(Block::random_block(1).header);
+
+        bus.publish(head());
+        assert!(fast.try_recv().is_ok());
+        bus.publish(head());
+        assert!(fast.try_recv().is_ok());
+
+        // The slow subscriber still gets what fit into its buffer.
+        assert!(slow.try_recv().is_ok());
+        assert_eq!(slow.try_recv().unwrap_err(), mpsc::TryRecvError::Disconnected);
+        assert_eq!(bus.subscribers.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,47 @@
+use super::block::Block;
+
+pub type WeightFunc = fn(&Block) -> u64;
+
+// Every block weighs one plus the number of transactions it carries.
+pub fn transaction_weight(b: &Block) -> u64 {
+    1 + b.transactions.len() as u64
+}
+
+/// Decides which of two competing branches becomes the canonical chain.
+/// Ties always keep the current chain.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum ForkChoice {
+    #[default]
+    LongestChain,
+    HeaviestChain(WeightFunc),
+    // Longest chain, but blocks `depth` or more below the tip are final and
+    // are never rolled back.
+    FinalizedCheckpoint { depth: u32 },
+}
+
+impl ForkChoice {
+    // Height at or below which the chain can no longer be reorganised.
+    pub fn finalized_height(&self, tip: u32) -> u32 {
+        match self {
+            ForkChoice::FinalizedCheckpoint { depth } => tip.saturating_sub(*depth),
+            _ => 0,
+        }
+    }
+
+    /// `current` and `candidate` are the two branches above their common
+    /// ancestor at height `ancestor`, in ascending height order.
+    pub fn prefers(&self, ancestor: u32, current: &[Block], candidate: &[Block]) -> bool {
+        match self {
+            ForkChoice::LongestChain => candidate.len() > current.len(),
+            ForkChoice::HeaviestChain(weight) => {
+                let current: u64 = current.iter().map(weight).sum();
+                let candidate: u64 = candidate.iter().map(weight).sum();
+                candidate > current
+            }
+            ForkChoice::FinalizedCheckpoint { .. } => {
+                let tip = ancestor + current.len() as u32;
+                ancestor >= self.finalized_height(tip) && candidate.len() > current.len()
+            }
+        }
+    }
+}
@@ -1,10 +1,11 @@
 use sha2::{Sha256, Digest};
 
+use crate::error::Error;
 use crate::types::hash::Hash;
 
 
 pub trait Bytes {
-    fn as_bytes(&self) -> Vec<u8>;
+    fn as_bytes(&self) -> Result<Vec<u8>, Error>;
 }
 
 pub struct Hasher {
@@ -15,12 +16,12 @@ impl Hasher {
     pub fn new() -> Hasher {
         Hasher {}
     }
-    pub fn hash<B>(&self, obj: &B) -> Result<Hash, String>
+    pub fn hash<B>(&self, obj: &B) -> Result<Hash, Error>
     where B: Bytes
      {
         let mut hasher = Sha256::new();
-        hasher.update(obj.as_bytes());
+        hasher.update(obj.as_bytes()?);
         let h = hasher.finalize();
-        Hash::from_bytes(&h)
+        Hash::from_bytes(&h).map_err(Error::Encode)
     }
 }
\ No newline at end of file
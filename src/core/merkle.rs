@@ -0,0 +1,148 @@
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::types::hash::Hash;
+
+// Leaves and inner nodes are hashed with different prefixes so a leaf can
+// never be passed off as an inner node.
+const LEAF_PREFIX: u8 = 0x00;
+const NODE_PREFIX: u8 = 0x01;
+
+fn hash_leaf(leaf: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([LEAF_PREFIX]);
+    hasher.update(leaf.to_vec());
+    Hash::from_bytes(&hasher.finalize()).unwrap()
+}
+
+fn hash_node(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([NODE_PREFIX]);
+    hasher.update(left.to_vec());
+    hasher.update(right.to_vec());
+    Hash::from_bytes(&hasher.finalize()).unwrap()
+}
+
+/// Binary Merkle tree. A node without a sibling is carried up to the next
+/// level unchanged instead of being paired with itself.
+pub struct MerkleTree {
+    levels: Vec<Vec<Hash>>,
+}
+
+impl MerkleTree {
+    pub fn new(leaves: &[Hash]) -> Self {
+        let mut levels = vec![leaves.iter().map(hash_leaf).collect::<Vec<Hash>>()];
+        while levels.last().unwrap().len() > 1 {
+            let next = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => hash_node(left, right),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+        MerkleTree { levels }
+    }
+
+    // Root of the tree, the zero hash if there are no leaves.
+    pub fn root(&self) -> Hash {
+        match self.levels.last().unwrap().first() {
+            Some(root) => *root,
+            None => Hash::default(),
+        }
+    }
+
+    pub fn proof(&self, index: usize) -> Option<MerkleProof> {
+        if index >= self.levels[0].len() {
+            return None;
+        }
+
+        let mut path = vec![];
+        let mut i = index;
+        for level in &self.levels[..self.levels.len() - 1] {
+            let sibling = i ^ 1;
+            if sibling < level.len() {
+                let side = if sibling < i { Side::Left } else { Side::Right };
+                path.push(ProofStep { hash: level[sibling], side });
+            }
+            i /= 2;
+        }
+        Some(MerkleProof { path })
+    }
+}
+
+pub fn merkle_root(leaves: &[Hash]) -> Hash {
+    MerkleTree::new(leaves).root()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Side {
+    Left,
+    Right,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProofStep {
+    pub hash: Hash,
+    pub side: Side,
+}
+
+/// Inclusion proof for a single leaf: the sibling hashes from the leaf up to
+/// the root.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    pub path: Vec<ProofStep>,
+}
+
+impl MerkleProof {
+    pub fn verify(&self, root: &Hash, leaf: &Hash) -> bool {
+        let computed = self.path.iter().fold(hash_leaf(leaf), |acc, step| match step.side {
+            Side::Left => hash_node(&step.hash, &acc),
+            Side::Right => hash_node(&acc, &step.hash),
+        });
+        &computed == root
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::types::hash::Hash;
+
+    use super::{merkle_root, MerkleTree};
+
+    #[test]
+    fn test_merkle_root() {
+        assert_eq!(merkle_root(&[]), Hash::default());
+
+        let leaves: Vec<Hash> = (0..5).map(|_| Hash::random()).collect();
+        let root = merkle_root(&leaves);
+        assert_ne!(root, Hash::default());
+        assert_eq!(root, merkle_root(&leaves));
+
+        let mut swapped = leaves.clone();
+        swapped.swap(0, 1);
+        assert_ne!(root, merkle_root(&swapped));
+        assert_ne!(root, merkle_root(&leaves[..4]));
+    }
+
+    #[test]
+    fn test_merkle_proof() {
+        for n in 1..12 {
+            let leaves: Vec<Hash> = (0..n).map(|_| Hash::random()).collect();
+            let tree = MerkleTree::new(&leaves);
+            let root = tree.root();
+
+            for (i, leaf) in leaves.iter().enumerate() {
+                let proof = tree.proof(i).unwrap();
+                assert!(proof.verify(&root, leaf));
+                assert!(!proof.verify(&root, &Hash::random()));
+                assert!(!proof.verify(&Hash::random(), leaf));
+            }
+            assert!(tree.proof(n).is_none());
+        }
+    }
+}
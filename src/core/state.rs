@@ -0,0 +1,376 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::types::address::Address;
+
+use super::block::Block;
+use super::transaction::Transaction;
+use super::vm::{self, ContractStorage};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Account {
+    pub balance: u64,
+    // Number of transactions sent from the account.
+    pub nonce: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum StateError {
+    MissingSender,
+    InvalidSender { signer: Address, from: Address },
+    InvalidNonce { address: Address, expected: u64, got: u64 },
+    InsufficientBalance { address: Address, balance: u64, amount: u64 },
+    BalanceOverflow(Address),
+    SupplyOverflow,
+    IntrinsicGas { limit: u64, required: u64 },
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::MissingSender => write!(f, "transaction is not signed"),
+            StateError::InvalidSender { signer, from } => {
+                write!(f, "transfer from {} is signed by {}", from, signer)
+            }
+            StateError::InvalidNonce { address, expected, got } => {
+                write!(f, "invalid nonce {} for {}, expected {}", got, address, expected)
+            }
+            StateError::InsufficientBalance { address, balance, amount } => {
+                write!(f, "cannot spend {} from {} with balance {}", amount, address, balance)
+            }
+            StateError::BalanceOverflow(address) => write!(f, "balance of {} overflows", address),
+            StateError::SupplyOverflow => write!(f, "total supply overflows"),
+            StateError::IntrinsicGas { limit, required } => {
+                write!(f, "gas limit {} is below the intrinsic gas {}", limit, required)
+            }
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// Account balances and nonces, and the storage of every contract. Accounts
+/// that were never touched have a zero balance and nonce.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct State {
+    accounts: HashMap<Address, Account>,
+    contracts: HashMap<Address, ContractStorage>,
+}
+
+impl State {
+    pub fn new() -> Self {
+        State::default()
+    }
+
+    /// Initial state defined by the genesis block. Every transfer in it mints
+    /// `amount` to `to`, nothing is debited and no signature is required.
+    /// This is the only way funds are created, so the total supply has to fit
+    /// into a balance.
+    pub fn genesis(b: &Block) -> Result<Self, StateError> {
+        let mut state = State::new();
+        let mut supply: u64 = 0;
+        for transfer in b.transactions.iter().filter_map(|tx| tx.transfer.as_ref()) {
+            supply = supply.checked_add(transfer.amount).ok_or(StateError::SupplyOverflow)?;
+            state.credit(&transfer.to, transfer.amount)?;
+        }
+        Ok(state)
+    }
+
+    pub fn get_account(&self, address: &Address) -> Account {
+        self.accounts.get(address).cloned().unwrap_or_default()
+    }
+
+    pub fn balance(&self, address: &Address) -> u64 {
+        self.get_account(address).balance
+    }
+
+    pub fn nonce(&self, address: &Address) -> u64 {
+        self.get_account(address).nonce
+    }
+
+    pub fn get_storage(&self, contract: &Address, key: i64) -> i64 {
+        self.contracts
+            .get(contract)
+            .and_then(|storage| storage.get(&key).cloned())
+            .unwrap_or(0)
+    }
+
+    // Runs the data of a transaction as code of the contract it identifies
+    // and returns the gas used. Storage writes of a failing program are
+    // discarded and it uses up all of `gas_limit`.
+    fn execute(&mut self, code: &[u8], gas_limit: u64) -> Result<u64, vm::VmError> {
+        if code.is_empty() {
+            return Ok(0);
+        }
+        let contract = vm::contract_address(code);
+        let storage = self.contracts.entry(contract).or_default();
+        let res = vm::execute(code, storage, gas_limit);
+        if storage.is_empty() {
+            self.contracts.remove(&contract);
+        }
+        if let Err(e) = &res {
+            log::debug!("execution of contract {} failed: {}", contract, e);
+        }
+        res
+    }
+
+    fn credit(&mut self, address: &Address, amount: u64) -> Result<(), StateError> {
+        let account = self.accounts.entry(*address).or_default();
+        account.balance = account
+            .balance
+            .checked_add(amount)
+            .ok_or(StateError::BalanceOverflow(*address))?;
+        Ok(())
+    }
+
+    /// Applies a single transaction and returns the gas it used. Every
+    /// transaction bumps the nonce of its sender and pays for the gas it used
+    /// to `coinbase`, fees are burnt if there is none. The transfer and the
+    /// storage writes of the data, which is executed by the VM, are reverted
+    /// if execution fails. Nothing is changed if an error is returned.
+    pub fn apply_transaction(&mut self, tx: &Transaction, coinbase: Option<&Address>) -> Result<u64, StateError> {
+        let signer = tx.sender().ok_or(StateError::MissingSender)?;
+        let sender = self.get_account(&signer);
+        if tx.nonce != sender.nonce {
+            return Err(StateError::InvalidNonce { address: signer, expected: sender.nonce, got: tx.nonce });
+        }
+        if let Some(transfer) = &tx.transfer {
+            if signer != transfer.from {
+                return Err(StateError::InvalidSender { signer, from: transfer.from });
+            }
+        }
+        let intrinsic = tx.intrinsic_gas();
+        if tx.gas_limit < intrinsic {
+            return Err(StateError::IntrinsicGas { limit: tx.gas_limit, required: intrinsic });
+        }
+
+        // The sender has to afford the whole gas limit up front.
+        let amount = tx.transfer.map_or(0, |transfer| transfer.amount);
+        let cost = tx
+            .gas_limit
+            .checked_mul(tx.gas_price)
+            .and_then(|fee| fee.checked_add(amount));
+        match cost {
+            Some(cost) if cost <= sender.balance => {}
+            _ => {
+                return Err(StateError::InsufficientBalance {
+                    address: signer,
+                    balance: sender.balance,
+                    amount: cost.unwrap_or(u64::MAX),
+                })
+            }
+        }
+
+        // Funds are conserved from here on, so no balance can overflow.
+        let account = self.accounts.entry(signer).or_default();
+        account.balance -= tx.gas_limit * tx.gas_price;
+        account.nonce += 1;
+
+        let gas_used = match self.execute(&tx.data, tx.gas_limit - intrinsic) {
+            Ok(gas) => {
+                if let Some(transfer) = &tx.transfer {
+                    self.accounts.entry(signer).or_default().balance -= amount;
+                    self.credit(&transfer.to, amount)?;
+                }
+                intrinsic + gas
+            }
+            Err(_) => tx.gas_limit,
+        };
+
+        self.credit(&signer, (tx.gas_limit - gas_used) * tx.gas_price)?;
+        if let Some(coinbase) = coinbase {
+            self.credit(coinbase, gas_used * tx.gas_price)?;
+        }
+        Ok(gas_used)
+    }
+
+    /// Applies all transactions of `b` in order, paying fees to the validator
+    /// of the block. Either all of them are applied or, on error, none are.
+    pub fn apply_block(&mut self, b: &Block) -> Result<(), StateError> {
+        let coinbase = b.validator.as_ref().and_then(|key| key.address().ok());
+        let mut next = self.clone();
+        for tx in &b.transactions {
+            next.apply_transaction(tx, coinbase.as_ref())?;
+        }
+        *self = next;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::core::block::Block;
+    use crate::core::transaction::{Transaction, Transfer};
+    use crate::core::vm::{assemble, contract_address};
+    use crate::crypto::keypair::PrivateKey;
+    use crate::types::address::Address;
+
+    use super::{State, StateError};
+
+    fn address(key: &PrivateKey) -> Address {
+        key.generate_public().address().unwrap()
+    }
+
+    fn transfer(key: &PrivateKey, to: Address, amount: u64, nonce: u64) -> Transaction {
+        let mut tx = Transaction::new_transfer(Transfer { from: address(key), to, amount });
+        tx.nonce = nonce;
+        assert!(tx.sign(key).is_ok());
+        tx
+    }
+
+    fn genesis(to: Address, amount: u64) -> State {
+        let mint = Transaction::new_transfer(Transfer { from: to, to, amount });
+        let mut b = Block::random_block(0);
+        assert!(b.add_transaction(&mint).is_ok());
+        State::genesis(&b).unwrap()
+    }
+
+    #[test]
+    fn test_transfer() {
+        let alice = PrivateKey::generate_key();
+        let bob = address(&PrivateKey::generate_key());
+        let mut state = genesis(address(&alice), 100);
+        assert_eq!(state.balance(&address(&alice)), 100);
+
+        assert!(state.apply_transaction(&transfer(&alice, bob, 30, 0), None).is_ok());
+        assert_eq!(state.balance(&address(&alice)), 70);
+        assert_eq!(state.nonce(&address(&alice)), 1);
+        assert_eq!(state.balance(&bob), 30);
+
+        let mut data = Transaction::new(b"foo".to_vec()).unwrap();
+        data.nonce = 1;
+        assert!(data.sign(&alice).is_ok());
+        assert!(state.apply_transaction(&data, None).is_ok());
+        assert_eq!(state.nonce(&address(&alice)), 2);
+        assert_eq!(state.balance(&address(&alice)), 70);
+
+        // The same transaction cannot be applied twice.
+        assert!(matches!(state.apply_transaction(&data, None), Err(StateError::InvalidNonce { expected: 2, got: 1, .. })));
+    }
+
+    #[test]
+    fn test_transfer_errors() {
+        let alice = PrivateKey::generate_key();
+        let bob = address(&PrivateKey::generate_key());
+        let mut state = genesis(address(&alice), 100);
+
+        assert!(matches!(
+            state.apply_transaction(&transfer(&alice, bob, 101, 0), None),
+            Err(StateError::InsufficientBalance { balance: 100, amount: 101, .. })
+        ));
+        assert!(matches!(
+            state.apply_transaction(&transfer(&alice, bob, 10, 1), None),
+            Err(StateError::InvalidNonce { expected: 0, got: 1, .. })
+        ));
+
+        let mut forged = transfer(&alice, bob, 10, 0);
+        forged.transfer.as_mut().unwrap().from = bob;
+        assert!(matches!(state.apply_transaction(&forged, None), Err(StateError::InvalidSender { .. })));
+
+        let unsigned = Transaction::new_transfer(Transfer { from: address(&alice), to: bob, amount: 10 });
+        assert_eq!(state.apply_transaction(&unsigned, None), Err(StateError::MissingSender));
+
+        assert_eq!(state.balance(&address(&alice)), 100);
+        assert_eq!(state.nonce(&address(&alice)), 0);
+    }
+
+    #[test]
+    fn test_apply_block_is_atomic() {
+        let alice = PrivateKey::generate_key();
+        let bob = address(&PrivateKey::generate_key());
+        let mut state = genesis(address(&alice), 100);
+
+        let mut b = Block::random_block(1);
+        assert!(b.add_transaction(&transfer(&alice, bob, 60, 0)).is_ok());
+        assert!(b.add_transaction(&transfer(&alice, bob, 60, 1)).is_ok());
+        assert!(state.apply_block(&b).is_err());
+        assert_eq!(state.balance(&address(&alice)), 100);
+        assert_eq!(state.balance(&bob), 0);
+
+        let mut b = Block::random_block(1);
+        assert!(b.add_transaction(&transfer(&alice, bob, 60, 0)).is_ok());
+        assert!(b.add_transaction(&transfer(&alice, bob, 40, 1)).is_ok());
+        assert!(state.apply_block(&b).is_ok());
+        assert_eq!(state.balance(&address(&alice)), 0);
+        assert_eq!(state.balance(&bob), 100);
+    }
+
+    #[test]
+    fn test_execute_contract() {
+        let alice = PrivateKey::generate_key();
+        let mut state = State::new();
+        let call = |nonce: u64, code: &[u8]| {
+            let mut tx = Transaction::new(code.to_vec()).unwrap();
+            tx.nonce = nonce;
+            assert!(tx.sign(&alice).is_ok());
+            tx
+        };
+
+        // Increments slot 0 on every call.
+        let counter = assemble("push 0 sload push 1 add push 0 sstore").unwrap();
+        let contract = contract_address(&counter);
+        assert!(state.apply_transaction(&call(0, &counter), None).is_ok());
+        assert!(state.apply_transaction(&call(1, &counter), None).is_ok());
+        assert_eq!(state.get_storage(&contract, 0), 2);
+
+        // A failing program still consumes the nonce but writes nothing.
+        let failing = assemble("push 1 push 0 sstore add").unwrap();
+        assert!(state.apply_transaction(&call(2, &failing), None).is_ok());
+        assert_eq!(state.nonce(&address(&alice)), 3);
+        assert_eq!(state.get_storage(&contract_address(&failing), 0), 0);
+    }
+
+    #[test]
+    fn test_gas_fees() {
+        let alice = PrivateKey::generate_key();
+        let coinbase = address(&PrivateKey::generate_key());
+        let bob = address(&PrivateKey::generate_key());
+        let mut state = genesis(address(&alice), 1_000_000);
+        let with_gas = |mut tx: Transaction, gas_limit: u64| {
+            tx.gas_limit = gas_limit;
+            tx.gas_price = 2;
+            assert!(tx.sign(&alice).is_ok());
+            tx
+        };
+
+        // Unused gas is refunded, the rest goes to the coinbase.
+        let mut tx = Transaction::new_transfer(Transfer { from: address(&alice), to: bob, amount: 100 });
+        tx.data = assemble("push 1 push 0 sstore").unwrap();
+        let tx = with_gas(tx, 5_000);
+        let gas = tx.intrinsic_gas() + 102;
+        assert_eq!(state.apply_transaction(&tx, Some(&coinbase)), Ok(gas));
+        assert_eq!(state.balance(&coinbase), gas * 2);
+        assert_eq!(state.balance(&bob), 100);
+        assert_eq!(state.balance(&address(&alice)), 1_000_000 - 100 - gas * 2);
+
+        // Running out of gas reverts the transfer and the storage writes, but
+        // the whole gas limit is charged.
+        let balance = state.balance(&address(&alice));
+        let mut tx = Transaction::new_transfer(Transfer { from: address(&alice), to: bob, amount: 100 });
+        tx.nonce = 1;
+        tx.data = assemble("push 2 push 0 sstore loop: push @loop jump").unwrap();
+        let contract = contract_address(&tx.data);
+        let tx = with_gas(tx, 5_000);
+        assert_eq!(state.apply_transaction(&tx, Some(&coinbase)), Ok(5_000));
+        assert_eq!(state.balance(&address(&alice)), balance - 10_000);
+        assert_eq!(state.balance(&bob), 100);
+        assert_eq!(state.get_storage(&contract, 0), 0);
+        assert_eq!(state.nonce(&address(&alice)), 2);
+
+        let mut tx = Transaction::new(vec![]).unwrap();
+        tx.nonce = 2;
+        let tx = with_gas(tx, 999);
+        assert_eq!(
+            state.apply_transaction(&tx, None),
+            Err(StateError::IntrinsicGas { limit: 999, required: 1_000 })
+        );
+
+        let mut tx = Transaction::new(vec![]).unwrap();
+        tx.nonce = 2;
+        let tx = with_gas(tx, u64::MAX);
+        assert!(matches!(state.apply_transaction(&tx, None), Err(StateError::InsufficientBalance { .. })));
+        assert_eq!(state.nonce(&address(&alice)), 2);
+    }
+}
@@ -1,19 +1,511 @@
-use super::block::Block;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 
-pub trait Storage {
-    fn put(&mut self, b: &Block) -> Result<(), ()>;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use sha2::{Digest, Sha256};
+
+use crate::error::Error;
+use crate::types::hash::Hash;
+
+use super::block::{Block, Header};
+use super::encoding::{Decode, Decoder};
+use super::hasher::{Bytes, Hasher};
+
+// Segments are rolled over once they grow past this size.
+const SEGMENT_SIZE: u64 = 64 * 1024 * 1024;
+// Record header in a segment: payload length + checksum.
+const RECORD_HEADER_LEN: u64 = 8;
+// Index entry: segment id + offset + payload length + header hash.
+const INDEX_ENTRY_LEN: u64 = 48;
+const INDEX_FILE: &str = "index.dat";
+
+#[derive(Debug)]
+pub enum StorageError {
+    Io(io::Error),
+    Corrupt(String),
+    Encoding(Error),
+    InvalidHeight { expected: u32, got: u32 },
+    HeightNotFound(u32),
+    HashNotFound(Hash),
+}
+
+impl fmt::Display for StorageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageError::Io(e) => write!(f, "storage io error: {}", e),
+            StorageError::Corrupt(e) => write!(f, "storage is corrupt: {}", e),
+            StorageError::Encoding(e) => write!(f, "storage encoding error: {}", e),
+            StorageError::InvalidHeight { expected, got } => {
+                write!(f, "block height {} cannot be stored, expected {}", got, expected)
+            }
+            StorageError::HeightNotFound(h) => write!(f, "no block stored at height {}", h),
+            StorageError::HashNotFound(h) => write!(f, "no block stored with hash {}", h),
+        }
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+impl From<io::Error> for StorageError {
+    fn from(e: io::Error) -> Self {
+        StorageError::Io(e)
+    }
+}
+
+impl From<Error> for StorageError {
+    fn from(e: Error) -> Self {
+        StorageError::Encoding(e)
+    }
+}
+
+fn header_hash(header: &Header) -> Result<Hash, StorageError> {
+    Ok(Hasher::new().hash(header)?)
 }
 
-pub struct MemoryStore{}
+pub trait Storage: Send + Sync {
+    fn put(&mut self, b: &Block) -> Result<(), StorageError>;
+    fn get_block_by_height(&self, h: u32) -> Result<Block, StorageError>;
+    fn get_block_by_hash(&self, hash: &Hash) -> Result<Block, StorageError>;
+    fn get_header_by_hash(&self, hash: &Hash) -> Result<Header, StorageError>;
+    // Removes every block above height `h`.
+    fn truncate(&mut self, h: u32) -> Result<(), StorageError>;
+    // Height of the last stored block, None if the store is empty.
+    fn height(&self) -> Option<u32>;
+    // Makes sure everything written so far is on disk.
+    fn flush(&mut self) -> Result<(), StorageError>;
+}
+
+impl<'s> dyn Storage + 's {
+    /// Iterates over the stored blocks from height `from` to `to`, inclusive.
+    pub fn blocks(&self, from: u32, to: u32) -> BlockRange<'_, 's> {
+        BlockRange { store: self, next: from, to }
+    }
+}
+
+pub struct BlockRange<'a, 's> {
+    store: &'a (dyn Storage + 's),
+    next: u32,
+    to: u32,
+}
+
+impl<'a, 's> Iterator for BlockRange<'a, 's> {
+    type Item = Result<Block, StorageError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next > self.to {
+            return None;
+        }
+        let h = self.next;
+        self.next += 1;
+        Some(self.store.get_block_by_height(h))
+    }
+}
+
+pub struct MemoryStore {
+    blocks: Vec<Block>,
+    hashes: HashMap<Hash, u32>,
+}
 
 impl MemoryStore {
     pub fn new() -> Self {
-        MemoryStore {  }
+        MemoryStore { blocks: vec![], hashes: HashMap::new() }
     }
 }
 
 impl Storage for MemoryStore {
-    fn put(&mut self, _b: &Block) -> Result<(), ()> {
+    fn put(&mut self, b: &Block) -> Result<(), StorageError> {
+        let expected = self.blocks.len() as u32;
+        if b.header.height != expected {
+            return Err(StorageError::InvalidHeight { expected, got: b.header.height });
+        }
+        self.hashes.insert(header_hash(&b.header)?, expected);
+        self.blocks.push(b.clone());
+        Ok(())
+    }
+
+    fn get_block_by_height(&self, h: u32) -> Result<Block, StorageError> {
+        self.blocks.get(h as usize).cloned().ok_or(StorageError::HeightNotFound(h))
+    }
+
+    fn get_block_by_hash(&self, hash: &Hash) -> Result<Block, StorageError> {
+        let h = self.hashes.get(hash).ok_or(StorageError::HashNotFound(*hash))?;
+        self.get_block_by_height(*h)
+    }
+
+    fn get_header_by_hash(&self, hash: &Hash) -> Result<Header, StorageError> {
+        self.get_block_by_hash(hash).map(|b| b.header)
+    }
+
+    fn truncate(&mut self, h: u32) -> Result<(), StorageError> {
+        let keep = (h as usize + 1).min(self.blocks.len());
+        self.blocks.truncate(keep);
+        self.hashes.retain(|_, height| (*height as usize) < keep);
+        Ok(())
+    }
+
+    fn height(&self) -> Option<u32> {
+        (self.blocks.len() as u32).checked_sub(1)
+    }
+
+    fn flush(&mut self) -> Result<(), StorageError> {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct IndexEntry {
+    segment: u32,
+    offset: u64,
+    len: u32,
+    hash: Hash,
+}
+
+impl IndexEntry {
+    fn end(&self) -> u64 {
+        self.offset + RECORD_HEADER_LEN + self.len as u64
+    }
+
+    fn encode_binary<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_u32::<LittleEndian>(self.segment)?;
+        writer.write_u64::<LittleEndian>(self.offset)?;
+        writer.write_u32::<LittleEndian>(self.len)?;
+        self.hash.encode_binary(writer)
+    }
+
+    fn decode_binary<R: Read>(reader: &mut R) -> io::Result<IndexEntry> {
+        Ok(IndexEntry {
+            segment: reader.read_u32::<LittleEndian>()?,
+            offset: reader.read_u64::<LittleEndian>()?,
+            len: reader.read_u32::<LittleEndian>()?,
+            hash: Hash::decode_binary(reader)?,
+        })
+    }
+}
+
+fn checksum(payload: &[u8]) -> [u8; 4] {
+    let digest = Sha256::digest(payload);
+    [digest[0], digest[1], digest[2], digest[3]]
+}
+
+/// Append-only block store. Encoded blocks are appended to numbered segment
+/// files and an index file maps each height to its record. Both are fsynced
+/// before `put` returns, and a torn tail left by a crash is cut off on open.
+pub struct DiskStore {
+    dir: PathBuf,
+    index: Vec<IndexEntry>,
+    hashes: HashMap<Hash, u32>,
+    index_file: File,
+    segment: u32,
+    segment_file: File,
+    segment_len: u64,
+}
+
+impl DiskStore {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<DiskStore, StorageError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let mut index_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(INDEX_FILE))?;
+
+        let mut raw = vec![];
+        index_file.read_to_end(&mut raw)?;
+        let mut reader = Cursor::new(raw);
+        let mut index = vec![];
+        for _ in 0..reader.get_ref().len() as u64 / INDEX_ENTRY_LEN {
+            index.push(IndexEntry::decode_binary(&mut reader)?);
+        }
+
+        // Drop entries whose record did not make it to disk in one piece.
+        while let Some(entry) = index.last() {
+            if Self::read_record(&dir, entry).is_ok() {
+                break;
+            }
+            log::warn!("dropping torn block record at height {}", index.len() - 1);
+            index.pop();
+        }
+        index_file.set_len(index.len() as u64 * INDEX_ENTRY_LEN)?;
+        index_file.sync_all()?;
+        index_file.seek(SeekFrom::End(0))?;
+
+        let (segment, segment_len) = match index.last() {
+            Some(entry) => (entry.segment, entry.end()),
+            None => (0, 0),
+        };
+        let segment_file = Self::open_segment(&dir, segment)?;
+        segment_file.set_len(segment_len)?;
+        segment_file.sync_all()?;
+
+        let hashes = index.iter().enumerate().map(|(h, entry)| (entry.hash, h as u32)).collect();
+
+        Ok(DiskStore {
+            dir,
+            index,
+            hashes,
+            index_file,
+            segment,
+            segment_file,
+            segment_len,
+        })
+    }
+
+    fn segment_path(dir: &Path, segment: u32) -> PathBuf {
+        dir.join(format!("segment-{:05}.dat", segment))
+    }
+
+    fn open_segment(dir: &Path, segment: u32) -> io::Result<File> {
+        OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(Self::segment_path(dir, segment))
+    }
+
+    fn read_record(dir: &Path, entry: &IndexEntry) -> Result<Vec<u8>, StorageError> {
+        let mut file = File::open(Self::segment_path(dir, entry.segment))?;
+        file.seek(SeekFrom::Start(entry.offset))?;
+
+        let len = file.read_u32::<LittleEndian>()?;
+        if len != entry.len {
+            return Err(StorageError::Corrupt(format!(
+                "record length {} does not match index length {}",
+                len, entry.len
+            )));
+        }
+        let mut sum = [0u8; 4];
+        file.read_exact(&mut sum)?;
+        let mut payload = vec![0u8; len as usize];
+        file.read_exact(&mut payload)?;
+
+        if checksum(&payload) != sum {
+            return Err(StorageError::Corrupt("record checksum mismatch".to_owned()));
+        }
+        Ok(payload)
+    }
+
+    fn roll_segment(&mut self) -> io::Result<()> {
+        self.segment_file.sync_all()?;
+        self.segment += 1;
+        self.segment_file = Self::open_segment(&self.dir, self.segment)?;
+        self.segment_file.set_len(0)?;
+        self.segment_len = 0;
+        Ok(())
+    }
+}
+
+impl Storage for DiskStore {
+    fn put(&mut self, b: &Block) -> Result<(), StorageError> {
+        let expected = self.index.len() as u32;
+        if b.header.height != expected {
+            return Err(StorageError::InvalidHeight { expected, got: b.header.height });
+        }
+
+        let payload = b.as_bytes()?;
+        if self.segment_len > 0 && self.segment_len + RECORD_HEADER_LEN + payload.len() as u64 > SEGMENT_SIZE {
+            self.roll_segment()?;
+        }
+
+        let entry = IndexEntry {
+            segment: self.segment,
+            offset: self.segment_len,
+            len: payload.len() as u32,
+            hash: header_hash(&b.header)?,
+        };
+
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN as usize + payload.len());
+        record.write_u32::<LittleEndian>(entry.len)?;
+        record.extend_from_slice(&checksum(&payload));
+        record.extend_from_slice(&payload);
+        self.segment_file.write_all(&record)?;
+        self.segment_file.sync_data()?;
+
+        // The index entry is only written once its record is durable, so an
+        // entry on disk always points at a complete record.
+        let mut raw = Vec::with_capacity(INDEX_ENTRY_LEN as usize);
+        entry.encode_binary(&mut raw)?;
+        self.index_file.write_all(&raw)?;
+        self.index_file.sync_data()?;
+
+        self.segment_len = entry.end();
+        self.hashes.insert(entry.hash, expected);
+        self.index.push(entry);
+        Ok(())
+    }
+
+    fn get_block_by_height(&self, h: u32) -> Result<Block, StorageError> {
+        let entry = self.index.get(h as usize).ok_or(StorageError::HeightNotFound(h))?;
+        let mut payload = Cursor::new(Self::read_record(&self.dir, entry)?);
+        let mut decoder = Decoder::new(&mut payload);
+        Ok(decoder.decode()?)
+    }
+
+    fn get_block_by_hash(&self, hash: &Hash) -> Result<Block, StorageError> {
+        let h = self.hashes.get(hash).ok_or(StorageError::HashNotFound(*hash))?;
+        self.get_block_by_height(*h)
+    }
+
+    fn get_header_by_hash(&self, hash: &Hash) -> Result<Header, StorageError> {
+        self.get_block_by_hash(hash).map(|b| b.header)
+    }
+
+    fn truncate(&mut self, h: u32) -> Result<(), StorageError> {
+        let keep = (h as usize + 1).min(self.index.len());
+        if keep == self.index.len() {
+            return Ok(());
+        }
+
+        // Shrink the index first: records past the end of the index are
+        // garbage and get cut off on the next open or overwritten.
+        self.index_file.set_len(keep as u64 * INDEX_ENTRY_LEN)?;
+        self.index_file.sync_all()?;
+        self.index_file.seek(SeekFrom::End(0))?;
+        for entry in self.index.drain(keep..) {
+            self.hashes.remove(&entry.hash);
+        }
+
+        let (segment, segment_len) = match self.index.last() {
+            Some(entry) => (entry.segment, entry.end()),
+            None => (0, 0),
+        };
+        if segment != self.segment {
+            self.segment_file = Self::open_segment(&self.dir, segment)?;
+            self.segment = segment;
+        }
+        self.segment_file.set_len(segment_len)?;
+        self.segment_file.sync_all()?;
+        self.segment_len = segment_len;
+        Ok(())
+    }
+
+    fn height(&self) -> Option<u32> {
+        (self.index.len() as u32).checked_sub(1)
+    }
+
+    fn flush(&mut self) -> Result<(), StorageError> {
+        self.segment_file.sync_all()?;
+        self.index_file.sync_all()?;
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use crate::core::block::Block;
+    use crate::core::hasher::Hasher;
+    use crate::types::hash::Hash;
+
+    use super::{DiskStore, Storage, INDEX_FILE};
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("rustchain-store-{}", Hash::random()))
+    }
+
+    #[test]
+    fn test_disk_store_put_get() {
+        let dir = temp_dir();
+        let mut store = DiskStore::open(&dir).unwrap();
+        assert_eq!(store.height(), None);
+
+        let mut blocks = vec![];
+        for i in 0..10 {
+            let b = Block::random_block_with_signature(i);
+            assert!(store.put(&b).is_ok());
+            blocks.push(b);
+        }
+        assert_eq!(store.height(), Some(9));
+        assert!(store.put(&Block::random_block(42)).is_err());
+
+        for (i, b) in blocks.iter().enumerate() {
+            let hash = Hasher::new().hash(&b.header).unwrap();
+            assert_eq!(&store.get_block_by_height(i as u32).unwrap(), b);
+            assert_eq!(&store.get_block_by_hash(&hash).unwrap(), b);
+            assert_eq!(store.get_header_by_hash(&hash).unwrap(), b.header);
+        }
+        assert!(store.get_block_by_height(10).is_err());
+        assert!(store.get_block_by_hash(&Hash::random()).is_err());
+
+        let store: &dyn Storage = &store;
+        let range: Vec<Block> = store.blocks(2, 5).map(|b| b.unwrap()).collect();
+        assert_eq!(range, blocks[2..6]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_disk_store_reopen() {
+        let dir = temp_dir();
+        let mut store = DiskStore::open(&dir).unwrap();
+        for i in 0..5 {
+            assert!(store.put(&Block::random_block_with_signature(i)).is_ok());
+        }
+        let last = store.get_block_by_height(4).unwrap();
+        drop(store);
+
+        let mut store = DiskStore::open(&dir).unwrap();
+        assert_eq!(store.height(), Some(4));
+        assert_eq!(store.get_block_by_height(4).unwrap(), last);
+        assert!(store.put(&Block::random_block(5)).is_ok());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_disk_store_truncate() {
+        let dir = temp_dir();
+        let mut store = DiskStore::open(&dir).unwrap();
+        let mut blocks = vec![];
+        for i in 0..6 {
+            let b = Block::random_block(i);
+            assert!(store.put(&b).is_ok());
+            blocks.push(b);
+        }
+
+        assert!(store.truncate(2).is_ok());
+        assert_eq!(store.height(), Some(2));
+        assert!(store.get_block_by_height(3).is_err());
+        assert!(store.get_block_by_hash(&Hasher::new().hash(&blocks[3].header).unwrap()).is_err());
+
+        let b = Block::random_block(3);
+        assert!(store.put(&b).is_ok());
+        drop(store);
+
+        let store = DiskStore::open(&dir).unwrap();
+        assert_eq!(store.height(), Some(3));
+        assert_eq!(store.get_block_by_height(2).unwrap(), blocks[2]);
+        assert_eq!(store.get_block_by_height(3).unwrap(), b);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_disk_store_torn_tail() {
+        let dir = temp_dir();
+        let mut store = DiskStore::open(&dir).unwrap();
+        for i in 0..3 {
+            assert!(store.put(&Block::random_block(i)).is_ok());
+        }
+        drop(store);
+
+        // Simulate a crash in the middle of writing an index entry.
+        let mut index = OpenOptions::new().append(true).open(dir.join(INDEX_FILE)).unwrap();
+        index.write_all(&[1, 2, 3]).unwrap();
+        drop(index);
+
+        let mut store = DiskStore::open(&dir).unwrap();
+        assert_eq!(store.height(), Some(2));
+        assert!(store.put(&Block::random_block(3)).is_ok());
+        assert!(store.get_block_by_height(3).is_ok());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,32 +1,65 @@
 use p256::ecdsa::Signature;
 use serde::{Serialize, Deserialize};
-use crate::{types::hash::Hash, core::encoding::{Encode, Decode, Encoder, Decoder}, crypto::keypair::{PublicKey, PrivateKey}};
+use crate::{types::{hash::Hash, address::Address}, core::encoding::{Encode, Encoder}, crypto::keypair::{PublicKey, PrivateKey}, error::Error};
 
 use super::hasher::{Hasher, Bytes};
 
+pub const DEFAULT_CHAIN_ID: u32 = 1;
+pub const DEFAULT_GAS_LIMIT: u64 = 100_000;
+// Gas charged for every transaction before any code runs.
+pub const TX_GAS: u64 = 1_000;
+pub const DATA_BYTE_GAS: u64 = 10;
+
+/// Moves `amount` from `from` to `to`. `from` must be the signer of the
+/// transaction.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
+pub struct Transfer {
+    pub from: Address,
+    pub to: Address,
+    pub amount: u64,
+}
+
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub struct Transaction {
     pub data: Vec<u8>,
+    pub transfer: Option<Transfer>,
+    // Number of transactions the sender has made before this one. Together
+    // with the chain id it is signed, so a transaction cannot be replayed
+    // later or on another chain.
+    pub nonce: u64,
+    pub chain_id: u32,
+    // Most gas the sender is willing to spend, and the fee paid per unit of
+    // gas used.
+    pub gas_limit: u64,
+    pub gas_price: u64,
     pub key: Option<PublicKey>,
     pub signature: Option<Signature>,
+    // Local cache and bookkeeping, not part of the encoded transaction.
+    #[serde(skip)]
     pub hash: Option<Hash>,
+    #[serde(skip)]
     pub seen: Option<i64>,
 }
 
 impl Bytes for Transaction {
-    fn as_bytes(&self) -> Vec<u8> {
+    fn as_bytes(&self) -> Result<Vec<u8>, Error> {
         let mut writer = vec![];
         let mut encoder = Encoder::new(&mut writer);
 
-        encoder.encode(self);
-        writer
+        encoder.encode(self)?;
+        Ok(writer)
     }
 }
 
 impl Transaction {
     pub fn new(data: Vec<u8>) -> Result<Transaction, ()> {
-        let mut tx = Transaction {
+        let tx = Transaction {
             data: data,
+            transfer: None,
+            nonce: 0,
+            chain_id: DEFAULT_CHAIN_ID,
+            gas_limit: DEFAULT_GAS_LIMIT,
+            gas_price: 0,
             key: None,
             signature: None,
             hash: None,
@@ -35,17 +68,57 @@ impl Transaction {
         Ok(tx)
     }
 
+    pub fn new_transfer(transfer: Transfer) -> Transaction {
+        Transaction {
+            data: vec![],
+            transfer: Some(transfer),
+            nonce: 0,
+            chain_id: DEFAULT_CHAIN_ID,
+            gas_limit: DEFAULT_GAS_LIMIT,
+            gas_price: 0,
+            key: None,
+            signature: None,
+            hash: None,
+            seen: None,
+        }
+    }
+
+    // The signature covers the encoded transaction without key and signature.
+    fn signing_bytes(&self) -> Result<Vec<u8>, Error> {
+        let unsigned = Transaction {
+            key: None,
+            signature: None,
+            ..self.clone()
+        };
+        unsigned.as_bytes()
+    }
+
     pub fn sign(&mut self, private_key: &PrivateKey) -> Result<(), String> {
-        self.signature = Some(private_key.sign(&self.data).expect("could not sign"));
+        let body = self.signing_bytes().map_err(|e| e.to_string())?;
+        self.signature = Some(private_key.sign(&body).expect("could not sign"));
         self.key = Some(private_key.generate_public());
         Ok(())
     }
     
 
     pub fn verify(&self) -> Result<(), p256::ecdsa::Error> {
-        assert_eq!(self.signature.is_none(), false);
-        assert_eq!(self.key.is_none(), false);
-        self.key.as_ref().unwrap().verify(&self.data, self.signature.as_ref().unwrap())
+        match (self.key.as_ref(), self.signature.as_ref()) {
+            (Some(key), Some(signature)) => {
+                let body = self.signing_bytes().map_err(|_| p256::ecdsa::Error::new())?;
+                key.verify(&body, signature)
+            }
+            _ => Err(p256::ecdsa::Error::new()),
+        }
+    }
+
+    // Gas used by the transaction before its data is executed.
+    pub fn intrinsic_gas(&self) -> u64 {
+        TX_GAS + DATA_BYTE_GAS * self.data.len() as u64
+    }
+
+    // Address that signed the transaction.
+    pub fn sender(&self) -> Option<Address> {
+        self.key.as_ref().and_then(|key| key.address().ok())
     }
 
     pub fn hash(&mut self, hasher: Hasher) -> Hash 
@@ -70,13 +143,18 @@ impl Transaction {
 mod test {
     use crate::crypto::keypair::PrivateKey;
 
-    use super::Transaction;
+    use super::{Transaction, Transfer};
 
     #[test]
     fn test_sign_transaction() {
         let key = PrivateKey::generate_key();
         let mut tx = Transaction {
             data: br#"foo"#.to_vec(),
+            transfer: None,
+            nonce: 0,
+            chain_id: 1,
+            gas_limit: 0,
+            gas_price: 0,
             key: None,
             signature: None,
             hash: None,
@@ -94,6 +172,11 @@ mod test {
         let key = PrivateKey::generate_key();
         let mut tx = Transaction {
             data: br#"foo"#.to_vec(),
+            transfer: None,
+            nonce: 0,
+            chain_id: 1,
+            gas_limit: 0,
+            gas_price: 0,
             key: None,
             signature: None,
             hash: None,
@@ -114,4 +197,33 @@ mod test {
         assert!(tx.verify().is_err());
 
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_verify_transfer() {
+        let key = PrivateKey::generate_key();
+        let from = key.generate_public().address().unwrap();
+        let mut tx = Transaction::new_transfer(Transfer { from, to: from, amount: 10 });
+
+        assert!(tx.sign(&key).is_ok());
+        assert!(tx.verify().is_ok());
+        assert_eq!(tx.sender(), Some(from));
+
+        // The signature covers the transfer, nonce, chain id and gas, not
+        // only the data.
+        tx.transfer.as_mut().unwrap().amount = 1000;
+        assert!(tx.verify().is_err());
+        tx.transfer.as_mut().unwrap().amount = 10;
+        assert!(tx.verify().is_ok());
+
+        tx.nonce = 1;
+        assert!(tx.verify().is_err());
+        tx.nonce = 0;
+
+        tx.chain_id = 2;
+        assert!(tx.verify().is_err());
+        tx.chain_id = 1;
+
+        tx.gas_price = 5;
+        assert!(tx.verify().is_err());
+    }
+}
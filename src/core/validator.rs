@@ -1,9 +1,74 @@
-use super::{block::Block, blockchain::Blockchain};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
 
+use chrono::Utc;
 
+use crate::types::{address::Address, hash::Hash};
 
-pub trait Validator {
-    fn validate_block(&self, bc: &Blockchain, b: &Block) -> Result<(), ()>;
+use super::{block::Block, blockchain::Blockchain, hasher::Hasher};
+
+pub const SUPPORTED_VERSION: u32 = 1;
+// How far ahead of the local clock a block timestamp may be.
+pub const MAX_FUTURE_DRIFT: Duration = Duration::from_secs(15);
+// Upper bound for the sum of the gas limits of the transactions in a block.
+pub const MAX_BLOCK_GAS: u64 = 10_000_000;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ValidationError {
+    BlockKnown(u32),
+    InvalidHeight { expected: u32, got: u32 },
+    UnknownParent(Hash),
+    BelowFinalized { finalized: u32, got: u32 },
+    TimestampTooOld { parent: i64, got: i64 },
+    TimestampInFuture { now: i64, got: i64 },
+    UnsupportedVersion(u32),
+    InvalidDataHash { expected: Hash, got: Hash },
+    InvalidSignature(String),
+    InvalidChainId { expected: u32, got: u32 },
+    InvalidNonce { address: Address, expected: u64, got: u64 },
+    BlockGasLimitExceeded { limit: u64, got: u64 },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationError::BlockKnown(h) => write!(f, "chain already contains block at height {}", h),
+            ValidationError::InvalidHeight { expected, got } => {
+                write!(f, "invalid block height {}, expected {}", got, expected)
+            }
+            ValidationError::UnknownParent(h) => write!(f, "unknown parent block {}", h),
+            ValidationError::BelowFinalized { finalized, got } => {
+                write!(f, "block height {} is at or below finalized height {}", got, finalized)
+            }
+            ValidationError::TimestampTooOld { parent, got } => {
+                write!(f, "block timestamp {} is not after parent timestamp {}", got, parent)
+            }
+            ValidationError::TimestampInFuture { now, got } => {
+                write!(f, "block timestamp {} is too far ahead of local time {}", got, now)
+            }
+            ValidationError::UnsupportedVersion(v) => write!(f, "unsupported block version {}", v),
+            ValidationError::InvalidDataHash { expected, got } => {
+                write!(f, "invalid transaction root {}, expected {}", got, expected)
+            }
+            ValidationError::InvalidSignature(e) => write!(f, "invalid block signature: {}", e),
+            ValidationError::InvalidChainId { expected, got } => {
+                write!(f, "transaction for chain {}, expected chain {}", got, expected)
+            }
+            ValidationError::InvalidNonce { address, expected, got } => {
+                write!(f, "invalid nonce {} for {}, expected {}", got, address, expected)
+            }
+            ValidationError::BlockGasLimitExceeded { limit, got } => {
+                write!(f, "block uses {} gas, limit is {}", got, limit)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+pub trait Validator: Send + Sync {
+    fn validate_block(&self, bc: &Blockchain, b: &Block) -> Result<(), ValidationError>;
 }
 
 pub struct BlockValidator {}
@@ -12,13 +77,203 @@ impl BlockValidator {
     pub fn new_validator() -> Self {
         BlockValidator {}
     }
+
+    fn validate_header(&self, bc: &Blockchain, b: &Block) -> Result<(), ValidationError> {
+        let header = &b.header;
+        if header.version != SUPPORTED_VERSION {
+            return Err(ValidationError::UnsupportedVersion(header.version));
+        }
+
+        let hash = Hasher::new().hash(header).expect("could not hash");
+        if bc.lookup_header(&hash).is_some() {
+            return Err(ValidationError::BlockKnown(header.height));
+        }
+
+        // The parent may sit on a side branch, not only at the tip.
+        let parent = match bc.lookup_header(&header.prev_block) {
+            Some(parent) => parent,
+            None => return Err(ValidationError::UnknownParent(header.prev_block)),
+        };
+        if header.height != parent.height + 1 {
+            return Err(ValidationError::InvalidHeight { expected: parent.height + 1, got: header.height });
+        }
+        let finalized = bc.finalized_height();
+        if header.height <= finalized {
+            return Err(ValidationError::BelowFinalized { finalized, got: header.height });
+        }
+
+        if header.timestamp <= parent.timestamp {
+            return Err(ValidationError::TimestampTooOld { parent: parent.timestamp, got: header.timestamp });
+        }
+        let now = Utc::now().timestamp_nanos();
+        if header.timestamp > now + MAX_FUTURE_DRIFT.as_nanos() as i64 {
+            return Err(ValidationError::TimestampInFuture { now, got: header.timestamp });
+        }
+
+        let data = Block::data_hash(&b.transactions);
+        if header.data != data {
+            return Err(ValidationError::InvalidDataHash { expected: data, got: header.data });
+        }
+
+        Ok(())
+    }
+
+    // Every transaction must be meant for this chain, and the nonces of each
+    // sender must be consecutive. If the block extends the tip, the first
+    // nonce of a sender must also follow its account nonce. Together the
+    // transactions may not exceed the block gas limit.
+    fn validate_transactions(&self, bc: &Blockchain, b: &Block) -> Result<(), ValidationError> {
+        let gas = b.transactions.iter().fold(0u64, |gas, tx| gas.saturating_add(tx.gas_limit));
+        if gas > MAX_BLOCK_GAS {
+            return Err(ValidationError::BlockGasLimitExceeded { limit: MAX_BLOCK_GAS, got: gas });
+        }
+
+        let chain_id = bc.chain_id();
+        let at_tip = bc.get_height_by_hash(&b.header.prev_block) == Some(bc.height());
+        let mut next: HashMap<Address, u64> = HashMap::new();
+        for tx in &b.transactions {
+            if tx.chain_id != chain_id {
+                return Err(ValidationError::InvalidChainId { expected: chain_id, got: tx.chain_id });
+            }
+
+            let address = match tx.sender() {
+                Some(address) => address,
+                None => return Err(ValidationError::InvalidSignature("transaction is not signed".to_owned())),
+            };
+            let expected = match next.get(&address) {
+                Some(nonce) => Some(*nonce),
+                None if at_tip => Some(bc.get_account(&address).nonce),
+                None => None,
+            };
+            if let Some(expected) = expected {
+                if tx.nonce != expected {
+                    return Err(ValidationError::InvalidNonce { address, expected, got: tx.nonce });
+                }
+            }
+            next.insert(address, tx.nonce.saturating_add(1));
+        }
+        Ok(())
+    }
 }
 
 impl Validator for BlockValidator {
-    fn validate_block(&self, bc: &Blockchain, b: &Block) -> Result<(), ()> {
-        assert!(bc.has_block(b.header.height).is_err());
-        assert!(b.verify().is_ok());
+    fn validate_block(&self, bc: &Blockchain, b: &Block) -> Result<(), ValidationError> {
+        self.validate_header(bc, b)?;
+        self.validate_transactions(bc, b)?;
+        b.verify().map_err(ValidationError::InvalidSignature)
+    }
+}
 
-        Ok(())
+#[cfg(test)]
+mod test {
+    use chrono::Utc;
+
+    use crate::core::{block::Block, blockchain::Blockchain, hasher::Hasher, transaction::Transaction};
+    use crate::crypto::keypair::PrivateKey;
+    use crate::types::hash::Hash;
+
+    use super::{BlockValidator, ValidationError, Validator, MAX_BLOCK_GAS, MAX_FUTURE_DRIFT};
+
+    fn next_block(bc: &Blockchain) -> Block {
+        let parent = bc.get_header(bc.height()).unwrap();
+        let mut b = Block::random_block(parent.height + 1);
+        b.header.prev_block = Hasher::new().hash(&parent).unwrap();
+        b
+    }
+
+    fn validate(bc: &Blockchain, mut b: Block) -> Result<(), ValidationError> {
+        assert!(b.sign(PrivateKey::generate_key()).is_ok());
+        BlockValidator::new_validator().validate_block(bc, &b)
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_validate_block() {
+        let bc = Blockchain::new(&mut Block::random_block(0)).unwrap();
+        assert!(validate(&bc, next_block(&bc)).is_ok());
+
+        let unsigned = next_block(&bc);
+        assert!(matches!(
+            BlockValidator::new_validator().validate_block(&bc, &unsigned),
+            Err(ValidationError::InvalidSignature(_))
+        ));
+    }
+
+    #[test]
+    fn test_validate_header_errors() {
+        let mut genesis = Block::random_block(0);
+        let bc = Blockchain::new(&mut genesis).unwrap();
+
+        let mut b = next_block(&bc);
+        b.header.version = 2;
+        assert_eq!(validate(&bc, b), Err(ValidationError::UnsupportedVersion(2)));
+
+        assert_eq!(validate(&bc, genesis), Err(ValidationError::BlockKnown(0)));
+
+        let mut b = next_block(&bc);
+        b.header.height = 2;
+        assert_eq!(validate(&bc, b), Err(ValidationError::InvalidHeight { expected: 1, got: 2 }));
+
+        let mut b = next_block(&bc);
+        let parent = Hash::random();
+        b.header.prev_block = parent;
+        assert_eq!(validate(&bc, b), Err(ValidationError::UnknownParent(parent)));
+
+        let mut b = next_block(&bc);
+        b.header.timestamp = bc.get_header(0).unwrap().timestamp;
+        assert!(matches!(validate(&bc, b), Err(ValidationError::TimestampTooOld { .. })));
+
+        let mut b = next_block(&bc);
+        b.header.data = Hash::random();
+        assert!(matches!(validate(&bc, b), Err(ValidationError::InvalidDataHash { .. })));
+
+        let mut b = next_block(&bc);
+        b.header.timestamp = Utc::now().timestamp_nanos() + 2 * MAX_FUTURE_DRIFT.as_nanos() as i64;
+        assert!(matches!(validate(&bc, b), Err(ValidationError::TimestampInFuture { .. })));
+    }
+
+    #[test]
+    fn test_validate_transactions() {
+        let bc = Blockchain::new(&mut Block::random_block(0)).unwrap();
+        let key = PrivateKey::generate_key();
+        let tx = |nonce: u64, chain_id: u32| {
+            let mut tx = Transaction::new(vec![nonce as u8]).unwrap();
+            tx.nonce = nonce;
+            tx.chain_id = chain_id;
+            assert!(tx.sign(&key).is_ok());
+            tx
+        };
+        let with_txs = |txs: Vec<Transaction>| {
+            let mut b = next_block(&bc);
+            for tx in &txs {
+                assert!(b.add_transaction(tx).is_ok());
+            }
+            b
+        };
+
+        assert!(validate(&bc, with_txs(vec![tx(0, 1), tx(1, 1)])).is_ok());
+        assert_eq!(
+            validate(&bc, with_txs(vec![tx(0, 2)])),
+            Err(ValidationError::InvalidChainId { expected: 1, got: 2 })
+        );
+        assert!(matches!(
+            validate(&bc, with_txs(vec![tx(1, 1)])),
+            Err(ValidationError::InvalidNonce { expected: 0, got: 1, .. })
+        ));
+        assert!(matches!(
+            validate(&bc, with_txs(vec![tx(0, 1), tx(0, 1)])),
+            Err(ValidationError::InvalidNonce { expected: 1, got: 0, .. })
+        ));
+        assert!(matches!(
+            validate(&bc, with_txs(vec![tx(0, 1), tx(2, 1)])),
+            Err(ValidationError::InvalidNonce { expected: 1, got: 2, .. })
+        ));
+
+        let mut greedy = tx(0, 1);
+        greedy.gas_limit = MAX_BLOCK_GAS + 1;
+        assert!(greedy.sign(&key).is_ok());
+        assert_eq!(
+            validate(&bc, with_txs(vec![greedy])),
+            Err(ValidationError::BlockGasLimitExceeded { limit: MAX_BLOCK_GAS, got: MAX_BLOCK_GAS + 1 })
+        );
+    }
+}
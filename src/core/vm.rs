@@ -0,0 +1,406 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use sha2::{Digest, Sha256};
+
+use crate::types::address::Address;
+
+// Maximum number of values on the stack.
+pub const MAX_STACK: usize = 1024;
+
+pub const STOP: u8 = 0x00;
+// Followed by an 8 byte big endian operand.
+pub const PUSH: u8 = 0x01;
+pub const POP: u8 = 0x02;
+pub const DUP: u8 = 0x03;
+pub const SWAP: u8 = 0x04;
+pub const ADD: u8 = 0x10;
+pub const SUB: u8 = 0x11;
+pub const MUL: u8 = 0x12;
+pub const DIV: u8 = 0x13;
+pub const MOD: u8 = 0x14;
+pub const EQ: u8 = 0x20;
+pub const LT: u8 = 0x21;
+pub const GT: u8 = 0x22;
+pub const NOT: u8 = 0x23;
+pub const JUMP: u8 = 0x30;
+pub const JUMPI: u8 = 0x31;
+pub const SLOAD: u8 = 0x40;
+pub const SSTORE: u8 = 0x41;
+
+const OPCODES: [(&str, u8); 18] = [
+    ("stop", STOP),
+    ("push", PUSH),
+    ("pop", POP),
+    ("dup", DUP),
+    ("swap", SWAP),
+    ("add", ADD),
+    ("sub", SUB),
+    ("mul", MUL),
+    ("div", DIV),
+    ("mod", MOD),
+    ("eq", EQ),
+    ("lt", LT),
+    ("gt", GT),
+    ("not", NOT),
+    ("jump", JUMP),
+    ("jumpi", JUMPI),
+    ("sload", SLOAD),
+    ("sstore", SSTORE),
+];
+
+// Gas charged for executing `opcode`.
+pub fn gas_cost(opcode: u8) -> u64 {
+    match opcode {
+        STOP | PUSH | POP | DUP | SWAP => 1,
+        ADD | SUB | EQ | LT | GT | NOT => 3,
+        MUL | DIV | MOD => 5,
+        JUMP => 8,
+        JUMPI => 10,
+        SLOAD => 50,
+        SSTORE => 100,
+        _ => 0,
+    }
+}
+
+/// Key/value storage of a single contract. Keys that were never written
+/// read as zero.
+pub type ContractStorage = HashMap<i64, i64>;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum VmError {
+    InvalidOpcode { pc: usize, opcode: u8 },
+    TruncatedPush(usize),
+    InvalidJump(i64),
+    StackUnderflow(usize),
+    StackOverflow(usize),
+    DivisionByZero(usize),
+    OutOfGas { pc: usize, limit: u64 },
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::InvalidOpcode { pc, opcode } => write!(f, "invalid opcode {:#04x} at {}", opcode, pc),
+            VmError::TruncatedPush(pc) => write!(f, "push at {} is missing its operand", pc),
+            VmError::InvalidJump(target) => write!(f, "invalid jump target {}", target),
+            VmError::StackUnderflow(pc) => write!(f, "stack underflow at {}", pc),
+            VmError::StackOverflow(pc) => write!(f, "stack overflow at {}", pc),
+            VmError::DivisionByZero(pc) => write!(f, "division by zero at {}", pc),
+            VmError::OutOfGas { pc, limit } => write!(f, "out of gas at {}, limit {}", pc, limit),
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+// Programs are identified by their code, so every run of the same code shares
+// the same storage.
+pub fn contract_address(code: &[u8]) -> Address {
+    let mut hasher = Sha256::new();
+    hasher.update(code);
+    let result = hasher.finalize();
+    Address::from_bytes(&result[result.len() - 20..]).unwrap()
+}
+
+/// Deterministic stack machine. Values are signed 64 bit integers, arithmetic
+/// wraps on overflow. Execution ends at `STOP`, at the end of the code or
+/// when the next instruction would use more than `gas_limit` in total.
+pub struct Vm<'a> {
+    code: &'a [u8],
+    pc: usize,
+    stack: Vec<i64>,
+    storage: &'a mut ContractStorage,
+    gas_limit: u64,
+    gas_used: u64,
+}
+
+impl<'a> Vm<'a> {
+    pub fn new(code: &'a [u8], storage: &'a mut ContractStorage, gas_limit: u64) -> Self {
+        Vm { code, pc: 0, stack: vec![], storage, gas_limit, gas_used: 0 }
+    }
+
+    pub fn stack(&self) -> &[i64] {
+        &self.stack
+    }
+
+    pub fn gas_used(&self) -> u64 {
+        self.gas_used
+    }
+
+    fn pop(&mut self) -> Result<i64, VmError> {
+        self.stack.pop().ok_or(VmError::StackUnderflow(self.pc))
+    }
+
+    fn push(&mut self, value: i64) -> Result<(), VmError> {
+        if self.stack.len() >= MAX_STACK {
+            return Err(VmError::StackOverflow(self.pc));
+        }
+        self.stack.push(value);
+        Ok(())
+    }
+
+    fn binary<F: Fn(i64, i64) -> i64>(&mut self, f: F) -> Result<(), VmError> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        self.push(f(a, b))
+    }
+
+    // Offsets that start an instruction. Jumps may only land on these, not
+    // inside a push operand. Jumping to the end of the code stops execution.
+    fn instructions(&self) -> Vec<bool> {
+        let mut starts = vec![false; self.code.len() + 1];
+        let mut pc = 0;
+        while pc < self.code.len() {
+            starts[pc] = true;
+            pc += if self.code[pc] == PUSH { 9 } else { 1 };
+        }
+        starts[self.code.len()] = true;
+        starts
+    }
+
+    fn jump_target(target: i64, starts: &[bool]) -> Result<usize, VmError> {
+        match usize::try_from(target) {
+            Ok(pc) if starts.get(pc) == Some(&true) => Ok(pc),
+            _ => Err(VmError::InvalidJump(target)),
+        }
+    }
+
+    /// Runs the code to completion. Storage writes are made in place, the
+    /// caller has to discard them if an error is returned.
+    pub fn run(&mut self) -> Result<(), VmError> {
+        let starts = self.instructions();
+        while self.pc < self.code.len() {
+            let pc = self.pc;
+            let opcode = self.code[pc];
+            let gas_used = self.gas_used + gas_cost(opcode);
+            if gas_used > self.gas_limit {
+                return Err(VmError::OutOfGas { pc, limit: self.gas_limit });
+            }
+            self.gas_used = gas_used;
+            let mut next = pc + 1;
+            match opcode {
+                STOP => return Ok(()),
+                PUSH => {
+                    let operand = self.code.get(pc + 1..pc + 9).ok_or(VmError::TruncatedPush(pc))?;
+                    next = pc + 9;
+                    self.push(i64::from_be_bytes(operand.try_into().unwrap()))?;
+                }
+                POP => {
+                    self.pop()?;
+                }
+                DUP => {
+                    let a = self.pop()?;
+                    self.push(a)?;
+                    self.push(a)?;
+                }
+                SWAP => {
+                    let b = self.pop()?;
+                    let a = self.pop()?;
+                    self.push(b)?;
+                    self.push(a)?;
+                }
+                ADD => self.binary(i64::wrapping_add)?,
+                SUB => self.binary(i64::wrapping_sub)?,
+                MUL => self.binary(i64::wrapping_mul)?,
+                DIV | MOD => {
+                    let b = self.pop()?;
+                    let a = self.pop()?;
+                    if b == 0 {
+                        return Err(VmError::DivisionByZero(pc));
+                    }
+                    self.push(if opcode == DIV { a.wrapping_div(b) } else { a.wrapping_rem(b) })?;
+                }
+                EQ => self.binary(|a, b| (a == b) as i64)?,
+                LT => self.binary(|a, b| (a < b) as i64)?,
+                GT => self.binary(|a, b| (a > b) as i64)?,
+                NOT => {
+                    let a = self.pop()?;
+                    self.push((a == 0) as i64)?;
+                }
+                JUMP => {
+                    let target = self.pop()?;
+                    next = Self::jump_target(target, &starts)?;
+                }
+                JUMPI => {
+                    let target = self.pop()?;
+                    let cond = self.pop()?;
+                    if cond != 0 {
+                        next = Self::jump_target(target, &starts)?;
+                    }
+                }
+                SLOAD => {
+                    let key = self.pop()?;
+                    let value = self.storage.get(&key).cloned().unwrap_or(0);
+                    self.push(value)?;
+                }
+                SSTORE => {
+                    let key = self.pop()?;
+                    let value = self.pop()?;
+                    self.storage.insert(key, value);
+                }
+                opcode => return Err(VmError::InvalidOpcode { pc, opcode }),
+            }
+            self.pc = next;
+        }
+        Ok(())
+    }
+}
+
+/// Translates assembly into bytecode. Instructions are separated by
+/// whitespace, `;` starts a comment, `name:` defines a label and `push`
+/// takes either an integer or `@name` for the offset of a label.
+pub fn assemble(source: &str) -> Result<Vec<u8>, String> {
+    let tokens: Vec<&str> = source
+        .lines()
+        .flat_map(|line| line.split(';').next().unwrap().split_whitespace())
+        .collect();
+
+    // First pass resolves label offsets, second pass emits the code.
+    let mut labels = HashMap::new();
+    let mut offset = 0;
+    let mut i = 0;
+    while i < tokens.len() {
+        if let Some(label) = tokens[i].strip_suffix(':') {
+            if labels.insert(label, offset as i64).is_some() {
+                return Err(format!("label {} is defined twice", label));
+            }
+        } else if tokens[i] == "push" {
+            offset += 9;
+            i += 1;
+        } else {
+            offset += 1;
+        }
+        i += 1;
+    }
+
+    let mut code = vec![];
+    let mut tokens = tokens.into_iter();
+    while let Some(token) = tokens.next() {
+        if token.ends_with(':') {
+            continue;
+        }
+        let opcode = match OPCODES.iter().find(|(name, _)| *name == token) {
+            Some((_, opcode)) => *opcode,
+            None => return Err(format!("unknown instruction {}", token)),
+        };
+        code.push(opcode);
+        if opcode == PUSH {
+            let operand = tokens.next().ok_or("push is missing its operand")?;
+            let value = match operand.strip_prefix('@') {
+                Some(label) => *labels.get(label).ok_or(format!("unknown label {}", label))?,
+                None => operand.parse::<i64>().map_err(|e| format!("invalid operand {}: {}", operand, e))?,
+            };
+            code.extend_from_slice(&value.to_be_bytes());
+        }
+    }
+    Ok(code)
+}
+
+/// Runs `code` against `storage` and returns the gas it used. The storage is
+/// only updated if the code runs to completion.
+pub fn execute(code: &[u8], storage: &mut ContractStorage, gas_limit: u64) -> Result<u64, VmError> {
+    let mut scratch = storage.clone();
+    let mut vm = Vm::new(code, &mut scratch, gas_limit);
+    vm.run()?;
+    let gas_used = vm.gas_used();
+    *storage = scratch;
+    Ok(gas_used)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{assemble, execute, ContractStorage, Vm, VmError, MAX_STACK};
+
+    const GAS: u64 = 1_000_000;
+
+    fn run(source: &str) -> Result<Vec<i64>, VmError> {
+        let code = assemble(source).unwrap();
+        let mut storage = ContractStorage::new();
+        let mut vm = Vm::new(&code, &mut storage, GAS);
+        vm.run()?;
+        Ok(vm.stack().to_vec())
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(run("push 2 push 3 add push 4 mul").unwrap(), vec![20]);
+        assert_eq!(run("push 7 push 2 sub push 2 div").unwrap(), vec![2]);
+        assert_eq!(run("push 7 push 3 mod").unwrap(), vec![1]);
+        assert_eq!(run("push 1 push 2 swap pop dup").unwrap(), vec![2, 2]);
+        assert_eq!(run("push 9223372036854775807 push 1 add").unwrap(), vec![i64::MIN]);
+        assert_eq!(run("push 1 push 2 lt push 1 push 2 gt push 3 push 3 eq push 0 not").unwrap(), vec![1, 0, 1, 1]);
+        assert_eq!(run("push 1 stop push 2").unwrap(), vec![1]);
+    }
+
+    #[test]
+    fn test_jumps() {
+        // Counts down from 5, leaving the number of iterations in slot 0.
+        let source = "
+            push 5
+            loop:
+                dup push 0 eq push @end jumpi
+                push 0 sload push 1 add push 0 sstore
+                push 1 sub
+                push @loop jump
+            end:
+        ";
+        let code = assemble(source).unwrap();
+        let mut storage = ContractStorage::new();
+        assert!(execute(&code, &mut storage, GAS).is_ok());
+        assert_eq!(storage.get(&0), Some(&5));
+
+        assert_eq!(run("push 0 push @end jumpi push 1 end:").unwrap(), vec![1]);
+        // Jumping into the operand of a push is not allowed.
+        assert_eq!(run("push 1 jump"), Err(VmError::InvalidJump(1)));
+        assert_eq!(run("push -1 jump"), Err(VmError::InvalidJump(-1)));
+    }
+
+    #[test]
+    fn test_storage() {
+        let mut storage = ContractStorage::new();
+        let code = assemble("push 42 push 7 sstore push 7 sload push 1 sload").unwrap();
+        assert!(execute(&code, &mut storage, GAS).is_ok());
+        assert_eq!(storage.get(&7), Some(&42));
+
+        // Failed runs leave the storage untouched.
+        let code = assemble("push 1 push 7 sstore pop").unwrap();
+        assert_eq!(execute(&code, &mut storage, GAS), Err(VmError::StackUnderflow(19)));
+        assert_eq!(storage.get(&7), Some(&42));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(run("add"), Err(VmError::StackUnderflow(0)));
+        assert_eq!(run("push 1 push 0 div"), Err(VmError::DivisionByZero(18)));
+
+        let mut storage = ContractStorage::new();
+        assert_eq!(Vm::new(&[0xff], &mut storage, GAS).run(), Err(VmError::InvalidOpcode { pc: 0, opcode: 0xff }));
+        assert_eq!(Vm::new(&[0x01, 0x00], &mut storage, GAS).run(), Err(VmError::TruncatedPush(0)));
+
+        let overflow = "push 1 ".repeat(MAX_STACK + 1);
+        assert!(matches!(run(&overflow), Err(VmError::StackOverflow(_))));
+
+        assert!(assemble("push").is_err());
+        assert!(assemble("push @nowhere").is_err());
+        assert!(assemble("jmp").is_err());
+        assert!(assemble("a: a:").is_err());
+    }
+
+    #[test]
+    fn test_gas() {
+        let mut storage = ContractStorage::new();
+        let code = assemble("push 2 push 3 add push 0 sstore").unwrap();
+        assert_eq!(execute(&code, &mut storage, GAS), Ok(1 + 1 + 3 + 1 + 100));
+        assert_eq!(execute(&code, &mut storage, 106), Ok(106));
+
+        // Running out of gas discards the storage writes.
+        let code = assemble("push 7 push 0 sstore push 1 push 2 add").unwrap();
+        assert_eq!(execute(&code, &mut storage, 104), Err(VmError::OutOfGas { pc: 37, limit: 104 }));
+        assert_eq!(storage.get(&0), Some(&5));
+
+        // Endless loops are stopped by the gas limit.
+        let code = assemble("loop: push @loop jump").unwrap();
+        assert!(matches!(execute(&code, &mut storage, GAS), Err(VmError::OutOfGas { .. })));
+    }
+}
@@ -1 +1,2 @@
-pub mod keypair;
\ No newline at end of file
+pub mod keypair;
+pub mod keystore;
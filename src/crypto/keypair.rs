@@ -32,6 +32,27 @@ impl PrivateKey {
         PrivateKey{key: secret_key_serialized}
     }
 
+    // Key from a PKCS#8 PEM document, as written by `to_pem`.
+    pub fn from_pem(pem: &str) -> Result<Self, String> {
+        pem.parse::<SecretKey>().map_err(|e| format!("invalid private key: {}", e))?;
+        Ok(PrivateKey{key: pem.to_owned()})
+    }
+
+    pub fn to_pem(&self) -> &str {
+        &self.key
+    }
+
+    // Key from its 32 byte big-endian scalar, as returned by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let secret_key = SecretKey::from_be_bytes(bytes).map_err(|e| format!("invalid private key: {}", e))?;
+        let pem = secret_key.to_pkcs8_pem(Default::default()).map_err(|e| e.to_string())?;
+        Ok(PrivateKey{key: pem.to_string()})
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.key.parse::<SecretKey>().expect("error").to_be_bytes().to_vec()
+    }
+
     pub fn generate_public(&self) -> PublicKey {
         let parsed = self.key.parse::<SecretKey>().unwrap().public_key();
         PublicKey{ key: parsed }
@@ -98,4 +119,17 @@ mod test {
         assert!(public.verify("hello".as_bytes(), signature.as_ref().unwrap()).is_err());
         assert!(other_public.verify(message, &signature.unwrap()).is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_key_round_trip() {
+        let private = PrivateKey::generate_key();
+        let parsed = PrivateKey::from_pem(private.to_pem()).unwrap();
+        assert_eq!(parsed.generate_public(), private.generate_public());
+
+        assert!(PrivateKey::from_pem("not a key").is_err());
+
+        let from_bytes = PrivateKey::from_bytes(&private.to_bytes()).unwrap();
+        assert_eq!(from_bytes.generate_public(), private.generate_public());
+        assert!(PrivateKey::from_bytes(&[0; 32]).is_err());
+    }
+}
@@ -0,0 +1,271 @@
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use aes_gcm::aead::{Aead, Nonce, Payload};
+use aes_gcm::{Aes256Gcm, KeyInit};
+use serde_derive::{Deserialize, Serialize};
+
+use super::keypair::PrivateKey;
+
+pub const KEYSTORE_VERSION: u32 = 1;
+const CIPHER: &str = "aes-256-gcm";
+const KDF: &str = "scrypt";
+// scrypt cost of new keystores, 2^15 rounds take a fraction of a second.
+const DEFAULT_LOG_N: u8 = 15;
+// Keystores asking for more rounds than this are refused rather than run
+// out of memory.
+const MAX_LOG_N: u8 = 20;
+// Keeps tests fast, real keystores use DEFAULT_LOG_N.
+#[cfg(test)]
+pub(crate) const TEST_LOG_N: u8 = 4;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+const KEY_LEN: usize = 32;
+const SALT_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug)]
+pub enum KeystoreError {
+    Io(io::Error),
+    Format(String),
+    // Wrong password, or the file was tampered with.
+    Decrypt,
+}
+
+impl fmt::Display for KeystoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeystoreError::Io(e) => write!(f, "{}", e),
+            KeystoreError::Format(e) => write!(f, "invalid keystore: {}", e),
+            KeystoreError::Decrypt => write!(f, "could not decrypt keystore, wrong password?"),
+        }
+    }
+}
+
+impl std::error::Error for KeystoreError {}
+
+impl From<io::Error> for KeystoreError {
+    fn from(e: io::Error) -> Self {
+        KeystoreError::Io(e)
+    }
+}
+
+/// A private key encrypted with a password, stored as JSON. The password is
+/// stretched with scrypt into an AES-256-GCM key, which also authenticates
+/// the address stored next to the ciphertext.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Keystore {
+    version: u32,
+    // Address of the key, in the clear to tell keystores apart.
+    address: String,
+    crypto: Crypto,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct Crypto {
+    cipher: String,
+    ciphertext: String,
+    nonce: String,
+    kdf: String,
+    kdfparams: KdfParams,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+struct KdfParams {
+    log_n: u8,
+    r: u32,
+    p: u32,
+    salt: String,
+}
+
+impl KdfParams {
+    fn derive_key(&self, password: &str) -> Result<[u8; KEY_LEN], KeystoreError> {
+        if self.log_n > MAX_LOG_N {
+            return Err(KeystoreError::Format(format!("scrypt cost 2^{} is too high", self.log_n)));
+        }
+        let params = scrypt::Params::new(self.log_n, self.r, self.p, KEY_LEN)
+            .map_err(|e| KeystoreError::Format(format!("invalid scrypt parameters: {}", e)))?;
+        let salt = decode_hex(&self.salt, "salt")?;
+        let mut key = [0u8; KEY_LEN];
+        scrypt::scrypt(password.as_bytes(), &salt, &params, &mut key).map_err(|e| KeystoreError::Format(e.to_string()))?;
+        Ok(key)
+    }
+}
+
+fn decode_hex(s: &str, field: &str) -> Result<Vec<u8>, KeystoreError> {
+    hex::decode(s).map_err(|e| KeystoreError::Format(format!("{}: {}", field, e)))
+}
+
+impl FromStr for Keystore {
+    type Err = KeystoreError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(s).map_err(|e| KeystoreError::Format(e.to_string()))
+    }
+}
+
+impl Keystore {
+    pub fn encrypt(key: &PrivateKey, password: &str) -> Result<Keystore, KeystoreError> {
+        Keystore::encrypt_with_cost(key, password, DEFAULT_LOG_N)
+    }
+
+    pub(crate) fn encrypt_with_cost(key: &PrivateKey, password: &str, log_n: u8) -> Result<Keystore, KeystoreError> {
+        let address = key.generate_public().address().map_err(KeystoreError::Format)?.to_string();
+        let kdfparams = KdfParams {
+            log_n,
+            r: SCRYPT_R,
+            p: SCRYPT_P,
+            salt: hex::encode(rand::random::<[u8; SALT_LEN]>()),
+        };
+        let cipher = Aes256Gcm::new_from_slice(&kdfparams.derive_key(password)?).expect("key has the cipher's length");
+        let nonce = rand::random::<[u8; NONCE_LEN]>();
+        let plaintext = key.to_bytes();
+        let payload = Payload { msg: &plaintext, aad: address.as_bytes() };
+        let ciphertext = cipher
+            .encrypt(&Nonce::<Aes256Gcm>::from(nonce), payload)
+            .map_err(|_| KeystoreError::Format("could not encrypt key".to_owned()))?;
+
+        Ok(Keystore {
+            version: KEYSTORE_VERSION,
+            address,
+            crypto: Crypto {
+                cipher: CIPHER.to_owned(),
+                ciphertext: hex::encode(ciphertext),
+                nonce: hex::encode(nonce),
+                kdf: KDF.to_owned(),
+                kdfparams,
+            },
+        })
+    }
+
+    pub fn decrypt(&self, password: &str) -> Result<PrivateKey, KeystoreError> {
+        if self.version != KEYSTORE_VERSION {
+            return Err(KeystoreError::Format(format!("unsupported version {}", self.version)));
+        }
+        if self.crypto.cipher != CIPHER || self.crypto.kdf != KDF {
+            return Err(KeystoreError::Format(format!("unsupported cipher {} with {}", self.crypto.cipher, self.crypto.kdf)));
+        }
+        let nonce = decode_hex(&self.crypto.nonce, "nonce")?;
+        let nonce = Nonce::<Aes256Gcm>::try_from(nonce.as_slice())
+            .map_err(|_| KeystoreError::Format(format!("nonce must be {} bytes", NONCE_LEN)))?;
+        let ciphertext = decode_hex(&self.crypto.ciphertext, "ciphertext")?;
+
+        let cipher = Aes256Gcm::new_from_slice(&self.crypto.kdfparams.derive_key(password)?).expect("key has the cipher's length");
+        let payload = Payload { msg: &ciphertext, aad: self.address.as_bytes() };
+        let plaintext = cipher.decrypt(&nonce, payload).map_err(|_| KeystoreError::Decrypt)?;
+        let key = PrivateKey::from_bytes(&plaintext).map_err(KeystoreError::Format)?;
+        if key.generate_public().address().map_err(KeystoreError::Format)?.to_string() != self.address {
+            return Err(KeystoreError::Format("address does not match the key".to_owned()));
+        }
+        Ok(key)
+    }
+
+    pub fn address(&self) -> &str {
+        &self.address
+    }
+
+    /// Writes the keystore to a new file only its owner can read. Existing
+    /// files are not overwritten.
+    pub fn save(&self, path: &Path) -> Result<(), KeystoreError> {
+        let json = serde_json::to_string_pretty(self).map_err(|e| KeystoreError::Format(e.to_string()))?;
+        let mut options = OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut file = options.open(path)?;
+        file.write_all(json.as_bytes())?;
+        file.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Reads a private key from `path`. Keystores are decrypted with the
+/// password returned by `password`, which is not called for plain PEM files.
+pub fn read_key<F>(path: &Path, password: F) -> Result<PrivateKey, KeystoreError>
+where
+    F: FnOnce() -> io::Result<String>,
+{
+    let text = fs::read_to_string(path)?;
+    if text.trim_start().starts_with("-----BEGIN") {
+        return PrivateKey::from_pem(&text).map_err(KeystoreError::Format);
+    }
+    let keystore: Keystore = text.parse()?;
+    keystore.decrypt(&password()?)
+}
+
+/// Password read from the first line of `file`, or asked for on the
+/// terminal with `prompt` if there is no file.
+pub fn read_password(file: Option<&Path>, prompt: &str) -> io::Result<String> {
+    match file {
+        Some(file) => Ok(fs::read_to_string(file)?.lines().next().unwrap_or_default().to_owned()),
+        None => rpassword::prompt_password(prompt),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::types::hash::Hash;
+
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let key = PrivateKey::generate_key();
+        let keystore = Keystore::encrypt_with_cost(&key, "secret", TEST_LOG_N).unwrap();
+        assert_eq!(keystore.address(), key.generate_public().address().unwrap().to_string());
+        assert_eq!(keystore.decrypt("secret").unwrap(), key);
+        assert!(!keystore.crypto.ciphertext.contains(&hex::encode(key.to_bytes())));
+
+        assert!(matches!(keystore.decrypt("wrong"), Err(KeystoreError::Decrypt)));
+
+        // The address is authenticated along with the key.
+        let json = serde_json::to_string(&keystore).unwrap();
+        let mut tampered: Keystore = serde_json::from_str(&json).unwrap();
+        tampered.address = PrivateKey::generate_key().generate_public().address().unwrap().to_string();
+        assert!(matches!(tampered.decrypt("secret"), Err(KeystoreError::Decrypt)));
+
+        let mut future: Keystore = serde_json::from_str(&json).unwrap();
+        future.version = KEYSTORE_VERSION + 1;
+        assert!(matches!(future.decrypt("secret"), Err(KeystoreError::Format(_))));
+
+        let mut costly: Keystore = serde_json::from_str(&json).unwrap();
+        costly.crypto.kdfparams.log_n = MAX_LOG_N + 1;
+        assert!(matches!(costly.decrypt("secret"), Err(KeystoreError::Format(_))));
+    }
+
+    #[test]
+    fn test_files() {
+        let dir = std::env::temp_dir().join(format!("rustchain-keystore-{}", Hash::random()));
+        fs::create_dir_all(&dir).unwrap();
+        let key = PrivateKey::generate_key();
+
+        let path = dir.join("keystore.json");
+        let keystore = Keystore::encrypt_with_cost(&key, "secret", TEST_LOG_N).unwrap();
+        assert!(keystore.save(&path).is_ok());
+        assert!(matches!(keystore.save(&path), Err(KeystoreError::Io(_))));
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap().parse::<Keystore>().unwrap(), keystore);
+
+        let password_file = dir.join("password");
+        fs::write(&password_file, "secret\n").unwrap();
+        let password = || read_password(Some(&password_file), "");
+        assert_eq!(read_key(&path, password).unwrap(), key);
+
+        // Plain PEM files need no password.
+        let pem = dir.join("key.pem");
+        fs::write(&pem, key.to_pem()).unwrap();
+        assert_eq!(read_key(&pem, || panic!("asked for a password")).unwrap(), key);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,18 @@
+use std::fmt;
+
+#[derive(Debug)]
+pub enum Error {
+    Encode(String),
+    Decode(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Encode(e) => write!(f, "could not encode: {}", e),
+            Error::Decode(e) => write!(f, "could not decode: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
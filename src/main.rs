@@ -1,47 +1,227 @@
-use std::{time, thread};
-use crypto::keypair::PrivateKey;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::mpsc;
+
+use clap::{Parser, Subcommand};
+use serde_json::{json, Value};
 use simple_logger::SimpleLogger;
-use network::{local_transport::LocalTransport, transport::{Transport, TransportWrapper}, server::{ServerOpts, Server}, rpc::default_rpc_decode_func};
 
+use crate::config::NodeConfig;
+use crate::core::hasher::Bytes;
+use crate::core::transaction::{Transaction, Transfer, DEFAULT_CHAIN_ID, DEFAULT_GAS_LIMIT};
+use crate::crypto::keypair::PrivateKey;
+use crate::crypto::keystore::{self, Keystore};
+use crate::network::api;
+use crate::network::server::Server;
+use crate::types::address::Address;
+
+mod config;
 mod network;
 mod core;
 mod types;
 mod crypto;
+mod error;
 
-fn main() {
-    SimpleLogger::new().with_threads(true).init().unwrap();
+const DEFAULT_RPC: &str = "127.0.0.1:8545";
 
-    let mut tr_local = LocalTransport::new("LOCAL".to_owned());
-    let mut tr_remote = LocalTransport::new("REMOTE".to_owned());
+#[derive(Parser)]
+#[command(about = "Blockchain node and tools to talk to it")]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
 
-    tr_local.connect(TransportWrapper::Local(&tr_remote));
+#[derive(Subcommand)]
+enum Command {
+    /// Runs a node.
+    Node {
+        #[command(subcommand)]
+        command: NodeCommand,
+    },
+    /// Generates a private key and writes it to a password-encrypted
+    /// keystore file.
+    Keygen {
+        #[arg(long, default_value = "keystore.json")]
+        out: PathBuf,
+        /// File holding the password, asked for if not given.
+        #[arg(long)]
+        password_file: Option<PathBuf>,
+    },
+    /// Signs and submits transactions.
+    Tx {
+        #[command(subcommand)]
+        command: TxCommand,
+    },
+    /// Inspects the chain of a running node.
+    Chain {
+        #[command(subcommand)]
+        command: ChainCommand,
+    },
+}
 
-    tr_remote.connect(TransportWrapper::Local(&tr_local));
+#[derive(Subcommand)]
+enum NodeCommand {
+    /// Starts a node and runs it until interrupted.
+    Run {
+        #[arg(long, default_value = "node.toml")]
+        config: PathBuf,
+    },
+}
 
-    let sec = time::Duration::from_secs(1);
+#[derive(Subcommand)]
+enum TxCommand {
+    /// Signs a transaction and prints it hex encoded, ready for `tx send`.
+    Sign {
+        /// Keystore or PEM file with the key of the sender.
+        #[arg(long)]
+        key: PathBuf,
+        /// File holding the keystore password, asked for if not given.
+        #[arg(long)]
+        password_file: Option<PathBuf>,
+        #[arg(long)]
+        nonce: u64,
+        /// Recipient of a transfer, hex encoded.
+        #[arg(long, requires = "amount")]
+        to: Option<String>,
+        #[arg(long)]
+        amount: Option<u64>,
+        /// Hex encoded data to execute.
+        #[arg(long, default_value = "")]
+        data: String,
+        #[arg(long, default_value_t = DEFAULT_GAS_LIMIT)]
+        gas_limit: u64,
+        #[arg(long, default_value_t = 0)]
+        gas_price: u64,
+        #[arg(long, default_value_t = DEFAULT_CHAIN_ID)]
+        chain_id: u32,
+    },
+    /// Submits a signed transaction to a node and prints its hash.
+    Send {
+        /// Hex encoded transaction, as printed by `tx sign`.
+        tx: String,
+        #[arg(long, default_value = DEFAULT_RPC)]
+        rpc: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ChainCommand {
+    /// Prints a block, the tip if no height is given.
+    Show {
+        height: Option<u32>,
+        #[arg(long, default_value = DEFAULT_RPC)]
+        rpc: String,
+    },
+}
+
+fn main() -> ExitCode {
+    let result = match Cli::parse().command {
+        Command::Node { command: NodeCommand::Run { config } } => run_node(&config),
+        Command::Keygen { out, password_file } => keygen(&out, password_file.as_deref()),
+        Command::Tx { command: TxCommand::Sign { key, password_file, nonce, to, amount, data, gas_limit, gas_price, chain_id } } => {
+            let key = read_key(&key, password_file.as_deref());
+            key.and_then(|key| sign_transaction(&key, nonce, to.zip(amount), &data, gas_limit, gas_price, chain_id))
+        }
+        Command::Tx { command: TxCommand::Send { tx, rpc } } => print_result(api::call(&rpc, "sendTransaction", vec![json!(tx)])),
+        Command::Chain { command: ChainCommand::Show { height, rpc } } => show_block(&rpc, height),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
 
-    let local_addr = tr_local.addr();
+fn run_node(path: &Path) -> Result<(), Box<dyn Error>> {
+    let config = NodeConfig::load(path)?;
+    SimpleLogger::new().with_level(config.log_level()?).with_threads(true).init()?;
 
+    let server = Server::new(config.server_opts()?)?;
+    let handle = server.start();
 
+    let (interrupted, interrupt) = mpsc::channel();
+    ctrlc::set_handler(move || {
+        let _ = interrupted.send(());
+    })?;
+    let _ = interrupt.recv();
+    log::info!("shutting down");
+    handle.shutdown()?;
+    Ok(())
+}
 
-    thread::spawn(move || {
-        loop {
-        tr_remote.send_message(local_addr.clone() ,"Hello World".as_bytes().to_vec());
-        thread::sleep(sec);
+fn keygen(out: &Path, password_file: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    if out.exists() {
+        return Err(format!("{} already exists", out.display()).into());
+    }
+    let password = match password_file {
+        Some(_) => keystore::read_password(password_file, "")?,
+        None => {
+            let password = keystore::read_password(None, "New password: ")?;
+            if keystore::read_password(None, "Repeat password: ")? != password {
+                return Err("passwords do not match".into());
+            }
+            password
         }
-    });
-
-    let mut opts = ServerOpts {
-        transports: Vec::new(),
-        block_time: Some(time::Duration::new(300, 0)),
-        key: Some(PrivateKey::generate_key()),
-        rpc_decode_func: default_rpc_decode_func,
     };
+    let keystore = Keystore::encrypt(&PrivateKey::generate_key(), &password)?;
+    keystore.save(out).map_err(|e| format!("could not write {}: {}", out.display(), e))?;
+    println!("{}", keystore.address());
+    Ok(())
+}
 
-    opts.transports.push(Box::new(tr_local.clone()));
+fn read_key(path: &Path, password_file: Option<&Path>) -> Result<PrivateKey, Box<dyn Error>> {
+    let key = keystore::read_key(path, || keystore::read_password(password_file, &format!("Password for {}: ", path.display())));
+    Ok(key.map_err(|e| format!("could not read {}: {}", path.display(), e))?)
+}
 
-    let server = Server::new(opts);
+fn sign_transaction(
+    key: &PrivateKey,
+    nonce: u64,
+    transfer: Option<(String, u64)>,
+    data: &str,
+    gas_limit: u64,
+    gas_price: u64,
+    chain_id: u32,
+) -> Result<(), Box<dyn Error>> {
+    let mut tx = match transfer {
+        Some((to, amount)) => {
+            let from = key.generate_public().address()?;
+            let to = Address::from_bytes(&hex::decode(to.trim_start_matches("0x"))?)?;
+            Transaction::new_transfer(Transfer { from, to, amount })
+        }
+        None => Transaction::new(vec![]).map_err(|_| "could not create transaction")?,
+    };
+    tx.data = hex::decode(data.trim_start_matches("0x"))?;
+    tx.nonce = nonce;
+    tx.gas_limit = gas_limit;
+    tx.gas_price = gas_price;
+    tx.chain_id = chain_id;
+    tx.sign(key)?;
+    println!("{}", hex::encode(tx.as_bytes()?));
+    Ok(())
+}
 
-    server.start();
+fn show_block(rpc: &str, height: Option<u32>) -> Result<(), Box<dyn Error>> {
+    let height = match height {
+        Some(height) => height,
+        None => api::call(rpc, "getHeight", vec![])?
+            .as_u64()
+            .and_then(|height| u32::try_from(height).ok())
+            .ok_or("node sent an invalid height")?,
+    };
+    match api::call(rpc, "getBlockByHeight", vec![json!(height)])? {
+        Value::Null => Err(format!("no block at height {}", height).into()),
+        block => print_result(Ok(block)),
+    }
+}
 
+fn print_result(result: Result<Value, String>) -> Result<(), Box<dyn Error>> {
+    match result? {
+        Value::String(s) => println!("{}", s),
+        value => println!("{}", serde_json::to_string_pretty(&value)?),
+    }
+    Ok(())
 }
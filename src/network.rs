@@ -1,6 +1,10 @@
+pub mod api;
 pub mod local_transport;
+pub mod peers;
+pub mod tcp_transport;
 pub mod server;
 pub mod transport;
 pub mod txpool;
+pub mod ws;
 pub mod channel;
 pub mod rpc;
\ No newline at end of file
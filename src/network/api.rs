@@ -0,0 +1,453 @@
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use crate::core::block::{Block, Header};
+use crate::core::hasher::Hasher;
+use crate::core::transaction::Transaction;
+use crate::types::address::Address;
+use crate::types::hash::Hash;
+
+// How long a client may take to send its whole request.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+// How often the listener checks whether the server is shutting down.
+const ACCEPT_POLL: Duration = Duration::from_millis(100);
+// Most bytes of request line and headers taken together.
+const MAX_HEAD: u64 = 8 << 10;
+const MAX_BODY: usize = 1 << 20;
+// Most requests answered at the same time, later clients are turned away.
+const MAX_CONNECTIONS: usize = 64;
+// How long `call` waits for an answer, a bit longer than the node waits for
+// its server loop.
+const CALL_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Error returned to the caller, with the codes of the JSON-RPC 2.0
+/// specification.
+#[derive(Debug, PartialEq)]
+pub enum ApiError {
+    Parse(String),
+    InvalidRequest(String),
+    MethodNotFound(String),
+    InvalidParams(String),
+    // The node could not carry out the call, e.g. a rejected transaction.
+    Failed(String),
+}
+
+impl ApiError {
+    pub fn code(&self) -> i64 {
+        match self {
+            ApiError::Parse(_) => -32700,
+            ApiError::InvalidRequest(_) => -32600,
+            ApiError::MethodNotFound(_) => -32601,
+            ApiError::InvalidParams(_) => -32602,
+            ApiError::Failed(_) => -32000,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApiError::Parse(e) => write!(f, "parse error: {}", e),
+            ApiError::InvalidRequest(e) => write!(f, "invalid request: {}", e),
+            ApiError::MethodNotFound(method) => write!(f, "method not found: {}", method),
+            ApiError::InvalidParams(e) => write!(f, "invalid params: {}", e),
+            ApiError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+/// A method call with its positional parameters.
+#[derive(Debug, PartialEq)]
+pub struct Call {
+    pub method: String,
+    pub params: Vec<Value>,
+}
+
+impl Call {
+    fn param(&self, i: usize) -> Result<&Value, ApiError> {
+        self.params.get(i).ok_or_else(|| ApiError::InvalidParams(format!("missing parameter {}", i)))
+    }
+
+    pub fn str_param(&self, i: usize) -> Result<&str, ApiError> {
+        self.param(i)?
+            .as_str()
+            .ok_or_else(|| ApiError::InvalidParams(format!("parameter {} is not a string", i)))
+    }
+
+    pub fn u64_param(&self, i: usize) -> Result<u64, ApiError> {
+        self.param(i)?
+            .as_u64()
+            .ok_or_else(|| ApiError::InvalidParams(format!("parameter {} is not a number", i)))
+    }
+
+    pub fn u32_param(&self, i: usize) -> Result<u32, ApiError> {
+        self.param(i)?
+            .as_u64()
+            .and_then(|n| u32::try_from(n).ok())
+            .ok_or_else(|| ApiError::InvalidParams(format!("parameter {} is not a height", i)))
+    }
+
+    // Hex string parameter, with or without a 0x prefix.
+    pub fn hex_param(&self, i: usize) -> Result<Vec<u8>, ApiError> {
+        hex::decode(self.str_param(i)?.trim_start_matches("0x")).map_err(|e| ApiError::InvalidParams(format!("parameter {}: {}", i, e)))
+    }
+
+    pub fn hash_param(&self, i: usize) -> Result<Hash, ApiError> {
+        Hash::from_bytes(&self.hex_param(i)?).map_err(|e| ApiError::InvalidParams(format!("parameter {}: {}", i, e)))
+    }
+
+    pub fn address_param(&self, i: usize) -> Result<Address, ApiError> {
+        Address::from_bytes(&self.hex_param(i)?).map_err(|e| ApiError::InvalidParams(format!("parameter {}: {}", i, e)))
+    }
+}
+
+/// HTTP endpoint that takes JSON-RPC requests as POST bodies.
+pub struct ApiServer {
+    listener: TcpListener,
+}
+
+impl ApiServer {
+    pub fn bind(addr: &str) -> io::Result<ApiServer> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(ApiServer { listener })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Answers requests with `handle` until `stop` is set, each connection on
+    /// its own thread.
+    pub fn run<F>(&self, stop: &AtomicBool, handle: F)
+    where
+        F: Fn(Call) -> Result<Value, ApiError> + Sync,
+    {
+        thread::scope(|scope| {
+            let mut clients = vec![];
+            while !stop.load(Ordering::SeqCst) {
+                clients.retain(|client: &thread::ScopedJoinHandle<()>| !client.is_finished());
+                match self.listener.accept() {
+                    Ok((mut stream, addr)) if clients.len() >= MAX_CONNECTIONS => {
+                        log::warn!("turned away api request from {}, too many connections", addr);
+                        let _ = stream
+                            .set_nonblocking(false)
+                            .and_then(|_| stream.set_write_timeout(Some(WRITE_TIMEOUT)))
+                            .and_then(|_| write_response(&mut stream, "503 Service Unavailable", b""));
+                    }
+                    Ok((stream, addr)) => {
+                        let handle = &handle;
+                        clients.push(scope.spawn(move || {
+                            if let Err(e) = serve(stream, handle) {
+                                log::warn!("could not answer api request from {}: {}", addr, e);
+                            }
+                        }));
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL),
+                    Err(e) => {
+                        log::error!("api listener failed: {}", e);
+                        return;
+                    }
+                }
+            }
+        });
+    }
+}
+
+// Reads from a stream until `deadline`, however slowly the bytes arrive.
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let left = self.deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "request took too long"));
+        }
+        self.stream.set_read_timeout(Some(left))?;
+        (&mut &*self.stream).read(buf)
+    }
+}
+
+// Reads one line of the request head, failing if the head is too long.
+fn read_head_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    if !line.ends_with('\n') {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "request head too long or cut short"));
+    }
+    Ok(line)
+}
+
+fn serve<F>(mut stream: TcpStream, handle: &F) -> io::Result<()>
+where
+    F: Fn(Call) -> Result<Value, ApiError>,
+{
+    // Accepted streams may inherit the listener's non-blocking mode.
+    stream.set_nonblocking(false)?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let deadline = Deadline { stream: &stream, deadline: Instant::now() + READ_TIMEOUT };
+    let mut reader = BufReader::new(deadline.take(MAX_HEAD));
+
+    let request_line = read_head_line(&mut reader)?;
+    let mut length = 0;
+    loop {
+        let line = read_head_line(&mut reader)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid content length"))?;
+            }
+        }
+    }
+
+    if !request_line.starts_with("POST ") {
+        return write_response(&mut stream, "405 Method Not Allowed", b"");
+    }
+    if length > MAX_BODY {
+        return write_response(&mut stream, "413 Payload Too Large", b"");
+    }
+    // The head limit no longer applies, the body is bounded by its length.
+    reader.get_mut().set_limit(length as u64);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+
+    let body = serde_json::to_vec(&answer(&body, &mut |call| handle(call)))?;
+    write_response(&mut stream, "200 OK", &body)
+}
+
+fn write_response(stream: &mut TcpStream, status: &str, body: &[u8]) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()
+}
+
+/// Calls `method` on the API of the node at `addr` and returns the result.
+pub fn call(addr: &str, method: &str, params: Vec<Value>) -> Result<Value, String> {
+    let body = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params }).to_string();
+    let mut stream = TcpStream::connect(addr).map_err(|e| format!("could not connect to {}: {}", addr, e))?;
+    stream.set_read_timeout(Some(CALL_TIMEOUT)).map_err(|e| e.to_string())?;
+    write!(
+        stream,
+        "POST / HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        addr,
+        body.len(),
+        body
+    )
+    .map_err(|e| e.to_string())?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).map_err(|e| e.to_string())?;
+    let (head, body) = response.split_once("\r\n\r\n").ok_or("malformed response")?;
+    let status = head.lines().next().unwrap_or_default();
+    if !status.starts_with("HTTP/1.1 200") {
+        return Err(format!("node answered {}", status));
+    }
+    let mut response: Value = serde_json::from_str(body).map_err(|e| e.to_string())?;
+    if let Some(error) = response.get("error") {
+        return Err(error["message"].as_str().unwrap_or("unknown error").to_owned());
+    }
+    Ok(response["result"].take())
+}
+
+/// Response object for the JSON-RPC request in `body`.
+pub fn answer<F>(body: &[u8], handle: &mut F) -> Value
+where
+    F: FnMut(Call) -> Result<Value, ApiError>,
+{
+    let (id, call) = parse_request(body);
+    match call.and_then(handle) {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(e) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": e.code(), "message": e.to_string() },
+        }),
+    }
+}
+
+// Splits a request into its id, which is echoed back, and the call.
+fn parse_request(body: &[u8]) -> (Value, Result<Call, ApiError>) {
+    let request: Value = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(e) => return (Value::Null, Err(ApiError::Parse(e.to_string()))),
+    };
+    let id = request.get("id").cloned().unwrap_or(Value::Null);
+    let method = match request.get("method").and_then(Value::as_str) {
+        Some(method) => method.to_owned(),
+        None => return (id, Err(ApiError::InvalidRequest("missing method".to_owned()))),
+    };
+    let params = match request.get("params") {
+        None | Some(Value::Null) => vec![],
+        Some(Value::Array(params)) => params.clone(),
+        Some(_) => return (id, Err(ApiError::InvalidParams("params must be an array".to_owned()))),
+    };
+    (id, Ok(Call { method, params }))
+}
+
+pub fn header_json(header: &Header) -> Value {
+    let hash = Hasher::new().hash(header).map(|hash| hash.to_string()).ok();
+    json!({
+        "hash": hash,
+        "height": header.height,
+        "version": header.version,
+        "prevBlock": header.prev_block.to_string(),
+        "dataHash": header.data.to_string(),
+        "timestamp": header.timestamp,
+    })
+}
+
+pub fn block_json(b: &Block) -> Value {
+    let mut value = header_json(&b.header);
+    value["validator"] = json!(b.validator.and_then(|key| key.address().ok()).map(|address| address.to_string()));
+    value["transactions"] = b.transactions.iter().map(transaction_json).collect();
+    value
+}
+
+pub fn transaction_json(tx: &Transaction) -> Value {
+    let mut tx = tx.clone();
+    json!({
+        "hash": tx.hash(Hasher::new()).to_string(),
+        "from": tx.sender().map(|address| address.to_string()),
+        "nonce": tx.nonce,
+        "chainId": tx.chain_id,
+        "gasLimit": tx.gas_limit,
+        "gasPrice": tx.gas_price,
+        "data": hex::encode(&tx.data),
+        "transfer": tx.transfer.map(|transfer| json!({
+            "from": transfer.from.to_string(),
+            "to": transfer.to.to_string(),
+            "amount": transfer.amount,
+        })),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+
+    fn post(addr: SocketAddr, body: &str) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    fn echo(call: Call) -> Result<Value, ApiError> {
+        match call.method.as_str() {
+            "echo" => Ok(Value::Array(call.params)),
+            "height" => call.u32_param(0).map(Value::from),
+            _ => Err(ApiError::MethodNotFound(call.method)),
+        }
+    }
+
+    #[test]
+    fn test_answer() {
+        let answer = |body: &str| answer(body.as_bytes(), &mut echo);
+
+        assert_eq!(
+            answer(r#"{"jsonrpc":"2.0","id":1,"method":"echo","params":["a",2]}"#),
+            json!({ "jsonrpc": "2.0", "id": 1, "result": ["a", 2] })
+        );
+        assert_eq!(answer(r#"{"id":"x","method":"echo"}"#)["result"], json!([]));
+
+        let code = |body: &str| answer(body)["error"]["code"].clone();
+        assert_eq!(code("{"), json!(-32700));
+        assert_eq!(code(r#"{"id":1}"#), json!(-32600));
+        assert_eq!(code(r#"{"id":1,"method":"nope"}"#), json!(-32601));
+        assert_eq!(code(r#"{"id":1,"method":"echo","params":{}}"#), json!(-32602));
+        assert_eq!(code(r#"{"id":1,"method":"height","params":[-1]}"#), json!(-32602));
+    }
+
+    #[test]
+    fn test_http() {
+        let api = ApiServer::bind("127.0.0.1:0").unwrap();
+        let addr = api.local_addr().unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let stop = stop.clone();
+            thread::spawn(move || api.run(&stop, echo))
+        };
+
+        let response = post(addr, r#"{"jsonrpc":"2.0","id":7,"method":"height","params":[3]}"#);
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        assert_eq!(serde_json::from_str::<Value>(body).unwrap(), json!({ "jsonrpc": "2.0", "id": 7, "result": 3 }));
+
+        let addr_str = addr.to_string();
+        assert_eq!(call(&addr_str, "echo", vec![json!("a")]), Ok(json!(["a"])));
+        assert_eq!(call(&addr_str, "nope", vec![]), Err("method not found: nope".to_owned()));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "GET / HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 405"));
+
+        stop.store(true, Ordering::SeqCst);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_slow_and_large_requests() {
+        let api = ApiServer::bind("127.0.0.1:0").unwrap();
+        let addr = api.local_addr().unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let stop = stop.clone();
+            thread::spawn(move || api.run(&stop, echo))
+        };
+
+        // A client that never finishes its request does not hold up others.
+        let mut slow = TcpStream::connect(addr).unwrap();
+        write!(slow, "POST / HTTP/1.1\r\n").unwrap();
+        let start = Instant::now();
+        assert_eq!(call(&addr.to_string(), "echo", vec![]), Ok(json!([])));
+        assert!(start.elapsed() < READ_TIMEOUT);
+
+        // Nor can it keep the connection open by trickling bytes.
+        let trickle = thread::spawn(move || {
+            while slow.write_all(b"x").is_ok() && start.elapsed() < READ_TIMEOUT * 3 {
+                thread::sleep(Duration::from_millis(500));
+            }
+            start.elapsed()
+        });
+        assert!(trickle.join().unwrap() < READ_TIMEOUT * 3);
+
+        // Overlong heads are dropped without an answer.
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let header = "x".repeat(MAX_HEAD as usize);
+        let _ = write!(stream, "POST / HTTP/1.1\r\nX-Padding: {}\r\n\r\n", header);
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response);
+        assert!(response.is_empty());
+
+        stop.store(true, Ordering::SeqCst);
+        handle.join().unwrap();
+    }
+}
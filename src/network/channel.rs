@@ -9,7 +9,12 @@ pub struct Channel<T> {
 
 impl<T> Channel<T>  {
     pub fn new() -> Channel<T> {
-        let (send, recv) = mpsc::sync_channel(0);
+        Channel::with_capacity(0)
+    }
+
+    // Channel that buffers up to `capacity` values before `send` blocks.
+    pub fn with_capacity(capacity: usize) -> Channel<T> {
+        let (send, recv) = mpsc::sync_channel(capacity);
         Channel {
             sender: send,
             receiver: Arc::new(Mutex::new(recv)),
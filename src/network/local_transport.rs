@@ -48,9 +48,10 @@ impl Transport for LocalTransport {
         self.chan.receiver()
     }
 
-    fn connect(&mut self, transport: TransportWrapper) -> Result<(), String> {
+    fn connect(&self, transport: TransportWrapper) -> Result<(), String> {
         let local_transport = match transport{
             TransportWrapper::Local(t) => t,
+            TransportWrapper::Addr(addr) => return Err(format!("local transport cannot connect to {}", addr)),
         };
         let addr = local_transport.addr();
         self.peers.write().unwrap().insert(addr, local_transport.clone());
@@ -60,7 +61,7 @@ impl Transport for LocalTransport {
     fn send_message(&self, to: NetAddr, payload: Vec<u8>) -> Result<(), String> {
         let peers = self.peers.read().unwrap();
 
-        let peer = peers.get(&to).expect("not found");
+        let peer = peers.get(&to).ok_or(format!("unknown peer {}", to))?;
 
         self.send(peer, payload)
     }
@@ -69,6 +70,19 @@ impl Transport for LocalTransport {
         self.addr.clone()
     }
 
+    fn peers(&self) -> Vec<NetAddr> {
+        let peers = self.peers.read().unwrap();
+        peers.keys().cloned().collect()
+    }
+
+    fn disconnect(&self, addr: &NetAddr) {
+        self.peers.write().unwrap().remove(addr);
+    }
+
+    fn close(&self) {
+        self.peers.write().unwrap().clear();
+    }
+
     fn broadcast(&self, payload: Vec<u8>) -> Result<(), String> {
         let peers = self.peers.read().unwrap();
         for (_, transport) in peers.iter() {
@@ -86,9 +100,9 @@ mod tests {
 
     #[test]
     fn test_connect() {
-        let mut tra = LocalTransport::new("A".to_string());
-        let mut trb = LocalTransport::new("B".to_string());
-        let mut trc = LocalTransport::new("C".to_string());
+        let tra = LocalTransport::new("A".to_string());
+        let trb = LocalTransport::new("B".to_string());
+        let trc = LocalTransport::new("C".to_string());
 
         assert!(tra.connect(TransportWrapper::Local(&trb)).is_ok());
         assert!(trb.connect(TransportWrapper::Local(&tra)).is_ok());
@@ -103,8 +117,8 @@ mod tests {
 
     #[test]
     fn test_send_message() {
-        let mut tra = LocalTransport::new("A".to_string());
-        let mut trb = LocalTransport::new("B".to_string());
+        let tra = LocalTransport::new("A".to_string());
+        let trb = LocalTransport::new("B".to_string());
 
         assert!(tra.connect(TransportWrapper::Local(&trb)).is_ok());
         assert!(trb.connect(TransportWrapper::Local(&tra)).is_ok());
@@ -129,9 +143,9 @@ mod tests {
 
     #[test]
     fn test_broadcast() {
-        let mut tra = LocalTransport::new("A".to_string());
-        let mut trb = LocalTransport::new("B".to_string());
-        let mut trc = LocalTransport::new("C".to_string());
+        let tra = LocalTransport::new("A".to_string());
+        let trb = LocalTransport::new("B".to_string());
+        let trc = LocalTransport::new("C".to_string());
 
         assert!(tra.connect(TransportWrapper::Local(&trb)).is_ok());
         assert!(tra.connect(TransportWrapper::Local(&trc)).is_ok());
@@ -0,0 +1,158 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+use rand::seq::SliceRandom;
+
+use super::transport::NetAddr;
+
+// Addresses that could not be dialed this many times in a row are forgotten.
+const MAX_FAILURES: u32 = 5;
+const MIN_RETRY: Duration = Duration::from_secs(1);
+const MAX_RETRY: Duration = Duration::from_secs(10 * 60);
+
+struct Entry {
+    // Bootstrap addresses are never forgotten.
+    bootstrap: bool,
+    failures: u32,
+    retry_at: Option<Instant>,
+}
+
+/// Addresses of nodes we could connect to, taken from the bootstrap list and
+/// from `Peers` messages of other nodes.
+pub struct PeerTable {
+    entries: HashMap<NetAddr, Entry>,
+    capacity: usize,
+}
+
+impl PeerTable {
+    /// Table that remembers at most `capacity` addresses besides the
+    /// bootstrap ones.
+    pub fn new(bootstrap: &[NetAddr], capacity: usize) -> PeerTable {
+        let entries = bootstrap
+            .iter()
+            .map(|addr| (addr.clone(), Entry { bootstrap: true, failures: 0, retry_at: None }))
+            .collect();
+        PeerTable { entries, capacity }
+    }
+
+    // Remembers `addr`, returns false if it was known already or the table
+    // is full.
+    pub fn add(&mut self, addr: &NetAddr) -> bool {
+        let learned = self.entries.values().filter(|entry| !entry.bootstrap).count();
+        if self.entries.contains_key(addr) || learned >= self.capacity {
+            return false;
+        }
+        self.entries.insert(addr.clone(), Entry { bootstrap: false, failures: 0, retry_at: None });
+        true
+    }
+
+    pub fn remove(&mut self, addr: &NetAddr) {
+        self.entries.remove(addr);
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Up to `n` addresses to dial, in random order. Addresses in `exclude`,
+    /// e.g. the connected peers, and those still backing off after a failed
+    /// dial are left out.
+    pub fn candidates(&self, n: usize, exclude: &HashSet<NetAddr>, now: Instant) -> Vec<NetAddr> {
+        let mut addrs: Vec<NetAddr> = self
+            .entries
+            .iter()
+            .filter(|(addr, entry)| !exclude.contains(*addr) && entry.retry_at.is_none_or(|at| at <= now))
+            .map(|(addr, _)| addr.clone())
+            .collect();
+        addrs.shuffle(&mut rand::thread_rng());
+        addrs.truncate(n);
+        addrs
+    }
+
+    /// Records the outcome of dialing `addr`. Failures back off
+    /// exponentially, and learned addresses failing too often are dropped.
+    pub fn dialed(&mut self, addr: &NetAddr, ok: bool, now: Instant) {
+        let entry = match self.entries.get_mut(addr) {
+            Some(entry) => entry,
+            None => return,
+        };
+        if ok {
+            entry.failures = 0;
+            entry.retry_at = None;
+            return;
+        }
+        entry.failures += 1;
+        if !entry.bootstrap && entry.failures >= MAX_FAILURES {
+            self.entries.remove(addr);
+            return;
+        }
+        let backoff = MIN_RETRY.saturating_mul(1 << (entry.failures - 1).min(16)).min(MAX_RETRY);
+        entry.retry_at = Some(now + backoff);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn known(table: &PeerTable, addr: &str) -> bool {
+        table.entries.contains_key(addr)
+    }
+
+    fn addrs(names: &[&str]) -> Vec<NetAddr> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_add() {
+        let mut table = PeerTable::new(&addrs(&["seed"]), 2);
+        assert!(!table.add(&"seed".to_owned()));
+        assert!(table.add(&"a".to_owned()));
+        assert!(!table.add(&"a".to_owned()));
+        assert!(table.add(&"b".to_owned()));
+        // Bootstrap addresses do not count towards the capacity.
+        assert!(!table.add(&"c".to_owned()));
+        assert_eq!(table.len(), 3);
+
+        table.remove(&"a".to_owned());
+        assert!(!known(&table, "a"));
+        assert!(table.add(&"c".to_owned()));
+    }
+
+    #[test]
+    fn test_candidates() {
+        let mut table = PeerTable::new(&addrs(&["seed"]), 10);
+        for addr in addrs(&["a", "b", "c"]) {
+            assert!(table.add(&addr));
+        }
+        let now = Instant::now();
+        let connected: HashSet<NetAddr> = addrs(&["a"]).into_iter().collect();
+
+        let mut candidates = table.candidates(10, &connected, now);
+        candidates.sort();
+        assert_eq!(candidates, addrs(&["b", "c", "seed"]));
+        assert_eq!(table.candidates(2, &connected, now).len(), 2);
+
+        // A failed address waits before it is dialed again.
+        table.dialed(&"b".to_owned(), false, now);
+        assert!(!table.candidates(10, &connected, now).contains(&"b".to_owned()));
+        assert!(table.candidates(10, &connected, now + MIN_RETRY).contains(&"b".to_owned()));
+        table.dialed(&"b".to_owned(), false, now);
+        assert!(!table.candidates(10, &connected, now + MIN_RETRY).contains(&"b".to_owned()));
+        table.dialed(&"b".to_owned(), true, now);
+        assert!(table.candidates(10, &connected, now).contains(&"b".to_owned()));
+    }
+
+    #[test]
+    fn test_failing_addresses_are_dropped() {
+        let mut table = PeerTable::new(&addrs(&["seed"]), 10);
+        assert!(table.add(&"a".to_owned()));
+        let now = Instant::now();
+        for _ in 0..MAX_FAILURES {
+            table.dialed(&"a".to_owned(), false, now);
+            table.dialed(&"seed".to_owned(), false, now);
+        }
+        assert!(!known(&table, "a"));
+        assert!(known(&table, "seed"));
+    }
+}
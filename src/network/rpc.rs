@@ -1,4 +1,4 @@
-use std::io::{self, Read};
+use std::io::Cursor;
 use std::fmt;
 
 use serde::{Deserialize, Serialize};
@@ -7,19 +7,27 @@ use log::debug;
 use crate::core::encoding::{Encoder, Decoder, Decode};
 use crate::core::hasher::Bytes;
 use crate::core::encoding::Encode;
+use crate::core::block::Block;
 use crate::core::transaction::Transaction;
-use super::transport::NetAddr;
+use crate::error::Error;
+use crate::types::hash::Hash;
+use super::transport::{NetAddr, RPC};
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub enum MessageType {
     Tx = 0x1,
     Block,
-}
-pub struct RPC  {
-    pub from: NetAddr,
-    pub payload: Box<dyn Read>,
+    GetStatus,
+    Status,
+    GetBlocks,
+    Blocks,
+    Handshake,
+    GetPeers,
+    Peers,
 }
 
+// Version of the wire protocol spoken by this node.
+pub const PROTOCOL_VERSION: u32 = 1;
 
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -29,12 +37,12 @@ pub struct Message {
 }
 
 impl Bytes for Message {
-    fn as_bytes(&self) -> Vec<u8> {
+    fn as_bytes(&self) -> Result<Vec<u8>, Error> {
         let mut writer = vec![];
         let mut encoder = Encoder::new(&mut writer);
 
-        encoder.encode(self);
-        writer
+        encoder.encode(self)?;
+        Ok(writer)
     }
 }
 
@@ -47,9 +55,108 @@ impl Message {
     }
 }
 
+/// First message on a new connection. A peer is only talked to once it sent
+/// a handshake for the same network and protocol version.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HandshakeMessage {
+    // Random id of the node, which tells it when it connected to itself.
+    pub node_id: u64,
+    pub version: u32,
+    pub chain_id: u32,
+    pub genesis: Hash,
+    pub height: u32,
+}
+
+impl Bytes for HandshakeMessage {
+    fn as_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut writer = vec![];
+        let mut encoder = Encoder::new(&mut writer);
+
+        encoder.encode(self)?;
+        Ok(writer)
+    }
+}
+
+/// Reply to `GetPeers`, listing addresses of peers the sender is connected
+/// to.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PeersMessage {
+    pub peers: Vec<NetAddr>,
+}
+
+impl Bytes for PeersMessage {
+    fn as_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut writer = vec![];
+        let mut encoder = Encoder::new(&mut writer);
+
+        encoder.encode(self)?;
+        Ok(writer)
+    }
+}
+
+/// Reply to `GetStatus`, describing the canonical chain of the sender.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StatusMessage {
+    pub version: u32,
+    pub height: u32,
+    pub tip: Hash,
+}
+
+/// Asks for the canonical blocks from height `from` to `to`, inclusive.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GetBlocksMessage {
+    pub from: u32,
+    pub to: u32,
+}
+
+/// Reply to `GetBlocks`, in ascending height order. Holds fewer blocks than
+/// asked for if the sender does not have them all.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BlocksMessage {
+    pub blocks: Vec<Block>,
+}
+
+impl Bytes for StatusMessage {
+    fn as_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut writer = vec![];
+        let mut encoder = Encoder::new(&mut writer);
+
+        encoder.encode(self)?;
+        Ok(writer)
+    }
+}
+
+impl Bytes for GetBlocksMessage {
+    fn as_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut writer = vec![];
+        let mut encoder = Encoder::new(&mut writer);
+
+        encoder.encode(self)?;
+        Ok(writer)
+    }
+}
+
+impl Bytes for BlocksMessage {
+    fn as_bytes(&self) -> Result<Vec<u8>, Error> {
+        let mut writer = vec![];
+        let mut encoder = Encoder::new(&mut writer);
+
+        encoder.encode(self)?;
+        Ok(writer)
+    }
+}
+
 #[derive(Debug)]
 pub enum Decoded {
     Tx(Transaction),
+    Block(Block),
+    GetStatus,
+    Status(StatusMessage),
+    GetBlocks(GetBlocksMessage),
+    Blocks(BlocksMessage),
+    Handshake(HandshakeMessage),
+    GetPeers,
+    Peers(PeersMessage),
 }
 
 #[derive(Debug)]
@@ -83,29 +190,128 @@ impl std::error::Error for MessageDecodeError {}
 
 pub type RPCDecodeFunc = fn(RPC) -> Result<DecodedMessage, MessageDecodeError>;
 
-pub fn default_rpc_decode_func(mut rpc: RPC) -> Result<DecodedMessage, MessageDecodeError> {
-    let mut decoder = Decoder::new(&mut rpc.payload);
-
-
-    let msg: Message = decoder.decode();
+pub fn default_rpc_decode_func(rpc: RPC) -> Result<DecodedMessage, MessageDecodeError> {
+    let mut payload = Cursor::new(rpc.payload);
+    let msg: Message = Decoder::new(&mut payload)
+        .decode()
+        .map_err(|e| MessageDecodeError { from: rpc.from.clone(), error: e.to_string() })?;
 
     debug!(
         "new incoming message from {}: type={:?}",
         rpc.from, msg.header
     );
 
-    match msg.header {
-        MessageType::Tx => {
-            let tx : Transaction = decoder.decode();
-            Ok(DecodedMessage::new(rpc.from, Decoded::Tx(tx)))
-        }
-        _ => Err(MessageDecodeError {
-            from: rpc.from,
-            error: format!("invalid message header {:?}", msg.header),
-        }),
+    let mut data = Cursor::new(msg.data);
+    let mut decoder = Decoder::new(&mut data);
+    let decoded = match msg.header {
+        MessageType::Tx => decoder.decode().map(Decoded::Tx),
+        MessageType::Block => decoder.decode().map(Decoded::Block),
+        MessageType::GetStatus => Ok(Decoded::GetStatus),
+        MessageType::Status => decoder.decode().map(Decoded::Status),
+        MessageType::GetBlocks => decoder.decode().map(Decoded::GetBlocks),
+        MessageType::Blocks => decoder.decode().map(Decoded::Blocks),
+        MessageType::Handshake => decoder.decode().map(Decoded::Handshake),
+        MessageType::GetPeers => Ok(Decoded::GetPeers),
+        MessageType::Peers => decoder.decode().map(Decoded::Peers),
+    };
+    match decoded {
+        Ok(decoded) => Ok(DecodedMessage::new(rpc.from, decoded)),
+        Err(e) => Err(MessageDecodeError { from: rpc.from, error: e.to_string() }),
     }
 }
 
 pub trait RPCProcessor {
-    fn process_message(&mut self, dm: &DecodedMessage) -> io::Result<()>;
+    fn process_message(&mut self, dm: &DecodedMessage) -> Result<(), Box<dyn std::error::Error>>;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::block::Block;
+    use crate::core::hasher::Bytes;
+    use crate::core::transaction::Transaction;
+    use crate::crypto::keypair::PrivateKey;
+    use crate::network::transport::RPC;
+
+    use super::*;
+
+    #[test]
+    fn test_decode_tx_message() {
+        let mut tx = Transaction::new(b"foo".to_vec()).unwrap();
+        assert!(tx.sign(&PrivateKey::generate_key()).is_ok());
+
+        let msg = Message::new(MessageType::Tx, tx.as_bytes().unwrap());
+        let rpc = RPC { from: "A".to_string(), payload: msg.as_bytes().unwrap() };
+
+        let decoded = default_rpc_decode_func(rpc).unwrap();
+        assert_eq!(decoded.from, "A");
+        match decoded.data {
+            Decoded::Tx(decoded_tx) => assert_eq!(decoded_tx, tx),
+            other => panic!("expected a transaction, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decode_block_message() {
+        let b = Block::random_block_with_signature(1);
+
+        let msg = Message::new(MessageType::Block, b.as_bytes().unwrap());
+        let rpc = RPC { from: "A".to_string(), payload: msg.as_bytes().unwrap() };
+
+        match default_rpc_decode_func(rpc).unwrap().data {
+            Decoded::Block(decoded_block) => assert_eq!(decoded_block, b),
+            other => panic!("expected a block, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decode_sync_messages() {
+        let decode = |header: MessageType, data: Vec<u8>| {
+            let rpc = RPC { from: "A".to_string(), payload: Message::new(header, data).as_bytes().unwrap() };
+            default_rpc_decode_func(rpc).unwrap().data
+        };
+
+        assert!(matches!(decode(MessageType::GetStatus, vec![]), Decoded::GetStatus));
+
+        let status = StatusMessage { version: PROTOCOL_VERSION, height: 7, tip: Hash::random() };
+        match decode(MessageType::Status, status.as_bytes().unwrap()) {
+            Decoded::Status(decoded) => assert_eq!(decoded, status),
+            other => panic!("expected a status, got {:?}", other),
+        }
+
+        let handshake = HandshakeMessage { node_id: 1, version: PROTOCOL_VERSION, chain_id: 1, genesis: Hash::random(), height: 7 };
+        match decode(MessageType::Handshake, handshake.as_bytes().unwrap()) {
+            Decoded::Handshake(decoded) => assert_eq!(decoded, handshake),
+            other => panic!("expected a handshake, got {:?}", other),
+        }
+
+        assert!(matches!(decode(MessageType::GetPeers, vec![]), Decoded::GetPeers));
+
+        let peers = PeersMessage { peers: vec!["127.0.0.1:3000".to_string(), "127.0.0.1:3001".to_string()] };
+        match decode(MessageType::Peers, peers.as_bytes().unwrap()) {
+            Decoded::Peers(decoded) => assert_eq!(decoded, peers),
+            other => panic!("expected peers, got {:?}", other),
+        }
+
+        let get_blocks = GetBlocksMessage { from: 1, to: 5 };
+        match decode(MessageType::GetBlocks, get_blocks.as_bytes().unwrap()) {
+            Decoded::GetBlocks(decoded) => assert_eq!(decoded, get_blocks),
+            other => panic!("expected a block request, got {:?}", other),
+        }
+
+        let blocks = BlocksMessage { blocks: vec![Block::random_block_with_signature(1), Block::random_block_with_signature(2)] };
+        match decode(MessageType::Blocks, blocks.as_bytes().unwrap()) {
+            Decoded::Blocks(decoded) => assert_eq!(decoded, blocks),
+            other => panic!("expected blocks, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_decode_malformed_message() {
+        let rpc = RPC { from: "A".to_string(), payload: b"garbage".to_vec() };
+        assert!(default_rpc_decode_func(rpc).is_err());
+
+        let msg = Message::new(MessageType::Block, b"garbage".to_vec());
+        let rpc = RPC { from: "A".to_string(), payload: msg.as_bytes().unwrap() };
+        assert!(default_rpc_decode_func(rpc).is_err());
+    }
 }
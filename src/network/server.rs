@@ -1,85 +1,438 @@
 
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{Receiver, SyncSender};
 use std::sync::{mpsc, Arc};
+use std::thread::{self, JoinHandle};
 use std::time::{Duration, Instant};
 
+use chrono::Utc;
 use log::info;
+use serde_json::{json, Value};
 
-use crate::core::hasher::{Hasher};
-use crate::core::transaction::Transaction;
+use crate::core::block::{Block, Header};
+use crate::core::blockchain::{Blockchain, BlockchainError, Reorg};
+use crate::core::encoding::{Decode, Decoder};
+use crate::core::hasher::{Hasher, Bytes};
+use crate::core::storage::{DiskStore, MemoryStore, Storage, StorageError};
+use crate::core::transaction::{Transaction, Transfer};
+use crate::core::validator::{ValidationError, MAX_BLOCK_GAS};
+use crate::types::{address::Address, hash::Hash};
 use crate::crypto::keypair::PrivateKey;
 
+use super::api::{block_json, transaction_json, ApiError, ApiServer, Call};
+use super::ws::WsServer;
 use super::channel::Channel;
-use super::rpc::RPCDecodeFunc;
-use super::transport::{Transport, RPC};
+use super::rpc::{
+    BlocksMessage, Decoded, DecodedMessage, GetBlocksMessage, HandshakeMessage, Message, MessageType, PeersMessage,
+    RPCDecodeFunc, RPCProcessor, StatusMessage, PROTOCOL_VERSION,
+};
+use super::peers::PeerTable;
+use super::transport::{NetAddr, Transport, TransportWrapper, RPC};
 use super::txpool::TxPool;
 
 const default_time: std::time::Duration = Duration::new(5, 0);
+// Inbound RPCs buffered between the transport readers and the server loop.
+const RPC_BUFFER: usize = 1024;
+// Most blocks requested from, or sent to, a peer in one message.
+const SYNC_BATCH: u32 = 32;
+// A sync peer that does not answer within this time is given up on.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+// How often the transports are checked for new peers to greet.
+const PEER_INTERVAL: Duration = Duration::from_secs(1);
+// How often missing outbound connections are dialed and peers are asked
+// for more addresses.
+const DISCOVERY_INTERVAL: Duration = Duration::from_secs(10);
+// Most addresses sent to, or taken from, a peer in one message.
+const MAX_PEERS_PER_MESSAGE: usize = 32;
+const PEER_TABLE_SIZE: usize = 1024;
+pub const DEFAULT_OUTBOUND_PEERS: usize = 8;
+// Most dials under way at the same time.
+const MAX_DIALS: usize = 8;
+// How often transport readers check whether the server is shutting down.
+const READ_POLL: Duration = Duration::from_millis(100);
+// How long an API call waits for the server loop to answer it.
+const API_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub struct ServerOpts {
     pub transports: Vec<Box<dyn Transport>>,
     pub block_time: Option<Duration>,
     pub key: Option<PrivateKey>,
     pub rpc_decode_func: RPCDecodeFunc,
+    // Directory for the on-disk block store, blocks are kept in memory if None.
+    pub data_dir: Option<PathBuf>,
+    pub chain_id: u32,
+    // Addresses dialed at start, discovery learns further peers from them.
+    pub bootstrap: Vec<NetAddr>,
+    // Number of connections to peers of our choice discovery tries to keep.
+    pub outbound_peers: usize,
+    // Address the HTTP JSON-RPC API listens on, the API is off if None.
+    pub api_addr: Option<String>,
+    // Address of the WebSocket event feed, the feed is off if None.
+    pub ws_addr: Option<String>,
+    // Balances minted by the genesis block. Every node of a chain needs the
+    // same allocation, since it changes the genesis hash.
+    pub genesis_alloc: BTreeMap<Address, u64>,
+}
+
+// Download of missing blocks from a peer that is ahead of us.
+struct SyncState {
+    peer: NetAddr,
+    // Height of the peer's chain.
+    target: u32,
+    requested: Instant,
+}
+
+// A peer that completed the handshake.
+struct PeerInfo {
+    node_id: u64,
+    // Best height the peer told us about.
+    height: u32,
 }
 
 pub struct Server {
     opts: ServerOpts,
+    // Random id sent in handshakes.
+    id: u64,
+    transports: Vec<Arc<dyn Transport>>,
     block_time: Duration,
     pool: TxPool,
     validator: bool,
-    rpc_ch: Channel<RPC>,
-    quit_ch: Channel<()>,
+    events: Channel<Event>,
     hasher: Hasher,
+    chain: Blockchain,
+    reorgs: Receiver<Reorg>,
+    sync: Option<SyncState>,
+    peers: HashMap<NetAddr, PeerInfo>,
+    // Peers our handshake was sent to.
+    greeted: HashSet<NetAddr>,
+    peer_table: PeerTable,
+    // Connected peers that we dialed.
+    outbound: HashSet<NetAddr>,
+    // Addresses being dialed, with their worker threads, joined before the
+    // server loop exits so no dial outlives the transports.
+    dialing: HashMap<NetAddr, JoinHandle<()>>,
+    // Taken by the API and feed threads on start.
+    api: Option<ApiServer>,
+    ws: Option<WsServer>,
+}
+
+// What the server loop waits for.
+enum Event {
+    Rpc(RPC),
+    // API call and where to send its result.
+    Api(Call, mpsc::Sender<Result<Value, ApiError>>),
+    // Outcome of a dial started by discovery.
+    Dialed(NetAddr, bool),
+    Quit,
+}
+
+/// Controls a server started with `Server::start`.
+pub struct ServerHandle {
+    events: SyncSender<Event>,
+    stop: Arc<AtomicBool>,
+    // Threads feeding the server loop, and the event feed.
+    readers: Vec<JoinHandle<()>>,
+    main: JoinHandle<Result<(), String>>,
+    transports: Vec<Arc<dyn Transport>>,
+}
+
+impl ServerHandle {
+    /// Stops the server. The transport readers, the API and the event feed
+    /// stop first, the messages they handed over are still processed, then
+    /// the storage is flushed and the transports are closed. Returns once
+    /// every thread has exited.
+    pub fn shutdown(self) -> Result<(), String> {
+        self.stop.store(true, Ordering::SeqCst);
+        for reader in self.readers {
+            reader.join().map_err(|_| "transport reader panicked".to_owned())?;
+        }
+        // Queued behind every message the readers forwarded.
+        let _ = self.events.send(Event::Quit);
+        let result = self.main.join().map_err(|_| "server loop panicked".to_owned())?;
+        for transport in &self.transports {
+            transport.close();
+        }
+        result
+    }
+}
+
+// Connects to `addr` over the first transport that can reach it.
+fn dial(transports: &[Arc<dyn Transport>], addr: &NetAddr) -> bool {
+    for transport in transports {
+        match transport.connect(TransportWrapper::Addr(addr.clone())) {
+            Ok(()) => {
+                info!("connected to {}", addr);
+                return true;
+            }
+            Err(e) => log::debug!("{}", e),
+        }
+    }
+    false
+}
+
+/// First block of the chain, minting `alloc`. Genesis transfers are not
+/// signed and nothing is debited from their sender.
+pub fn genesis_block(alloc: &BTreeMap<Address, u64>) -> Block {
+    let transactions: Vec<Transaction> = alloc
+        .iter()
+        .map(|(to, amount)| Transaction::new_transfer(Transfer { from: *to, to: *to, amount: *amount }))
+        .collect();
+    let header = Header {
+        version: 1,
+        data: Block::data_hash(&transactions),
+        prev_block: Hash::default(),
+        timestamp: 0,
+        height: 0,
+    };
+    Block::new(header, transactions)
 }
 
 impl Server {
-    pub fn new(opts: ServerOpts) -> Server {
+    pub fn new(mut opts: ServerOpts) -> Result<Server, Box<dyn std::error::Error>> {
         let duration = match opts.block_time
          {
             Some(s) => s,
             None => default_time,
         };
-        Server {
-            rpc_ch: Channel::new(),
-            quit_ch: Channel::new(),
+        let store: Box<dyn Storage> = match &opts.data_dir {
+            Some(dir) => Box::new(DiskStore::open(dir)?),
+            None => Box::new(MemoryStore::new()),
+        };
+        let mut chain = Blockchain::new_with_store(store, &mut genesis_block(&opts.genesis_alloc))?;
+        chain.set_chain_id(opts.chain_id);
+        let reorgs = chain.subscribe_reorgs();
+        let transports = opts.transports.drain(..).map(Arc::from).collect();
+        let peer_table = PeerTable::new(&opts.bootstrap, PEER_TABLE_SIZE);
+        let api = opts.api_addr.as_deref().map(ApiServer::bind).transpose()?;
+        let ws = opts.ws_addr.as_deref().map(WsServer::bind).transpose()?;
+        let mut pool = TxPool::new(opts.chain_id);
+        pool.set_event_bus(chain.events());
+        Ok(Server {
+            id: rand::random(),
+            transports,
+            events: Channel::with_capacity(RPC_BUFFER),
             block_time: duration,
-            pool: TxPool::new(),
+            pool,
             validator: opts.key.is_some(),
             opts,
             hasher: Hasher::new(),
-        }
+            chain,
+            reorgs,
+            sync: None,
+            peers: HashMap::new(),
+            greeted: HashSet::new(),
+            peer_table,
+            outbound: HashSet::new(),
+            dialing: HashMap::new(),
+            api,
+            ws,
+        })
     }
 
-    pub fn start(self) {
-        for transport in self.opts.transports {
-            let sender = self.rpc_ch.sender().clone();
-            std::thread::spawn(move || {
-                while let Ok(msg) = transport.consume().lock().unwrap().recv() {
-                    let _ = sender.send(msg);
-                }
-            });
+    // Address the API is bound to, if it is enabled.
+    pub fn api_addr(&self) -> Option<SocketAddr> {
+        self.api.as_ref().and_then(|api| api.local_addr().ok())
+    }
+
+    pub fn ws_addr(&self) -> Option<SocketAddr> {
+        self.ws.as_ref().and_then(|ws| ws.local_addr().ok())
+    }
+
+    /// Runs the server on its own thread, with one thread per transport
+    /// handing inbound messages to it.
+    pub fn start(mut self) -> ServerHandle {
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut readers: Vec<JoinHandle<()>> = self
+            .transports
+            .iter()
+            .map(|transport| {
+                let receiver = transport.consume();
+                let sender = self.events.sender();
+                let stop = stop.clone();
+                thread::spawn(move || {
+                    while !stop.load(Ordering::SeqCst) {
+                        let msg = receiver.lock().unwrap().recv_timeout(READ_POLL);
+                        match msg {
+                            Ok(rpc) => {
+                                if sender.send(Event::Rpc(rpc)).is_err() {
+                                    return;
+                                }
+                            }
+                            Err(mpsc::RecvTimeoutError::Timeout) => (),
+                            Err(mpsc::RecvTimeoutError::Disconnected) => return,
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        // API calls are answered by the server loop, between messages.
+        if let Some(addr) = self.api_addr() {
+            info!("api listening on {}", addr);
+        }
+        if let Some(api) = self.api.take() {
+            let sender = self.events.sender();
+            let stop = stop.clone();
+            readers.push(thread::spawn(move || {
+                api.run(&stop, |call| {
+                    let (reply, result) = mpsc::channel();
+                    sender
+                        .send(Event::Api(call, reply))
+                        .map_err(|_| ApiError::Failed("server is shutting down".to_owned()))?;
+                    result
+                        .recv_timeout(API_TIMEOUT)
+                        .map_err(|_| ApiError::Failed("server did not answer".to_owned()))?
+                })
+            }));
+        }
+
+        if let Some(addr) = self.ws_addr() {
+            info!("event feed listening on {}", addr);
         }
+        if let Some(ws) = self.ws.take() {
+            let events = self.chain.events();
+            let stop = stop.clone();
+            readers.push(thread::spawn(move || ws.run(&stop, &events)));
+        }
+
+        let events = self.events.sender();
+        let transports = self.transports.clone();
+        let main = thread::spawn(move || self.run());
+        ServerHandle { events, stop, readers, main, transports }
+    }
+
+    // Waits for messages until the next tick is due, until told to quit.
+    fn run(mut self) -> Result<(), String> {
+        let events = self.events.receiver();
+        let events = events.lock().unwrap();
 
         let mut ticker = Instant::now() + self.block_time;
+        let mut peer_ticker = Instant::now();
+        let mut discovery_ticker = Instant::now();
 
         loop {
-            let msg = self.rpc_ch.receiver().lock().unwrap().try_recv();
-            match msg {
-                Ok(rpc) => println!("{:?}", rpc),
-                Err(mpsc::TryRecvError::Empty) => (),
-                Err(mpsc::TryRecvError::Disconnected) => break,
+            let next = ticker.min(peer_ticker).min(discovery_ticker);
+            match events.recv_timeout(next.saturating_duration_since(Instant::now())) {
+                Ok(Event::Rpc(rpc)) => match (self.opts.rpc_decode_func)(rpc) {
+                    Ok(msg) => {
+                        if let Err(e) = self.process_message(&msg) {
+                            log::error!("could not process message from {}: {}", msg.from, e);
+                        }
+                    }
+                    Err(e) => log::error!("{}", e),
+                },
+                Ok(Event::Api(call, reply)) => {
+                    let _ = reply.send(self.handle_api_call(&call));
+                }
+                Ok(Event::Dialed(addr, ok)) => self.dialed(addr, ok),
+                Ok(Event::Quit) | Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                Err(mpsc::RecvTimeoutError::Timeout) => (),
             };
 
+            if peer_ticker <= Instant::now() {
+                self.greet_peers();
+                peer_ticker = Instant::now() + PEER_INTERVAL;
+            }
+
+            if discovery_ticker <= Instant::now() {
+                self.discover();
+                discovery_ticker = Instant::now() + DISCOVERY_INTERVAL;
+            }
+
             if ticker <= Instant::now() {
-                println!("do stuff every x seconds");
+                let pruned = self.pool.prune_expired(Utc::now().timestamp_nanos());
+                if pruned > 0 {
+                    info!("dropped {} expired transactions from the mempool", pruned);
+                }
+                if self.validator {
+                    if let Err(e) = self.create_new_block() {
+                        log::error!("could not create block: {}", e);
+                    }
+                }
+                if let Err(e) = self.request_status() {
+                    log::error!("could not ask peers for their status: {}", e);
+                }
                 ticker = Instant::now() + self.block_time;
             }
+        }
 
-            //std::thread::yield_now();
+        // Dials under way report back before the loop exits, so none of them
+        // reaches a transport after shutdown closed it.
+        while !self.dialing.is_empty() {
+            match events.recv() {
+                Ok(Event::Dialed(addr, ok)) => self.dialed(addr, ok),
+                Ok(_) => (),
+                Err(_) => break,
+            }
         }
 
-        println!("Server shutdown");
+        self.chain.flush().map_err(|e| format!("could not flush storage: {}", e))?;
+        info!("server stopped at height {}", self.chain.height());
+        Ok(())
+    }
+
+    fn handle_api_call(&mut self, call: &Call) -> Result<Value, ApiError> {
+        let failed = |e: StorageError| ApiError::Failed(e.to_string());
+        match call.method.as_str() {
+            "getHeight" => Ok(json!(self.chain.height())),
+            "getBlockByHeight" => {
+                let height = call.u32_param(0)?;
+                if height > self.chain.height() {
+                    return Ok(Value::Null);
+                }
+                self.chain.get_block_by_height(height).map(|b| block_json(&b)).map_err(failed)
+            }
+            "getBlockByHash" => match self.chain.get_height_by_hash(&call.hash_param(0)?) {
+                Some(height) => self.chain.get_block_by_height(height).map(|b| block_json(&b)).map_err(failed),
+                None => Ok(Value::Null),
+            },
+            // Pooled transactions have a null block height.
+            "getTransaction" => {
+                let hash = call.hash_param(0)?;
+                let (tx, height) = match self.pool.get(&hash) {
+                    Some(tx) => (tx, None),
+                    None => match self.chain.get_transaction(&hash).map_err(failed)? {
+                        Some((tx, height)) => (tx, Some(height)),
+                        None => return Ok(Value::Null),
+                    },
+                };
+                let mut value = transaction_json(&tx);
+                value["blockHeight"] = json!(height);
+                Ok(value)
+            }
+            "getAccount" => {
+                let account = self.chain.get_account(&call.address_param(0)?);
+                Ok(json!({ "balance": account.balance, "nonce": account.nonce }))
+            }
+            // Takes the CBOR encoded transaction and returns its hash.
+            "sendTransaction" => {
+                let mut payload = Cursor::new(call.hex_param(0)?);
+                let mut tx: Transaction = Decoder::new(&mut payload)
+                    .decode()
+                    .map_err(|e| ApiError::InvalidParams(e.to_string()))?;
+                self.handle_transaction(&tx).map_err(|e| ApiError::Failed(e.to_string()))?;
+                Ok(json!(tx.hash(Hasher::new()).to_string()))
+            }
+            "getPoolSize" => Ok(json!({ "pending": self.pool.pending_len(), "queued": self.pool.queued_len() })),
+            "getPeers" => {
+                let mut peers: Vec<(&NetAddr, &PeerInfo)> = self.peers.iter().collect();
+                peers.sort_by(|a, b| a.0.cmp(b.0));
+                Ok(peers
+                    .into_iter()
+                    .map(|(addr, peer)| json!({
+                        "addr": addr,
+                        "nodeId": peer.node_id,
+                        "height": peer.height,
+                        "outbound": self.outbound.contains(addr),
+                    }))
+                    .collect())
+            }
+            _ => Err(ApiError::MethodNotFound(call.method.clone())),
+        }
     }
 
     fn handle_transaction(&mut self, tx: &Transaction) -> Result<(), Box<dyn std::error::Error>> {
@@ -87,7 +440,7 @@ impl Server {
             return Err(Box::new(e));
         }
 
-        let hash = self.hasher.hash(tx).unwrap();
+        let hash = self.hasher.hash(tx)?;
 
         if self.pool.has(&hash) {
             info!("transaction already in mempool: hash={}", hash);
@@ -96,16 +449,1021 @@ impl Server {
 
         info!("adding new tx to the mempool: hash={}", hash);
 
-        self.pool.add(tx.clone());
+        let mut tx = tx.clone();
+        tx.set_seen(Utc::now().timestamp_nanos());
+        let account = match tx.sender() {
+            Some(address) => self.chain.get_account(&address),
+            None => return Err("transaction is not signed".into()),
+        };
+        self.pool.add(tx.clone(), account)?;
+
+        self.broadcast_tx(&tx)?;
+        Ok(())
+    }
+
+    fn process_block(&mut self, from: &NetAddr, b: &Block) -> Result<(), Box<dyn std::error::Error>> {
+        let mut b = b.clone();
+        let hash = b.hash(Hasher::new());
+        if self.chain.lookup_header(&hash).is_some() {
+            info!("block already known: hash={}", hash);
+            return Ok(());
+        }
+
+        if let Err(e) = self.chain.add_block(&mut b) {
+            // We are missing blocks the peer has, catch up with it.
+            if let BlockchainError::Validation(ValidationError::UnknownParent(_)) = e {
+                let msg = Message::new(MessageType::GetStatus, vec![]);
+                if let Err(e) = self.send(from, msg.as_bytes()?) {
+                    log::warn!("could not ask {} for its status: {}", from, e);
+                }
+            }
+            return Err(Box::new(e));
+        }
+        self.update_pool(&b);
+        self.broadcast_block(&b)?;
+        Ok(())
+    }
+
+    fn request_status(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let msg = Message::new(MessageType::GetStatus, vec![]);
+        self.broadcast(msg.as_bytes()?);
+        Ok(())
+    }
 
+    fn handshake(&self) -> Result<HandshakeMessage, Box<dyn std::error::Error>> {
+        let genesis = self.chain.get_header(0).expect("genesis header is always present");
+        Ok(HandshakeMessage {
+            node_id: self.id,
+            version: PROTOCOL_VERSION,
+            chain_id: self.chain.chain_id(),
+            genesis: self.hasher.hash(&genesis)?,
+            height: self.chain.height(),
+        })
+    }
+
+    fn send_handshake(&mut self, to: &NetAddr) -> Result<(), Box<dyn std::error::Error>> {
+        let msg = Message::new(MessageType::Handshake, self.handshake()?.as_bytes()?);
+        self.send(to, msg.as_bytes()?)?;
+        self.greeted.insert(to.clone());
         Ok(())
     }
 
-    fn create_new_block(&self) -> Result<(), Box<dyn std::error::Error>> {
-        println!("creating a new block");
+    // Sends our handshake to peers the transports connected since the last
+    // call, and forgets about the ones that went away.
+    fn greet_peers(&mut self) {
+        let connected = self.connected_peers();
+        self.peers.retain(|addr, peer| {
+            if !connected.contains(addr) {
+                info!("peer {} went away: node={:x}, height={}", addr, peer.node_id, peer.height);
+            }
+            connected.contains(addr)
+        });
+        self.greeted.retain(|addr| connected.contains(addr));
+        for addr in connected {
+            if !self.greeted.contains(&addr) {
+                if let Err(e) = self.send_handshake(&addr) {
+                    log::warn!("could not send handshake to {}: {}", addr, e);
+                }
+            }
+        }
+    }
+
+    // Accepts a peer on the same network and protocol version, answering
+    // with our own handshake if it has not got it yet. Other peers are
+    // disconnected.
+    fn handle_handshake(&mut self, from: &NetAddr, msg: &HandshakeMessage) -> Result<(), Box<dyn std::error::Error>> {
+        let ours = self.handshake()?;
+        let refused = if msg.node_id == ours.node_id {
+            Some("it is this node".to_owned())
+        } else if msg.version != ours.version {
+            Some(format!("protocol version {} is not {}", msg.version, ours.version))
+        } else if msg.chain_id != ours.chain_id {
+            Some(format!("chain {} is not {}", msg.chain_id, ours.chain_id))
+        } else if msg.genesis != ours.genesis {
+            Some(format!("genesis {} is not {}", msg.genesis, ours.genesis))
+        } else {
+            None
+        };
+        if let Some(reason) = refused {
+            self.peers.remove(from);
+            self.outbound.remove(from);
+            self.peer_table.remove(from);
+            for transport in &self.transports {
+                transport.disconnect(from);
+            }
+            return Err(format!("refusing peer {}: {}", from, reason).into());
+        }
+
+        info!("handshake with {} done: version={}, height={}", from, msg.version, msg.height);
+        self.peers.insert(from.clone(), PeerInfo { node_id: msg.node_id, height: msg.height });
+        if !self.greeted.contains(from) {
+            self.send_handshake(from)?;
+        }
+        self.start_sync(from, msg.height)
+    }
+
+    fn handle_get_status(&self, from: &NetAddr) -> Result<(), Box<dyn std::error::Error>> {
+        let height = self.chain.height();
+        let tip = self.chain.get_header(height).expect("tip header is always present");
+        let status = StatusMessage {
+            version: PROTOCOL_VERSION,
+            height,
+            tip: self.hasher.hash(&tip)?,
+        };
+        let msg = Message::new(MessageType::Status, status.as_bytes()?);
+        Ok(self.send(from, msg.as_bytes()?)?)
+    }
+
+    fn connected_peers(&self) -> HashSet<NetAddr> {
+        self.transports.iter().flat_map(|transport| transport.peers()).collect()
+    }
+
+    // Dials known addresses until `outbound_peers` connections are open, and
+    // asks the peers for more addresses while there are too few. Dials run on
+    // their own threads, since an address may take long to resolve or not
+    // answer at all, and report back with `Event::Dialed`.
+    fn discover(&mut self) {
+        let mut exclude = self.connected_peers();
+        self.outbound.retain(|addr| exclude.contains(addr));
+        exclude.extend(self.transports.iter().map(|transport| transport.addr()));
+        exclude.extend(self.dialing.keys().cloned());
+
+        let missing = self
+            .opts
+            .outbound_peers
+            .saturating_sub(self.outbound.len() + self.dialing.len())
+            .min(MAX_DIALS.saturating_sub(self.dialing.len()));
+        for addr in self.peer_table.candidates(missing, &exclude, Instant::now()) {
+            let transports = self.transports.clone();
+            let events = self.events.sender();
+            let dialed = addr.clone();
+            let worker = thread::spawn(move || {
+                let ok = dial(&transports, &dialed);
+                let _ = events.send(Event::Dialed(dialed, ok));
+            });
+            self.dialing.insert(addr, worker);
+        }
+
+        if self.outbound.len() < self.opts.outbound_peers {
+            match Message::new(MessageType::GetPeers, vec![]).as_bytes() {
+                Ok(payload) => self.broadcast(payload),
+                Err(e) => log::error!("could not ask peers for addresses: {}", e),
+            }
+        }
+    }
+
+    fn dialed(&mut self, addr: NetAddr, ok: bool) {
+        if let Some(worker) = self.dialing.remove(&addr) {
+            let _ = worker.join();
+        }
+        self.peer_table.dialed(&addr, ok, Instant::now());
+        if ok {
+            self.outbound.insert(addr);
+        }
+    }
+
+    fn handle_get_peers(&self, from: &NetAddr) -> Result<(), Box<dyn std::error::Error>> {
+        let peers = self.peers.keys().filter(|addr| *addr != from).take(MAX_PEERS_PER_MESSAGE).cloned().collect();
+        let msg = Message::new(MessageType::Peers, PeersMessage { peers }.as_bytes()?);
+        Ok(self.send(from, msg.as_bytes()?)?)
+    }
+
+    fn handle_peers(&mut self, from: &NetAddr, msg: &PeersMessage) -> Result<(), Box<dyn std::error::Error>> {
+        let own: HashSet<NetAddr> = self.transports.iter().map(|transport| transport.addr()).collect();
+        let learned = msg
+            .peers
+            .iter()
+            .take(MAX_PEERS_PER_MESSAGE)
+            .filter(|addr| !own.contains(*addr) && self.peer_table.add(addr))
+            .count();
+        if learned > 0 {
+            info!("learned {} peer addresses from {}, {} known", learned, from, self.peer_table.len());
+        }
+        Ok(())
+    }
+
+    fn handle_status(&mut self, from: &NetAddr, status: &StatusMessage) -> Result<(), Box<dyn std::error::Error>> {
+        if status.version != PROTOCOL_VERSION {
+            return Err(format!("peer {} speaks protocol version {}, expected {}", from, status.version, PROTOCOL_VERSION).into());
+        }
+        if let Some(peer) = self.peers.get_mut(from) {
+            peer.height = status.height;
+        }
+        self.start_sync(from, status.height)
+    }
+
+    // Starts downloading blocks from a peer whose chain is longer than ours,
+    // unless a download from another peer is still under way.
+    fn start_sync(&mut self, from: &NetAddr, height: u32) -> Result<(), Box<dyn std::error::Error>> {
+        if height <= self.chain.height() {
+            return Ok(());
+        }
+        match &mut self.sync {
+            // A request that got no answer in time is sent again, to whichever
+            // peer reports a higher chain, the one we sync from included.
+            Some(sync) if sync.requested.elapsed() < SYNC_TIMEOUT => {
+                if sync.peer == *from {
+                    sync.target = sync.target.max(height);
+                }
+                Ok(())
+            }
+            _ => {
+                info!("syncing from {}: height={}, our height={}", from, height, self.chain.height());
+                self.request_blocks(from, self.chain.height() + 1, height)
+            }
+        }
+    }
+
+    fn request_blocks(&mut self, peer: &NetAddr, from: u32, target: u32) -> Result<(), Box<dyn std::error::Error>> {
+        let request = GetBlocksMessage { from, to: target.min(from + SYNC_BATCH - 1) };
+        let msg = Message::new(MessageType::GetBlocks, request.as_bytes()?);
+        self.sync = Some(SyncState { peer: peer.clone(), target, requested: Instant::now() });
+        Ok(self.send(peer, msg.as_bytes()?)?)
+    }
+
+    fn handle_get_blocks(&self, from: &NetAddr, request: &GetBlocksMessage) -> Result<(), Box<dyn std::error::Error>> {
+        if request.from > request.to {
+            return Err(format!("invalid block range {}..={}", request.from, request.to).into());
+        }
+        let to = request.to.min(request.from.saturating_add(SYNC_BATCH - 1));
+        let blocks = match request.from <= self.chain.height() {
+            true => self.chain.get_blocks(request.from, to)?,
+            false => vec![],
+        };
+        let msg = Message::new(MessageType::Blocks, BlocksMessage { blocks }.as_bytes()?);
+        Ok(self.send(from, msg.as_bytes()?)?)
+    }
+
+    // Adds a batch of downloaded blocks and asks for the next one.
+    fn handle_blocks(&mut self, from: &NetAddr, msg: &BlocksMessage) -> Result<(), Box<dyn std::error::Error>> {
+        let target = match &self.sync {
+            Some(sync) if sync.peer == *from => sync.target,
+            _ => {
+                info!("ignoring blocks from {}, which we are not syncing from", from);
+                return Ok(());
+            }
+        };
+        // The sync is restarted by the next status if anything goes wrong.
+        let sync = self.sync.take();
+
+        let mut blocks = msg.blocks.clone();
+        let (first, last) = match (blocks.first(), blocks.last()) {
+            (Some(first), Some(last)) => (first.header, last.header),
+            _ => return Ok(()),
+        };
+        for i in 1..blocks.len() {
+            let parent = blocks[i - 1].hash(Hasher::new());
+            let header = &blocks[i].header;
+            if header.prev_block != parent || header.height != blocks[i - 1].header.height + 1 {
+                return Err(format!("peer {} sent blocks that do not form a chain", from).into());
+            }
+        }
+
+        // The peer is on a fork that branches off below the requested range,
+        // walk back towards the common ancestor.
+        if self.chain.lookup_header(&first.prev_block).is_none() {
+            let floor = self.chain.finalized_height() + 1;
+            if first.height <= floor {
+                return Err(format!("peer {} is on a fork below the finalized height", from).into());
+            }
+            let start = first.height.saturating_sub(SYNC_BATCH).max(floor);
+            self.sync = sync;
+            return self.request_blocks(from, start, target);
+        }
+
+        for b in blocks.iter_mut() {
+            if self.chain.lookup_header(&b.hash(Hasher::new())).is_some() {
+                continue;
+            }
+            self.chain.add_block(b)?;
+            self.update_pool(b);
+        }
+
+        if last.height < target {
+            self.sync = sync;
+            return self.request_blocks(from, last.height + 1, target);
+        }
+        info!("synced with {}: height={}", from, self.chain.height());
+        Ok(())
+    }
+
+    // Sends a message to a single peer, over whichever transport reaches it.
+    fn send(&self, to: &NetAddr, payload: Vec<u8>) -> Result<(), String> {
+        match self.transports.iter().find(|transport| transport.peers().contains(to)) {
+            Some(transport) => transport.send_message(to.clone(), payload),
+            None => Err(format!("unknown peer {}", to)),
+        }
+    }
+
+    // Keeps the mempool in line with the canonical chain after `b` was added:
+    // transactions included in the chain leave the pool, and those of blocks
+    // rolled back by a reorg go back into it.
+    fn update_pool(&mut self, b: &Block) {
+        let mut reorged = false;
+        while let Ok(reorg) = self.reorgs.try_recv() {
+            reorged = true;
+            for added in &reorg.added {
+                self.pool.remove_transactions(&added.transactions);
+            }
+
+            let now = Utc::now().timestamp_nanos();
+            let included: HashSet<Hash> = reorg.added
+                .iter()
+                .flat_map(|added| added.transactions.iter())
+                .filter_map(|tx| self.hasher.hash(tx).ok())
+                .collect();
+            let mut txs = vec![];
+            for dropped in &reorg.dropped {
+                for tx in &dropped.transactions {
+                    if self.hasher.hash(tx).is_ok_and(|hash| included.contains(&hash)) {
+                        continue;
+                    }
+                    let mut tx = tx.clone();
+                    tx.set_seen(now);
+                    txs.push(tx);
+                }
+            }
+            let chain = &self.chain;
+            let added = self.pool.reinject(txs, |address| chain.get_account(address));
+            info!(
+                "reorg at height {}: {} blocks dropped, {} transactions back in the mempool",
+                reorg.common_ancestor, reorg.dropped.len(), added
+            );
+        }
+
+        // Without a reorg the block either extended the tip or went to a side
+        // branch, whose transactions stay pooled.
+        let mut b = b.clone();
+        if !reorged && self.chain.get_header_by_hash(&b.hash(Hasher::new())).is_some() {
+            self.pool.remove_transactions(&b.transactions);
+        }
+    }
+
+    fn broadcast_tx(&self, tx: &Transaction) -> Result<(), Box<dyn std::error::Error>> {
+        let msg = Message::new(MessageType::Tx, tx.as_bytes()?);
+        self.broadcast(msg.as_bytes()?);
+        Ok(())
+    }
+
+    // Sends a message to every peer that completed the handshake.
+    fn broadcast(&self, payload: Vec<u8>) {
+        for addr in self.peers.keys() {
+            if let Err(e) = self.send(addr, payload.clone()) {
+                log::warn!("{}", e);
+            }
+        }
+    }
+
+    fn broadcast_block(&self, b: &Block) -> Result<(), Box<dyn std::error::Error>> {
+        let msg = Message::new(MessageType::Block, b.as_bytes()?);
+        self.broadcast(msg.as_bytes()?);
+        Ok(())
+    }
+
+    fn create_new_block(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let key = match &self.opts.key {
+            Some(key) => key.clone(),
+            None => return Err("server has no validator key".into()),
+        };
+
+        let tip = self.chain.get_header(self.chain.height()).expect("tip header is always present");
+        // Transactions that do not apply on top of the tip are left out, a
+        // block containing them would be rejected.
+        let coinbase = key.generate_public().address()?;
+        let mut state = self.chain.state();
+        let mut txs = vec![];
+        for tx in self.pool.take_by_gas(MAX_BLOCK_GAS) {
+            match state.apply_transaction(&tx, Some(&coinbase)) {
+                Ok(_) => txs.push(tx),
+                Err(e) => log::debug!("leaving transaction out of block: {}", e),
+            }
+        }
+        let mut block = Block::from_prev_header(&tip, txs);
+        block.sign(key)?;
+        self.chain.add_block(&mut block)?;
+
+        let hash = block.hash(Hasher::new());
+        info!(
+            "created new block - height: {}, hash: {}, txs: {}",
+            block.header.height, hash, block.transactions.len()
+        );
+
+        self.update_pool(&block);
+        self.broadcast_block(&block)?;
         Ok(())
     }
 
 }
 
 
+impl RPCProcessor for Server {
+    fn process_message(&mut self, dm: &DecodedMessage) -> Result<(), Box<dyn std::error::Error>> {
+        if let Decoded::Handshake(msg) = &dm.data {
+            return self.handle_handshake(&dm.from, msg);
+        }
+        if !self.peers.contains_key(&dm.from) {
+            return Err(format!("peer {} has not completed the handshake", dm.from).into());
+        }
+        match &dm.data {
+            Decoded::Tx(tx) => self.handle_transaction(tx),
+            Decoded::Block(b) => self.process_block(&dm.from, b),
+            Decoded::GetStatus => self.handle_get_status(&dm.from),
+            Decoded::Status(status) => self.handle_status(&dm.from, status),
+            Decoded::GetBlocks(request) => self.handle_get_blocks(&dm.from, request),
+            Decoded::Blocks(blocks) => self.handle_blocks(&dm.from, blocks),
+            Decoded::GetPeers => self.handle_get_peers(&dm.from),
+            Decoded::Peers(msg) => self.handle_peers(&dm.from, msg),
+            Decoded::Handshake(_) => unreachable!("handshakes are handled above"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::io::{Cursor, Read, Write};
+    use std::net::TcpStream;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::core::block::Block;
+    use crate::core::events::ChainEvent;
+    use crate::core::state::Account;
+    use crate::types::hash::Hash;
+    use crate::core::encoding::{Decode, Decoder};
+    use crate::core::hasher::Hasher;
+    use crate::core::transaction::{Transaction, Transfer, DEFAULT_CHAIN_ID};
+    use crate::crypto::keypair::PrivateKey;
+    use crate::network::local_transport::LocalTransport;
+    use crate::network::tcp_transport::TcpTransport;
+    use crate::core::hasher::Bytes;
+    use crate::network::rpc::{default_rpc_decode_func, Decoded, DecodedMessage, Message, MessageType, RPCProcessor};
+    use crate::network::transport::{NetAddr, Transport, TransportWrapper, RPC};
+
+    use serde_json::{json, Value};
+
+    use crate::network::api::{ApiError, Call};
+    use super::{Event, PeerInfo, Server, ServerOpts, DEFAULT_OUTBOUND_PEERS, MAX_DIALS, SYNC_BATCH, SYNC_TIMEOUT};
+
+    fn new_server(key: Option<PrivateKey>, transport: LocalTransport) -> Server {
+        Server::new(ServerOpts {
+            transports: vec![Box::new(transport)],
+            block_time: None,
+            key,
+            rpc_decode_func: default_rpc_decode_func,
+            data_dir: None,
+            chain_id: DEFAULT_CHAIN_ID,
+            bootstrap: vec![],
+            outbound_peers: DEFAULT_OUTBOUND_PEERS,
+            api_addr: None,
+            ws_addr: None,
+            genesis_alloc: BTreeMap::new(),
+        }).unwrap()
+    }
+
+    // Lets `server` talk to `addr` as if they had exchanged handshakes.
+    fn trust(server: &mut Server, addr: &str) {
+        server.peers.insert(addr.to_owned(), PeerInfo { node_id: 0, height: 0 });
+        server.greeted.insert(addr.to_owned());
+    }
+
+    #[test]
+    fn test_create_new_block() {
+        let tr_local = LocalTransport::new("LOCAL".to_owned());
+        let tr_remote = LocalTransport::new("REMOTE".to_owned());
+        assert!(tr_local.connect(TransportWrapper::Local(&tr_remote)).is_ok());
+
+        let receiver = tr_remote.consume();
+        let handle = thread::spawn(move || receiver.lock().unwrap().recv().unwrap());
+
+        let key = PrivateKey::generate_key();
+        let mut server = new_server(Some(key.clone()), tr_local);
+        trust(&mut server, "REMOTE");
+
+        let mut tx = Transaction::new(b"foo".to_vec()).unwrap();
+        assert!(tx.sign(&key).is_ok());
+        assert!(server.pool.add(tx.clone(), Account::default()).is_ok());
+
+        assert!(server.create_new_block().is_ok());
+        assert_eq!(server.chain.height(), 1);
+        assert_eq!(server.pool.len(), 0);
+
+        let rpc = handle.join().unwrap();
+        let mut payload = Cursor::new(rpc.payload);
+        let msg: Message = Decoder::new(&mut payload).decode().unwrap();
+        assert_eq!(msg.header, MessageType::Block);
+
+        let mut data = Cursor::new(msg.data);
+        let b: Block = Decoder::new(&mut data).decode().unwrap();
+        assert_eq!(b.header, server.chain.get_header(1).unwrap());
+        assert_eq!(b.transactions, vec![tx]);
+        assert!(b.verify().is_ok());
+    }
+
+    #[test]
+    fn test_process_transaction() {
+        let tr_local = LocalTransport::new("LOCAL".to_owned());
+        let tr_remote = LocalTransport::new("REMOTE".to_owned());
+        assert!(tr_local.connect(TransportWrapper::Local(&tr_remote)).is_ok());
+
+        let receiver = tr_remote.consume();
+        let handle = thread::spawn(move || receiver.lock().unwrap().recv().unwrap());
+
+        let mut server = new_server(None, tr_local);
+        trust(&mut server, "REMOTE");
+
+        let key = PrivateKey::generate_key();
+        let mut tx = Transaction::new(b"foo".to_vec()).unwrap();
+        assert!(tx.sign(&key).is_ok());
+        let payload = Message::new(MessageType::Tx, tx.as_bytes().unwrap()).as_bytes().unwrap();
+
+        let rpc = RPC { from: "REMOTE".to_owned(), payload: payload.clone() };
+        let dm = (server.opts.rpc_decode_func)(rpc).unwrap();
+        assert!(server.process_message(&dm).is_ok());
+        assert_eq!(server.pool.len(), 1);
+
+        // The new transaction is gossiped on to the peers.
+        assert_eq!(handle.join().unwrap().payload, payload);
+
+        // A transaction already in the pool is not broadcast again.
+        assert!(server.process_message(&dm).is_ok());
+        assert_eq!(server.pool.len(), 1);
+
+        tx.data = b"bar".to_vec();
+        let rpc = RPC { from: "REMOTE".to_owned(), payload: Message::new(MessageType::Tx, tx.as_bytes().unwrap()).as_bytes().unwrap() };
+        let dm = (server.opts.rpc_decode_func)(rpc).unwrap();
+        assert!(server.process_message(&dm).is_err());
+        assert_eq!(server.pool.len(), 1);
+
+        // A transaction after a nonce gap waits in the queue.
+        let receiver = tr_remote.consume();
+        let handle = thread::spawn(move || receiver.lock().unwrap().recv().unwrap());
+        tx.nonce = 2;
+        assert!(tx.sign(&key).is_ok());
+        let rpc = RPC { from: "REMOTE".to_owned(), payload: Message::new(MessageType::Tx, tx.as_bytes().unwrap()).as_bytes().unwrap() };
+        let dm = (server.opts.rpc_decode_func)(rpc).unwrap();
+        assert!(server.process_message(&dm).is_ok());
+        assert!(handle.join().is_ok());
+        assert_eq!((server.pool.pending_len(), server.pool.queued_len()), (1, 1));
+        assert_eq!(server.pool.take(2).len(), 1);
+
+        // Nonces too far ahead of the account are rejected.
+        tx.nonce = 1_000;
+        assert!(tx.sign(&key).is_ok());
+        let rpc = RPC { from: "REMOTE".to_owned(), payload: Message::new(MessageType::Tx, tx.as_bytes().unwrap()).as_bytes().unwrap() };
+        let dm = (server.opts.rpc_decode_func)(rpc).unwrap();
+        assert!(server.process_message(&dm).is_err());
+        assert_eq!(server.pool.len(), 2);
+    }
+
+    #[test]
+    fn test_process_block() {
+        let tr_a = LocalTransport::new("A".to_owned());
+        let tr_b = LocalTransport::new("B".to_owned());
+        let tr_c = LocalTransport::new("C".to_owned());
+        assert!(tr_a.connect(TransportWrapper::Local(&tr_b)).is_ok());
+        assert!(tr_b.connect(TransportWrapper::Local(&tr_c)).is_ok());
+
+        // A produces a block and gossips it to B.
+        let receiver_b = tr_b.consume();
+        let handle = thread::spawn(move || receiver_b.lock().unwrap().recv().unwrap());
+        let mut server_a = new_server(Some(PrivateKey::generate_key()), tr_a);
+        trust(&mut server_a, "B");
+        assert!(server_a.create_new_block().is_ok());
+        let rpc = handle.join().unwrap();
+
+        // B accepts it and gossips it on to C.
+        let receiver_c = tr_c.consume();
+        let handle = thread::spawn(move || receiver_c.lock().unwrap().recv().unwrap());
+        let mut server_b = new_server(None, tr_b);
+        trust(&mut server_b, "A");
+        trust(&mut server_b, "C");
+        let payload = rpc.payload.clone();
+        let dm = (server_b.opts.rpc_decode_func)(rpc).unwrap();
+        assert!(server_b.process_message(&dm).is_ok());
+        assert_eq!(server_b.chain.height(), 1);
+        assert_eq!(server_b.chain.get_header(1), server_a.chain.get_header(1));
+        assert_eq!(handle.join().unwrap().payload, payload);
+
+        // Seen blocks are skipped instead of being gossiped again.
+        assert!(server_b.process_message(&dm).is_ok());
+        assert_eq!(server_b.chain.height(), 1);
+
+        // Blocks that do not fit the chain are rejected.
+        let mut b = Block::random_block_with_signature(2);
+        let rpc = RPC { from: "A".to_owned(), payload: Message::new(MessageType::Block, b.as_bytes().unwrap()).as_bytes().unwrap() };
+        let dm = (server_b.opts.rpc_decode_func)(rpc).unwrap();
+        assert!(server_b.process_message(&dm).is_err());
+        assert_eq!(server_b.chain.height(), 1);
+        assert!(server_b.chain.lookup_header(&b.hash(Hasher::new())).is_none());
+    }
+
+    #[test]
+    fn test_pool_follows_chain() {
+        let mut server = new_server(None, LocalTransport::new("LOCAL".to_owned()));
+        let genesis = server.chain.get_header(0).unwrap();
+        let key = PrivateKey::generate_key();
+        let txs: Vec<Transaction> = (0..3).map(|nonce| {
+            let mut tx = Transaction::new(vec![nonce as u8]).unwrap();
+            tx.nonce = nonce;
+            assert!(tx.sign(&key).is_ok());
+            assert!(server.pool.add(tx.clone(), Account::default()).is_ok());
+            tx
+        }).collect();
+
+        // Only the transactions of an accepted block leave the pool.
+        let mut a1 = Block::from_prev_header(&genesis, txs[..2].to_vec());
+        assert!(a1.sign(PrivateKey::generate_key()).is_ok());
+        assert!(server.process_block(&"PEER".to_owned(), &a1).is_ok());
+        assert_eq!(server.pool.len(), 1);
+
+        // A longer branch without them rolls a1 back.
+        let mut b1 = Block::from_prev_header(&genesis, vec![]);
+        assert!(b1.sign(PrivateKey::generate_key()).is_ok());
+        assert!(server.process_block(&"PEER".to_owned(), &b1).is_ok());
+        assert_eq!(server.pool.len(), 1);
+        let mut b2 = Block::from_prev_header(&b1.header, vec![]);
+        assert!(b2.sign(PrivateKey::generate_key()).is_ok());
+        assert!(server.process_block(&"PEER".to_owned(), &b2).is_ok());
+        assert_eq!(server.chain.height(), 2);
+        assert_eq!(server.pool.len(), 3);
+        assert_eq!(server.pool.take(3).iter().filter(|tx| tx.seen().is_some()).count(), 2);
+
+        // Expired transactions are dropped.
+        assert_eq!(server.pool.prune_expired(i64::MAX), 3);
+        assert_eq!(server.pool.len(), 0);
+    }
+
+    // Runs `send`, which makes a server send one message over `to`, and
+    // returns the message.
+    fn exchange<F: FnOnce()>(to: &LocalTransport, send: F) -> DecodedMessage {
+        let receiver = to.consume();
+        let handle = thread::spawn(move || receiver.lock().unwrap().recv().unwrap());
+        send();
+        default_rpc_decode_func(handle.join().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_sync() {
+        let tr_a = LocalTransport::new("A".to_owned());
+        let tr_b = LocalTransport::new("B".to_owned());
+        let mut server_a = new_server(Some(PrivateKey::generate_key()), tr_a.clone());
+        let mut server_b = new_server(Some(PrivateKey::generate_key()), tr_b.clone());
+
+        // Both build a chain before they meet, B's is shorter and forks off
+        // right after genesis.
+        let height = SYNC_BATCH + 8;
+        for _ in 0..height {
+            assert!(server_a.create_new_block().is_ok());
+        }
+        for _ in 0..2 {
+            assert!(server_b.create_new_block().is_ok());
+        }
+        assert!(tr_a.connect(TransportWrapper::Local(&tr_b)).is_ok());
+        assert!(tr_b.connect(TransportWrapper::Local(&tr_a)).is_ok());
+        trust(&mut server_a, "B");
+        trust(&mut server_b, "A");
+
+        let get_status = exchange(&tr_a, || assert!(server_b.request_status().is_ok()));
+        let status = exchange(&tr_b, || assert!(server_a.process_message(&get_status).is_ok()));
+        match &status.data {
+            Decoded::Status(status) => assert_eq!(status.height, height),
+            other => panic!("expected a status, got {:?}", other),
+        }
+
+        // B first asks for the blocks above its tip, walks back to the fork
+        // when they do not fit, then downloads the rest.
+        let mut request = exchange(&tr_a, || assert!(server_b.process_message(&status).is_ok()));
+        for (from, expected_height) in [(3, 2), (1, SYNC_BATCH), (SYNC_BATCH + 1, height)] {
+            match &request.data {
+                Decoded::GetBlocks(request) => assert_eq!(request.from, from),
+                other => panic!("expected a block request, got {:?}", other),
+            }
+            let blocks = exchange(&tr_b, || assert!(server_a.process_message(&request).is_ok()));
+            if expected_height < height {
+                request = exchange(&tr_a, || assert!(server_b.process_message(&blocks).is_ok()));
+            } else {
+                assert!(server_b.process_message(&blocks).is_ok());
+            }
+            assert_eq!(server_b.chain.height(), expected_height);
+        }
+        assert_eq!(server_b.chain.get_header(height), server_a.chain.get_header(height));
+        assert!(server_b.sync.is_none());
+
+        // Blocks nobody asked for are ignored.
+        let blocks = exchange(&tr_b, || assert!(server_a.process_message(&request).is_ok()));
+        assert!(server_b.process_message(&blocks).is_ok());
+        assert_eq!(server_b.chain.height(), height);
+
+        // A request that got lost is sent again once it timed out, even if
+        // the same peer reports its chain.
+        let _broadcast = exchange(&tr_b, || assert!(server_a.create_new_block().is_ok()));
+        let status = exchange(&tr_b, || assert!(server_a.process_message(&get_status).is_ok()));
+        let lost = exchange(&tr_a, || assert!(server_b.process_message(&status).is_ok()));
+        assert!(matches!(lost.data, Decoded::GetBlocks(_)));
+        server_b.sync.as_mut().unwrap().requested -= SYNC_TIMEOUT;
+        let retry = exchange(&tr_a, || assert!(server_b.process_message(&status).is_ok()));
+        match &retry.data {
+            Decoded::GetBlocks(request) => assert_eq!(request.from, height + 1),
+            other => panic!("expected a block request, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_handshake() {
+        let tr_a = LocalTransport::new("A".to_owned());
+        let tr_b = LocalTransport::new("B".to_owned());
+        let tr_c = LocalTransport::new("C".to_owned());
+        assert!(tr_a.connect(TransportWrapper::Local(&tr_b)).is_ok());
+        assert!(tr_b.connect(TransportWrapper::Local(&tr_a)).is_ok());
+        assert!(tr_a.connect(TransportWrapper::Local(&tr_c)).is_ok());
+        assert!(tr_c.connect(TransportWrapper::Local(&tr_a)).is_ok());
+        let mut server_a = new_server(None, tr_a.clone());
+        let mut server_b = new_server(None, tr_b.clone());
+        let mut server_c = Server::new(ServerOpts {
+            transports: vec![Box::new(tr_c.clone())],
+            block_time: None,
+            key: None,
+            rpc_decode_func: default_rpc_decode_func,
+            data_dir: None,
+            chain_id: DEFAULT_CHAIN_ID + 1,
+            bootstrap: vec![],
+            outbound_peers: DEFAULT_OUTBOUND_PEERS,
+            api_addr: None,
+            ws_addr: None,
+            genesis_alloc: BTreeMap::new(),
+        }).unwrap();
+
+        // Peers are not listened to before the handshake.
+        let get_status = Message::new(MessageType::GetStatus, vec![]).as_bytes().unwrap();
+        let dm = default_rpc_decode_func(RPC { from: "B".to_owned(), payload: get_status }).unwrap();
+        assert!(server_a.process_message(&dm).is_err());
+
+        // B answers A's handshake with its own.
+        let handshake = exchange(&tr_b, || assert!(server_a.send_handshake(&"B".to_owned()).is_ok()));
+        let reply = exchange(&tr_a, || assert!(server_b.process_message(&handshake).is_ok()));
+        assert!(server_b.peers.contains_key("A"));
+        assert!(server_a.process_message(&reply).is_ok());
+        assert!(server_a.peers.contains_key("B"));
+
+        // C is on another chain and gets dropped.
+        let handshake = exchange(&tr_a, || assert!(server_c.send_handshake(&"A".to_owned()).is_ok()));
+        assert!(server_a.process_message(&handshake).is_err());
+        assert!(!server_a.peers.contains_key("C"));
+        assert!(!tr_a.has_peer(&"C".to_owned()));
+
+        // A node that dialed itself drops the connection too.
+        assert!(tr_b.connect(TransportWrapper::Local(&tr_b.clone())).is_ok());
+        let handshake = exchange(&tr_b, || assert!(server_b.send_handshake(&"B".to_owned()).is_ok()));
+        assert!(server_b.process_message(&handshake).is_err());
+        assert!(!tr_b.has_peer(&"B".to_owned()));
+    }
+
+    fn tcp_server(bootstrap: Vec<NetAddr>, outbound_peers: usize) -> (Server, TcpTransport) {
+        let transport = TcpTransport::listen("127.0.0.1:0").unwrap();
+        let server = Server::new(ServerOpts {
+            transports: vec![Box::new(transport.clone())],
+            block_time: None,
+            key: None,
+            rpc_decode_func: default_rpc_decode_func,
+            data_dir: None,
+            chain_id: DEFAULT_CHAIN_ID,
+            bootstrap,
+            outbound_peers,
+            api_addr: None,
+            ws_addr: None,
+            genesis_alloc: BTreeMap::new(),
+        }).unwrap();
+        (server, transport)
+    }
+
+    // Hands the next message that arrived at `transport` to `server`.
+    fn deliver(server: &mut Server, transport: &TcpTransport) {
+        let rpc = transport.consume().lock().unwrap().recv_timeout(Duration::from_secs(5)).unwrap();
+        let dm = default_rpc_decode_func(rpc).unwrap();
+        assert!(server.process_message(&dm).is_ok());
+    }
+
+    // Hands the results of the dials discovery started to `server`.
+    fn finish_dials(server: &mut Server) {
+        let events = server.events.receiver();
+        while !server.dialing.is_empty() {
+            let event = events.lock().unwrap().recv_timeout(Duration::from_secs(10)).unwrap();
+            if let Event::Dialed(addr, ok) = event {
+                server.dialed(addr, ok);
+            }
+        }
+    }
+
+    #[test]
+    fn test_discovery() {
+        let (mut seed, tr_seed) = tcp_server(vec![], 2);
+        let (mut b, tr_b) = tcp_server(vec![tr_seed.addr()], 2);
+        let (mut c, tr_c) = tcp_server(vec![tr_seed.addr()], 2);
+
+        // B and C only know the seed. They dial it and exchange handshakes.
+        for (server, transport) in [(&mut b, &tr_b), (&mut c, &tr_c)] {
+            server.discover();
+            finish_dials(server);
+            assert!(server.outbound.contains(&tr_seed.addr()));
+            server.greet_peers();
+            deliver(&mut seed, &tr_seed);
+            deliver(server, transport);
+        }
+
+        // C is short of outbound peers, so it asks the seed for more and
+        // dials B.
+        c.discover();
+        deliver(&mut seed, &tr_seed);
+        deliver(&mut c, &tr_c);
+        assert_eq!(c.peer_table.len(), 2);
+        c.discover();
+        assert_eq!(c.dialing.len(), 1);
+        finish_dials(&mut c);
+        assert!(tr_c.has_peer(&tr_b.addr()));
+        assert_eq!(c.outbound.len(), 2);
+    }
+
+    #[test]
+    fn test_dial_does_not_block() {
+        // Nothing answers on a blackholed address, so the dial takes until
+        // its timeout.
+        let (mut server, _) = tcp_server(vec!["10.255.255.1:3000".to_owned()], 1);
+        let started = Instant::now();
+        server.discover();
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert!(server.dialing.contains_key("10.255.255.1:3000"));
+
+        // It is not dialed twice while the first attempt is under way.
+        server.discover();
+        assert_eq!(server.dialing.len(), 1);
+
+        // However many peers are missing, only so many are dialed at once.
+        let blackholed = (1..=MAX_DIALS as u8 * 2).map(|i| format!("10.255.255.{}:3000", i)).collect();
+        let (mut server, _) = tcp_server(blackholed, MAX_DIALS * 2);
+        server.discover();
+        assert_eq!(server.dialing.len(), MAX_DIALS);
+    }
+
+    #[test]
+    fn test_shutdown_waits_for_dials() {
+        let (server, transport) = tcp_server(vec!["10.255.255.1:3000".to_owned()], 1);
+        let handle = server.start();
+        thread::sleep(Duration::from_millis(100));
+        assert!(handle.shutdown().is_ok());
+
+        // The dial was over before the transport closed, so it left no
+        // thread behind to reconnect.
+        assert!(transport.peers().is_empty());
+        assert!(TcpTransport::listen(&transport.addr()).is_ok());
+    }
+
+    #[test]
+    fn test_start_and_shutdown() {
+        let dir = std::env::temp_dir().join(format!("rustchain-shutdown-{}", Hash::random()));
+        let opts = |transport: LocalTransport| ServerOpts {
+            transports: vec![Box::new(transport)],
+            block_time: Some(Duration::from_millis(20)),
+            key: Some(PrivateKey::generate_key()),
+            rpc_decode_func: default_rpc_decode_func,
+            data_dir: Some(dir.clone()),
+            chain_id: DEFAULT_CHAIN_ID,
+            bootstrap: vec![],
+            outbound_peers: DEFAULT_OUTBOUND_PEERS,
+            api_addr: None,
+            ws_addr: None,
+            genesis_alloc: BTreeMap::new(),
+        };
+
+        let transport = LocalTransport::new("LOCAL".to_owned());
+        let handle = Server::new(opts(transport.clone())).unwrap().start();
+        thread::sleep(Duration::from_millis(200));
+        assert!(handle.shutdown().is_ok());
+
+        // The reader let go of the transport and the blocks made it to disk.
+        assert!(transport.consume().try_lock().is_ok());
+        let server = Server::new(opts(LocalTransport::new("LOCAL".to_owned()))).unwrap();
+        assert!(server.chain.height() > 0);
+        drop(server);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    fn api(server: &mut Server, method: &str, params: Value) -> Result<Value, ApiError> {
+        let params = params.as_array().unwrap().clone();
+        server.handle_api_call(&Call { method: method.to_owned(), params })
+    }
+
+    #[test]
+    fn test_shutdown_closes_transports() {
+        let (server, transport) = tcp_server(vec![], DEFAULT_OUTBOUND_PEERS);
+        let handle = server.start();
+        assert!(handle.shutdown().is_ok());
+
+        // A node restarted on the same address can listen again.
+        assert!(TcpTransport::listen(&transport.addr()).is_ok());
+    }
+
+    #[test]
+    fn test_api() {
+        let key = PrivateKey::generate_key();
+        let mut server = new_server(Some(key.clone()), LocalTransport::new("LOCAL".to_owned()));
+        let events = server.chain.events().subscribe(8);
+
+        let mut tx = Transaction::new(b"foo".to_vec()).unwrap();
+        assert!(tx.sign(&key).is_ok());
+        let hash = tx.hash(Hasher::new()).to_string();
+        assert_eq!(api(&mut server, "sendTransaction", json!([hex::encode(tx.as_bytes().unwrap())])), Ok(json!(hash)));
+        assert_eq!(api(&mut server, "getPoolSize", json!([])), Ok(json!({ "pending": 1, "queued": 0 })));
+        assert_eq!(api(&mut server, "getTransaction", json!([hash])).unwrap()["blockHeight"], Value::Null);
+        assert!(matches!(api(&mut server, "sendTransaction", json!(["zz"])), Err(ApiError::InvalidParams(_))));
+        assert!(matches!(api(&mut server, "getTransaction", json!([])), Err(ApiError::InvalidParams(_))));
+        assert!(matches!(api(&mut server, "getBalance", json!([])), Err(ApiError::MethodNotFound(_))));
+
+        assert!(server.create_new_block().is_ok());
+        assert_eq!(api(&mut server, "getHeight", json!([])), Ok(json!(1)));
+        // The pool and the chain publish to the same bus.
+        assert!(matches!(events.try_recv(), Ok(ChainEvent::PendingTransaction(_))));
+        assert!(matches!(events.try_recv(), Ok(ChainEvent::NewHead(header)) if header.height == 1));
+        let b = api(&mut server, "getBlockByHeight", json!([1])).unwrap();
+        assert_eq!(b["transactions"][0]["hash"], json!(hash));
+        assert_eq!(api(&mut server, "getBlockByHash", json!([b["hash"]])), Ok(b));
+        assert_eq!(api(&mut server, "getBlockByHeight", json!([2])), Ok(Value::Null));
+        assert_eq!(api(&mut server, "getTransaction", json!([hash])).unwrap()["blockHeight"], json!(1));
+        let sender = key.generate_public().address().unwrap().to_string();
+        assert_eq!(api(&mut server, "getAccount", json!([sender])).unwrap()["nonce"], json!(1));
+
+        trust(&mut server, "REMOTE");
+        assert_eq!(
+            api(&mut server, "getPeers", json!([])),
+            Ok(json!([{ "addr": "REMOTE", "nodeId": 0, "height": 0, "outbound": false }]))
+        );
+    }
+
+    #[test]
+    fn test_genesis_alloc() {
+        let (alice, bob) = (PrivateKey::generate_key(), PrivateKey::generate_key());
+        let from = alice.generate_public().address().unwrap();
+        let to = bob.generate_public().address().unwrap();
+        let mut server = Server::new(ServerOpts {
+            transports: vec![Box::new(LocalTransport::new("LOCAL".to_owned()))],
+            block_time: None,
+            key: Some(PrivateKey::generate_key()),
+            rpc_decode_func: default_rpc_decode_func,
+            data_dir: None,
+            chain_id: DEFAULT_CHAIN_ID,
+            bootstrap: vec![],
+            outbound_peers: DEFAULT_OUTBOUND_PEERS,
+            api_addr: None,
+            ws_addr: None,
+            genesis_alloc: BTreeMap::from([(from, 1_000)]),
+        }).unwrap();
+        assert_eq!(server.chain.get_account(&from).balance, 1_000);
+
+        let mut tx = Transaction::new_transfer(Transfer { from, to, amount: 400 });
+        assert!(tx.sign(&alice).is_ok());
+        assert!(api(&mut server, "sendTransaction", json!([hex::encode(tx.as_bytes().unwrap())])).is_ok());
+        assert!(server.create_new_block().is_ok());
+        let included = server.chain.get_block_by_height(1).unwrap().transactions;
+        assert_eq!(included.iter().map(|tx| tx.transfer).collect::<Vec<_>>(), vec![tx.transfer]);
+        assert_eq!(server.chain.get_account(&from), Account { balance: 600, nonce: 1 });
+        assert_eq!(server.chain.get_account(&to).balance, 400);
+
+        // Nobody can send more than they were given.
+        let mut tx = Transaction::new_transfer(Transfer { from: to, to: from, amount: 401 });
+        assert!(tx.sign(&bob).is_ok());
+        assert!(matches!(
+            api(&mut server, "sendTransaction", json!([hex::encode(tx.as_bytes().unwrap())])),
+            Err(ApiError::Failed(_))
+        ));
+    }
+
+    #[test]
+    fn test_api_over_http() {
+        let server = Server::new(ServerOpts {
+            transports: vec![Box::new(LocalTransport::new("LOCAL".to_owned()))],
+            block_time: None,
+            key: None,
+            rpc_decode_func: default_rpc_decode_func,
+            data_dir: None,
+            chain_id: DEFAULT_CHAIN_ID,
+            bootstrap: vec![],
+            outbound_peers: DEFAULT_OUTBOUND_PEERS,
+            api_addr: Some("127.0.0.1:0".to_owned()),
+            ws_addr: None,
+            genesis_alloc: BTreeMap::new(),
+        }).unwrap();
+        let addr = server.api_addr().unwrap();
+        let handle = server.start();
+
+        let body = r#"{"jsonrpc":"2.0","id":1,"method":"getHeight"}"#;
+        let mut stream = TcpStream::connect(addr).unwrap();
+        write!(stream, "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let body = response.split("\r\n\r\n").nth(1).unwrap();
+        assert_eq!(serde_json::from_str::<Value>(body).unwrap()["result"], json!(0));
+
+        assert!(handle.shutdown().is_ok());
+        assert!(TcpStream::connect(addr).is_err());
+    }
+}
@@ -0,0 +1,514 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{Receiver, TrySendError};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::network::channel::Channel;
+
+use super::transport::{NetAddr, RPC, Transport, TransportWrapper};
+
+// Frames larger than this are treated as a protocol violation.
+const MAX_FRAME_LEN: u32 = 32 * 1024 * 1024;
+// The hello only carries an address, and has to arrive soon after connecting.
+const MAX_HELLO_LEN: u32 = 512;
+const HELLO_TIMEOUT: Duration = Duration::from_secs(5);
+// Most inbound connections waiting for their hello at the same time.
+const MAX_PENDING: usize = 64;
+const RPC_BUFFER: usize = 1024;
+const MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+const DIAL_TIMEOUT: Duration = Duration::from_secs(5);
+// How often the listener checks for new connections, and it and threads
+// waiting on a full channel or a backoff whether the transport was closed.
+const POLL: Duration = Duration::from_millis(50);
+
+fn write_frame<W: Write>(writer: &mut W, payload: &[u8]) -> io::Result<()> {
+    if payload.len() > MAX_FRAME_LEN as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "frame too large"));
+    }
+    writer.write_u32::<BigEndian>(payload.len() as u32)?;
+    writer.write_all(payload)?;
+    writer.flush()
+}
+
+fn read_frame<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    read_frame_max(reader, MAX_FRAME_LEN)
+}
+
+fn read_frame_max<R: Read>(reader: &mut R, max: u32) -> io::Result<Vec<u8>> {
+    let len = reader.read_u32::<BigEndian>()?;
+    if len > max {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes is too large", len)));
+    }
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload)?;
+    Ok(payload)
+}
+
+struct Peer {
+    // Identifies the connection, so a reader of a replaced connection does
+    // not remove its successor.
+    id: u64,
+    stream: Arc<Mutex<TcpStream>>,
+}
+
+/// Transport over TCP. Messages are framed with a big endian u32 length
+/// prefix. The first frame on a new connection carries the listen address of
+/// the dialing side, which is then used as its `NetAddr`. Connections
+/// claiming the address of a connected peer are refused. Peers that were
+/// dialed are redialed with backoff when the connection drops.
+#[derive(Clone)]
+pub struct TcpTransport {
+    addr: NetAddr,
+    chan: Channel<RPC>,
+    peers: Arc<RwLock<HashMap<NetAddr, Peer>>>,
+    next_id: Arc<AtomicU64>,
+    closed: Arc<AtomicBool>,
+    // Accept loop and connection readers, joined on close.
+    threads: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl TcpTransport {
+    /// Binds `addr` and starts accepting connections. Port 0 picks a free
+    /// port, `addr()` returns the bound address.
+    pub fn listen(addr: &str) -> io::Result<Self> {
+        TcpTransport::bind(addr, None)
+    }
+
+    /// Like `listen`, but tells peers it can be reached at `advertise`
+    /// instead of the bound address, e.g. when listening on 0.0.0.0.
+    pub fn listen_as(addr: &str, advertise: &str) -> io::Result<Self> {
+        TcpTransport::bind(addr, Some(advertise))
+    }
+
+    fn bind(addr: &str, advertise: Option<&str>) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        let transport = TcpTransport {
+            addr: match advertise {
+                Some(advertise) => advertise.to_owned(),
+                None => listener.local_addr()?.to_string(),
+            },
+            chan: Channel::with_capacity(RPC_BUFFER),
+            peers: Arc::new(RwLock::new(HashMap::new())),
+            next_id: Arc::new(AtomicU64::new(0)),
+            closed: Arc::new(AtomicBool::new(false)),
+            threads: Arc::new(Mutex::new(vec![])),
+        };
+
+        let tr = transport.clone();
+        transport.spawn(move || {
+            // Connections waiting for their hello, joined before the loop
+            // exits so `close` waits for them too.
+            let mut pending: Vec<JoinHandle<()>> = vec![];
+            // The listener is dropped, freeing the port, when the loop ends.
+            while !tr.closed.load(Ordering::SeqCst) {
+                pending.retain(|accept| !accept.is_finished());
+                match listener.accept() {
+                    Ok((_, addr)) if pending.len() >= MAX_PENDING => {
+                        log::warn!("{}: turned away {}, too many pending connections", tr.addr, addr);
+                    }
+                    Ok((stream, _)) => {
+                        let tr = tr.clone();
+                        pending.push(thread::spawn(move || tr.accept(stream)));
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL),
+                    Err(e) => log::warn!("{}: could not accept connection: {}", tr.addr, e),
+                }
+            }
+            for accept in pending {
+                let _ = accept.join();
+            }
+        });
+
+        Ok(transport)
+    }
+
+    // Runs `f` on a thread that `close` waits for.
+    fn spawn<F: FnOnce() + Send + 'static>(&self, f: F) {
+        let mut threads = self.threads.lock().unwrap();
+        threads.retain(|thread| !thread.is_finished());
+        threads.push(thread::spawn(f));
+    }
+
+    fn accept(&self, mut stream: TcpStream) {
+        let hello = stream
+            .set_nonblocking(false)
+            .and_then(|_| stream.set_read_timeout(Some(HELLO_TIMEOUT)))
+            .and_then(|_| read_frame_max(&mut stream, MAX_HELLO_LEN))
+            .and_then(|hello| stream.set_read_timeout(None).map(|_| hello));
+        let from = match hello.map(String::from_utf8) {
+            Ok(Ok(from)) => from,
+            _ => {
+                log::warn!("{}: dropping connection without a valid hello", self.addr);
+                return;
+            }
+        };
+        log::info!("{}: accepted connection from {}", self.addr, from);
+        if let Err(e) = self.run(from.clone(), stream, false) {
+            log::warn!("{}: could not register peer {}: {}", self.addr, from, e);
+        }
+    }
+
+    fn dial(&self, to: &NetAddr) -> io::Result<()> {
+        let addr = to
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} does not resolve", to)))?;
+        let mut stream = TcpStream::connect_timeout(&addr, DIAL_TIMEOUT)?;
+        stream.set_nodelay(true)?;
+        write_frame(&mut stream, self.addr.as_bytes())?;
+        log::info!("{}: connected to {}", self.addr, to);
+        self.run(to.clone(), stream, true)
+    }
+
+    // Registers the connection and spawns its reader thread.
+    fn run(&self, from: NetAddr, stream: TcpStream, outbound: bool) -> io::Result<()> {
+        let mut reader = stream.try_clone()?;
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let peer = Peer { id, stream: Arc::new(Mutex::new(stream)) };
+        {
+            // Checked under the lock, so `close` sees every registered peer.
+            let mut peers = self.peers.write().unwrap();
+            if self.closed.load(Ordering::SeqCst) {
+                let _ = peer.stream.lock().unwrap().shutdown(Shutdown::Both);
+                return Err(io::Error::new(io::ErrorKind::NotConnected, "transport is closed"));
+            }
+            // The hello is taken on trust, so an inbound connection must not
+            // take over the address of a peer that is already connected.
+            if !outbound && peers.contains_key(&from) {
+                let _ = peer.stream.lock().unwrap().shutdown(Shutdown::Both);
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, "peer is already connected"));
+            }
+            peers.insert(from.clone(), peer);
+        }
+
+        let tr = self.clone();
+        self.spawn(move || {
+            let sender = tr.chan.sender();
+            loop {
+                match read_frame(&mut reader) {
+                    Ok(payload) => {
+                        // Nothing may drain the channel once the transport is
+                        // closing, so a blocking send could wait forever.
+                        let mut rpc = RPC { from: from.clone(), payload };
+                        loop {
+                            match sender.try_send(rpc) {
+                                Ok(()) => break,
+                                Err(TrySendError::Full(full)) if !tr.closed.load(Ordering::SeqCst) => {
+                                    rpc = full;
+                                    thread::sleep(POLL);
+                                }
+                                Err(_) => return,
+                            }
+                        }
+                    }
+                    Err(e) => {
+                        log::info!("{}: connection to {} closed: {}", tr.addr, from, e);
+                        break;
+                    }
+                }
+            }
+            tr.disconnected(&from, id, outbound);
+        });
+        Ok(())
+    }
+
+    fn disconnected(&self, addr: &NetAddr, id: u64, outbound: bool) {
+        {
+            let mut peers = self.peers.write().unwrap();
+            match peers.get(addr) {
+                Some(peer) if peer.id == id => {
+                    peers.remove(addr);
+                }
+                _ => return,
+            }
+        }
+        if outbound {
+            self.reconnect(addr.clone());
+        }
+    }
+
+    fn reconnect(&self, addr: NetAddr) {
+        let tr = self.clone();
+        self.spawn(move || {
+            let mut backoff = MIN_BACKOFF;
+            loop {
+                // Jittered, so two peers redialing each other do not keep
+                // refusing each other's connection.
+                if !tr.sleep(backoff + MIN_BACKOFF.mul_f64(rand::random())) || tr.has_peer(&addr) {
+                    return;
+                }
+                match tr.dial(&addr) {
+                    Ok(()) => return,
+                    Err(e) => {
+                        log::debug!("{}: could not reconnect to {}: {}", tr.addr, addr, e);
+                        backoff = (backoff * 2).min(MAX_BACKOFF);
+                    }
+                }
+            }
+        });
+    }
+
+    // Sleeps for `duration`, returning false early if the transport is closed.
+    fn sleep(&self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+        while !self.closed.load(Ordering::SeqCst) {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return true;
+            }
+            thread::sleep(left.min(POLL));
+        }
+        false
+    }
+
+    pub fn has_peer(&self, addr: &NetAddr) -> bool {
+        let peers = self.peers.read().unwrap();
+        peers.contains_key(addr)
+    }
+
+    fn send(&self, to: &NetAddr, stream: &Mutex<TcpStream>, payload: &[u8]) -> Result<(), String> {
+        let mut stream = stream.lock().unwrap();
+        write_frame(&mut *stream, payload).map_err(|e| {
+            // The reader thread notices the broken connection and cleans up.
+            let _ = stream.shutdown(Shutdown::Both);
+            format!("could not send to {}: {}", to, e)
+        })
+    }
+}
+
+impl Transport for TcpTransport {
+    fn consume(&self) -> Arc<Mutex<Receiver<RPC>>> {
+        self.chan.receiver()
+    }
+
+    fn connect(&self, transport: TransportWrapper) -> Result<(), String> {
+        let addr = match transport {
+            TransportWrapper::Addr(addr) => addr,
+            TransportWrapper::Local(_) => return Err("tcp transport cannot connect to a local transport".to_owned()),
+        };
+        self.dial(&addr).map_err(|e| format!("could not connect to {}: {}", addr, e))
+    }
+
+    fn send_message(&self, to: NetAddr, payload: Vec<u8>) -> Result<(), String> {
+        let stream = match self.peers.read().unwrap().get(&to) {
+            Some(peer) => peer.stream.clone(),
+            None => return Err(format!("unknown peer {}", to)),
+        };
+        self.send(&to, &stream, &payload)
+    }
+
+    fn addr(&self) -> NetAddr {
+        self.addr.clone()
+    }
+
+    fn peers(&self) -> Vec<NetAddr> {
+        let peers = self.peers.read().unwrap();
+        peers.keys().cloned().collect()
+    }
+
+    fn disconnect(&self, addr: &NetAddr) {
+        // Once the peer is gone from the table, its reader thread does not
+        // redial it.
+        if let Some(peer) = self.peers.write().unwrap().remove(addr) {
+            let _ = peer.stream.lock().unwrap().shutdown(Shutdown::Both);
+        }
+    }
+
+    fn close(&self) {
+        {
+            let mut peers = self.peers.write().unwrap();
+            self.closed.store(true, Ordering::SeqCst);
+            for (_, peer) in peers.drain() {
+                let _ = peer.stream.lock().unwrap().shutdown(Shutdown::Both);
+            }
+        }
+        // Exiting readers may still start a reconnect, which is joined in a
+        // later round.
+        loop {
+            let threads = std::mem::take(&mut *self.threads.lock().unwrap());
+            if threads.is_empty() {
+                break;
+            }
+            for thread in threads {
+                let _ = thread.join();
+            }
+        }
+    }
+
+    fn broadcast(&self, payload: Vec<u8>) -> Result<(), String> {
+        let peers: Vec<(NetAddr, Arc<Mutex<TcpStream>>)> = self
+            .peers
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(addr, peer)| (addr.clone(), peer.stream.clone()))
+            .collect();
+        for (addr, stream) in peers {
+            if let Err(e) = self.send(&addr, &stream, &payload) {
+                log::warn!("{}: {}", self.addr, e);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wait_for<F: Fn() -> bool>(f: F) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !f() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_frame_roundtrip() {
+        let mut buf = vec![];
+        assert!(write_frame(&mut buf, b"hello").is_ok());
+        assert_eq!(read_frame(&mut buf.as_slice()).unwrap(), b"hello");
+
+        let mut too_large = vec![];
+        too_large.write_u32::<BigEndian>(MAX_FRAME_LEN + 1).unwrap();
+        assert!(read_frame(&mut too_large.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_send_message() {
+        let tra = TcpTransport::listen("127.0.0.1:0").unwrap();
+        let trb = TcpTransport::listen("127.0.0.1:0").unwrap();
+
+        assert!(tra.connect(TransportWrapper::Addr(trb.addr())).is_ok());
+        assert!(tra.has_peer(&trb.addr()));
+        wait_for(|| trb.has_peer(&tra.addr()));
+
+        assert!(tra.send_message(trb.addr(), b"hello world".to_vec()).is_ok());
+        let rpc = trb.consume().lock().unwrap().recv().unwrap();
+        assert_eq!(rpc.from, tra.addr());
+        assert_eq!(rpc.payload, b"hello world");
+
+        // The connection carries messages both ways.
+        assert!(trb.broadcast(b"foo".to_vec()).is_ok());
+        let rpc = tra.consume().lock().unwrap().recv().unwrap();
+        assert_eq!(rpc.from, trb.addr());
+        assert_eq!(rpc.payload, b"foo");
+
+        assert!(tra.send_message("127.0.0.1:1".to_owned(), vec![]).is_err());
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let tra = TcpTransport::listen("127.0.0.1:0").unwrap();
+        let trb = TcpTransport::listen("127.0.0.1:0").unwrap();
+        assert!(tra.connect(TransportWrapper::Addr(trb.addr())).is_ok());
+        wait_for(|| trb.has_peer(&tra.addr()));
+
+        let old = tra.peers.read().unwrap().get(&trb.addr()).unwrap().id;
+        let stream = tra.peers.read().unwrap().get(&trb.addr()).unwrap().stream.clone();
+        stream.lock().unwrap().shutdown(Shutdown::Both).unwrap();
+
+        wait_for(|| matches!(tra.peers.read().unwrap().get(&trb.addr()), Some(peer) if peer.id != old));
+        assert!(tra.send_message(trb.addr(), b"again".to_vec()).is_ok());
+        let rpc = trb.consume().lock().unwrap().recv().unwrap();
+        assert_eq!(rpc.payload, b"again");
+
+        // A peer dropped on purpose is not redialed.
+        tra.disconnect(&trb.addr());
+        assert!(!tra.has_peer(&trb.addr()));
+        thread::sleep(MIN_BACKOFF * 3);
+        assert!(!tra.has_peer(&trb.addr()));
+    }
+
+    #[test]
+    fn test_hello_cannot_take_over_peer() {
+        let tra = TcpTransport::listen("127.0.0.1:0").unwrap();
+        let trb = TcpTransport::listen("127.0.0.1:0").unwrap();
+        assert!(tra.connect(TransportWrapper::Addr(trb.addr())).is_ok());
+        wait_for(|| trb.has_peer(&tra.addr()));
+        let id = trb.peers.read().unwrap().get(&tra.addr()).unwrap().id;
+
+        // A second connection claiming to be tra is refused.
+        let mut impostor = TcpStream::connect(trb.addr()).unwrap();
+        write_frame(&mut impostor, tra.addr().as_bytes()).unwrap();
+        impostor.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert!(matches!(impostor.read(&mut [0; 1]), Ok(0) | Err(_)));
+        assert_eq!(trb.peers.read().unwrap().get(&tra.addr()).unwrap().id, id);
+
+        assert!(trb.send_message(tra.addr(), b"still tra".to_vec()).is_ok());
+        let rpc = tra.consume().lock().unwrap().recv().unwrap();
+        assert_eq!(rpc.payload, b"still tra");
+    }
+
+    #[test]
+    fn test_bad_hello() {
+        let tr = TcpTransport::listen("127.0.0.1:0").unwrap();
+
+        // A hello larger than an address is refused before it is read.
+        let mut stream = TcpStream::connect(tr.addr()).unwrap();
+        stream.write_u32::<BigEndian>(MAX_HELLO_LEN + 1).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+        assert!(tr.peers().is_empty());
+
+        // A connection that never says hello holds up close for at most the
+        // hello timeout.
+        let _silent = TcpStream::connect(tr.addr()).unwrap();
+        thread::sleep(POLL * 2);
+        let start = Instant::now();
+        tr.close();
+        assert!(start.elapsed() < HELLO_TIMEOUT + POLL * 4);
+    }
+
+    #[test]
+    fn test_close_with_full_channel() {
+        let tra = TcpTransport::listen("127.0.0.1:0").unwrap();
+        let trb = TcpTransport::listen("127.0.0.1:0").unwrap();
+        assert!(tra.connect(TransportWrapper::Addr(trb.addr())).is_ok());
+        wait_for(|| trb.has_peer(&tra.addr()));
+
+        // Nobody consumes trb's messages, so its reader ends up waiting on a
+        // full channel.
+        for _ in 0..RPC_BUFFER + 8 {
+            assert!(tra.send_message(trb.addr(), vec![0]).is_ok());
+        }
+        thread::sleep(POLL * 4);
+
+        let (done, closed) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            trb.close();
+            let _ = done.send(());
+        });
+        assert!(closed.recv_timeout(Duration::from_secs(5)).is_ok());
+
+        // tra redials trb in the background until it is closed too.
+        let start = Instant::now();
+        tra.close();
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_close() {
+        let tra = TcpTransport::listen("127.0.0.1:0").unwrap();
+        let trb = TcpTransport::listen("127.0.0.1:0").unwrap();
+        assert!(tra.connect(TransportWrapper::Addr(trb.addr())).is_ok());
+        wait_for(|| trb.has_peer(&tra.addr()));
+
+        // Closing drops the peers, without redialing them, and frees the port.
+        trb.close();
+        assert!(trb.peers().is_empty());
+        assert!(TcpListener::bind(trb.addr()).is_ok());
+        wait_for(|| !tra.has_peer(&trb.addr()));
+        assert!(trb.connect(TransportWrapper::Addr(tra.addr())).is_err());
+        tra.close();
+    }
+}
@@ -12,14 +12,23 @@ pub struct RPC {
 
 pub trait Transport: Send + Sync {
     fn consume(&self) -> Arc<Mutex<Receiver<RPC>>>;
-    fn connect(&mut self, transport: TransportWrapper) -> Result<(), String>;
+    fn connect(&self, transport: TransportWrapper) -> Result<(), String>;
     fn send_message(&self, addr: NetAddr, payload: Vec<u8>) -> Result<(), String>;
     fn broadcast(&self, payload: Vec<u8>) -> Result<(), String>;
     fn addr(&self) -> NetAddr;
+    // Addresses of the connected peers.
+    fn peers(&self) -> Vec<NetAddr>;
+    // Drops the connection to a peer, without redialing it.
+    fn disconnect(&self, addr: &NetAddr);
+    // Stops accepting connections and drops every peer. Returns once the
+    // transport's threads have exited.
+    fn close(&self);
     // fn as_any(&self) -> &dyn Any;
     
 }
 
 pub enum TransportWrapper<'a> {
     Local(&'a LocalTransport),
+    // Remote peer reachable at the given address.
+    Addr(NetAddr),
 }
\ No newline at end of file
@@ -1,40 +1,268 @@
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{btree_map, BTreeMap, BinaryHeap, HashMap, HashSet};
+use std::fmt;
 use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use crate::core::hasher::Hasher;
+use crate::core::events::{ChainEvent, EventBus};
+use crate::core::state::Account;
 use crate::core::{transaction::Transaction};
-use crate::types::hash::Hash;
-use rand::Rng;
+use crate::core::validator::MAX_BLOCK_GAS;
+use crate::types::{address::Address, hash::Hash};
+
+pub const DEFAULT_MAX_SIZE: usize = 4096;
+pub const DEFAULT_MAX_PER_SENDER: usize = 64;
+// A transaction replacing a pooled one with the same nonce must raise the gas
+// price by at least this much.
+pub const PRICE_BUMP_PERCENT: u64 = 10;
+// Transactions are dropped once they have waited this long in the pool.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(3 * 60 * 60);
 
 type TxMap = HashMap<Hash, Transaction>;
-pub struct TxMapSorter {
-    transactions: Vec<Transaction>
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TxPoolError {
+    AlreadyKnown(Hash),
+    MissingSender,
+    InvalidChainId { expected: u32, got: u32 },
+    GasLimitTooHigh { max: u64, got: u64 },
+    IntrinsicGas { limit: u64, required: u64 },
+    InsufficientBalance { address: Address, balance: u64, cost: u64 },
+    NonceTooLow { address: Address, expected: u64, got: u64 },
+    NonceTooHigh { address: Address, max: u64, got: u64 },
+    ReplacementUnderpriced { address: Address, nonce: u64, min_gas_price: u64 },
+    SenderLimit { address: Address, limit: usize },
+    PoolFull { limit: usize, min_gas_price: u64 },
 }
 
-impl TxMapSorter {
-    fn new(map: &TxMap) -> Self {
-        let mut txs = Vec::with_capacity(map.len());
-        for (_, tx) in map {
-            txs.push(tx.clone());
+impl fmt::Display for TxPoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TxPoolError::AlreadyKnown(h) => write!(f, "transaction {} is already in the pool", h),
+            TxPoolError::MissingSender => write!(f, "transaction is not signed"),
+            TxPoolError::InvalidChainId { expected, got } => {
+                write!(f, "transaction for chain {}, expected chain {}", got, expected)
+            }
+            TxPoolError::GasLimitTooHigh { max, got } => {
+                write!(f, "gas limit {} exceeds the block gas limit {}", got, max)
+            }
+            TxPoolError::IntrinsicGas { limit, required } => {
+                write!(f, "gas limit {} is below the intrinsic gas {}", limit, required)
+            }
+            TxPoolError::InsufficientBalance { address, balance, cost } => {
+                write!(f, "transaction costs up to {}, but {} has a balance of {}", cost, address, balance)
+            }
+            TxPoolError::NonceTooLow { address, expected, got } => {
+                write!(f, "nonce {} for {} was already used, next nonce is {}", got, address, expected)
+            }
+            TxPoolError::NonceTooHigh { address, max, got } => {
+                write!(f, "nonce {} for {} is too far ahead, highest accepted nonce is {}", got, address, max)
+            }
+            TxPoolError::ReplacementUnderpriced { address, nonce, min_gas_price } => {
+                write!(
+                    f,
+                    "replacing the transaction from {} with nonce {} needs a gas price of at least {}",
+                    address, nonce, min_gas_price
+                )
+            }
+            TxPoolError::SenderLimit { address, limit } => {
+                write!(f, "pool already holds {} transactions from {}", limit, address)
+            }
+            TxPoolError::PoolFull { limit, min_gas_price } => {
+                write!(f, "pool is full with {} transactions, gas price must exceed {}", limit, min_gas_price)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TxPoolError {}
+
+// Block builders prefer higher gas prices, then transactions seen earlier.
+type Priority = (u64, Reverse<i64>, Reverse<Address>);
+
+fn priority(tx: &Transaction, address: Address) -> Priority {
+    (tx.gas_price, Reverse(tx.seen.unwrap_or(i64::MAX)), Reverse(address))
+}
+
+// Lowest gas price that may replace a transaction paying `gas_price`.
+fn replacement_price(gas_price: u64) -> u64 {
+    let bump = (gas_price as u128 * PRICE_BUMP_PERCENT as u128 / 100) as u64;
+    gas_price.saturating_add(bump.max(1))
+}
+
+// Pooled transactions of one sender. Those with consecutive nonces from the
+// account nonce on are pending and can go into the next block, the ones
+// after a nonce gap are queued until the missing nonces arrive.
+#[derive(Default)]
+struct SenderTxs {
+    // Account nonce on chain, as far as the pool knows.
+    nonce: u64,
+    txs: BTreeMap<u64, Hash>,
+}
+
+impl SenderTxs {
+    // Nonce after the last pending transaction.
+    fn pending_end(&self) -> u64 {
+        let mut end = self.nonce;
+        while self.txs.contains_key(&end) {
+            end += 1;
+        }
+        end
+    }
+
+    fn pending(&self) -> btree_map::Range<'_, u64, Hash> {
+        self.txs.range(self.nonce..self.pending_end())
+    }
+
+    // Moves the account nonce, dropping the transactions it leaves behind.
+    fn set_nonce(&mut self, nonce: u64, transactions: &mut TxMap) {
+        self.nonce = nonce;
+        let rest = self.txs.split_off(&nonce);
+        for hash in std::mem::replace(&mut self.txs, rest).values() {
+            transactions.remove(hash);
         }
-        txs.sort_by(|a, b| a.seen.cmp(&b.seen));
-        TxMapSorter { transactions: txs }
     }
 }
 
 pub struct TxPool {
     transactions: Arc<RwLock<TxMap>>,
+    senders: Arc<RwLock<HashMap<Address, SenderTxs>>>,
+    chain_id: u32,
+    max_size: usize,
+    max_per_sender: usize,
+    ttl: Duration,
+    events: EventBus,
 }
 
 impl TxPool {
-    pub fn new() -> TxPool {
+    pub fn new(chain_id: u32) -> TxPool {
+        TxPool::with_capacity(chain_id, DEFAULT_MAX_SIZE, DEFAULT_MAX_PER_SENDER)
+    }
+
+    /// Pool holding at most `max_size` transactions, and at most
+    /// `max_per_sender` from a single sender.
+    pub fn with_capacity(chain_id: u32, max_size: usize, max_per_sender: usize) -> TxPool {
         TxPool {
             transactions: Arc::new(RwLock::new(TxMap::new())),
+            senders: Arc::new(RwLock::new(HashMap::new())),
+            chain_id,
+            max_size,
+            max_per_sender,
+            ttl: DEFAULT_TTL,
+            events: EventBus::new(),
         }
     }
 
-    pub fn add(&mut self, mut tx: Transaction) -> Result<(), ()> {
-        let mut transactions = self.transactions.write().unwrap();
+    // Bus that admitted transactions are published to.
+    pub fn set_event_bus(&mut self, events: EventBus) {
+        self.events = events;
+    }
+
+    /// Admits a signed transaction. `account` is the sender's account on
+    /// chain, which has to cover the full gas limit and the amount
+    /// transferred. The gas limit must fit into a block. A transaction that follows the sender's pending ones
+    /// becomes pending itself, one after a nonce gap is queued until the gap
+    /// is filled. A transaction with the nonce of a pooled one replaces it if
+    /// it pays at least `PRICE_BUMP_PERCENT` more gas. If the pool is full,
+    /// the cheapest transaction that ends a sender's sequence is evicted to
+    /// make room, preferring queued ones.
+    pub fn add(&mut self, mut tx: Transaction, account: Account) -> Result<(), TxPoolError> {
+        if tx.chain_id != self.chain_id {
+            return Err(TxPoolError::InvalidChainId { expected: self.chain_id, got: tx.chain_id });
+        }
+        let address = tx.sender().ok_or(TxPoolError::MissingSender)?;
+        if tx.gas_limit > MAX_BLOCK_GAS {
+            return Err(TxPoolError::GasLimitTooHigh { max: MAX_BLOCK_GAS, got: tx.gas_limit });
+        }
+        let required = tx.intrinsic_gas();
+        if tx.gas_limit < required {
+            return Err(TxPoolError::IntrinsicGas { limit: tx.gas_limit, required });
+        }
+        let amount = tx.transfer.map_or(0, |transfer| transfer.amount);
+        let cost = tx.gas_limit.checked_mul(tx.gas_price).and_then(|fee| fee.checked_add(amount));
+        match cost {
+            Some(cost) if cost <= account.balance => {}
+            _ => {
+                let cost = cost.unwrap_or(u64::MAX);
+                return Err(TxPoolError::InsufficientBalance { address, balance: account.balance, cost });
+            }
+        }
+        let account_nonce = account.nonce;
         let hash = tx.hash(Hasher::new());
+
+        let mut transactions = self.transactions.write().unwrap();
+        let mut senders = self.senders.write().unwrap();
+        if transactions.contains_key(&hash) {
+            return Err(TxPoolError::AlreadyKnown(hash));
+        }
+        if tx.nonce < account_nonce {
+            return Err(TxPoolError::NonceTooLow { address, expected: account_nonce, got: tx.nonce });
+        }
+
+        // Pooled transactions below the account nonce were included in a
+        // block meanwhile.
+        if let Some(sender) = senders.get_mut(&address) {
+            sender.set_nonce(account_nonce, &mut transactions);
+        }
+        let sender = senders.get(&address);
+
+        if let Some(replaced) = sender.and_then(|sender| sender.txs.get(&tx.nonce)).copied() {
+            let min_gas_price = replacement_price(transactions[&replaced].gas_price);
+            if tx.gas_price < min_gas_price {
+                return Err(TxPoolError::ReplacementUnderpriced { address, nonce: tx.nonce, min_gas_price });
+            }
+            log::debug!("transaction {} replaces {}", hash, replaced);
+            transactions.remove(&replaced);
+            senders.get_mut(&address).unwrap().txs.insert(tx.nonce, hash);
+            self.events.publish(ChainEvent::PendingTransaction(Box::new(tx.clone())));
+            transactions.insert(hash, tx);
+            return Ok(());
+        }
+
+        if sender.map_or(0, |sender| sender.txs.len()) >= self.max_per_sender {
+            return Err(TxPoolError::SenderLimit { address, limit: self.max_per_sender });
+        }
+        // Nonces further ahead could not be filled in within the sender limit.
+        let max = account_nonce.saturating_add(self.max_per_sender as u64 - 1);
+        if tx.nonce > max {
+            return Err(TxPoolError::NonceTooHigh { address, max, got: tx.nonce });
+        }
+
+        if transactions.len() >= self.max_size {
+            let pending = tx.nonce == sender.map_or(account_nonce, |sender| sender.pending_end());
+            // Only the last transaction of a sender can go without leaving a
+            // nonce gap behind.
+            let cheapest = senders
+                .iter()
+                .filter_map(|(address, sender)| {
+                    let (nonce, hash) = sender.txs.iter().next_back()?;
+                    let queued = *nonce >= sender.pending_end();
+                    Some((*address, *nonce, *hash, queued))
+                })
+                .min_by_key(|(address, _, hash, queued)| (!queued, priority(&transactions[hash], *address)));
+            match cheapest {
+                Some((evicted, nonce, evicted_hash, queued))
+                    if (queued && pending) || transactions[&evicted_hash].gas_price < tx.gas_price =>
+                {
+                    log::debug!("evicting transaction {} from the full pool", evicted_hash);
+                    transactions.remove(&evicted_hash);
+                    let sender = senders.get_mut(&evicted).unwrap();
+                    sender.txs.remove(&nonce);
+                    if sender.txs.is_empty() {
+                        senders.remove(&evicted);
+                    }
+                }
+                _ => {
+                    let min_gas_price = cheapest.map_or(0, |(_, _, hash, _)| transactions[&hash].gas_price);
+                    return Err(TxPoolError::PoolFull { limit: self.max_size, min_gas_price });
+                }
+            }
+        }
+
+        let sender = senders.entry(address).or_default();
+        sender.nonce = account_nonce;
+        sender.txs.insert(tx.nonce, hash);
+        self.events.publish(ChainEvent::PendingTransaction(Box::new(tx.clone())));
         transactions.insert(hash, tx);
         Ok(())
     }
@@ -44,47 +272,216 @@ impl TxPool {
         transactions.contains_key(hash)
     }
 
+    pub fn get(&self, hash: &Hash) -> Option<Transaction> {
+        let transactions = self.transactions.read().unwrap();
+        transactions.get(hash).cloned()
+    }
+
     pub fn len(&self) -> usize {
         let transactions = self.transactions.read().unwrap();
         transactions.len()
     }
 
+    // Number of transactions that can go into the next block.
+    pub fn pending_len(&self) -> usize {
+        let senders = self.senders.read().unwrap();
+        senders.values().map(|sender| sender.pending().count()).sum()
+    }
+
+    // Number of transactions waiting for a missing nonce.
+    pub fn queued_len(&self) -> usize {
+        self.len() - self.pending_len()
+    }
+
     pub fn flush(&mut self) -> Result<(), ()> {
         let mut transactions = self.transactions.write().unwrap();
+        let mut senders = self.senders.write().unwrap();
 
         transactions.clear();
+        senders.clear();
         Ok(())
     }
 
-    fn get_transactions(&self) -> Vec<Transaction> {
+    /// Drops transactions first seen more than the TTL before `now`, in
+    /// nanoseconds, together with the later transactions of their senders,
+    /// which cannot run without them. Returns the number of dropped
+    /// transactions.
+    pub fn prune_expired(&mut self, now: i64) -> usize {
+        let mut transactions = self.transactions.write().unwrap();
+        let mut senders = self.senders.write().unwrap();
+        let deadline = now.saturating_sub(self.ttl.as_nanos() as i64);
+
+        let mut pruned = 0;
+        senders.retain(|_, sender| {
+            let expired = sender
+                .txs
+                .iter()
+                .find(|(_, hash)| matches!(transactions[*hash].seen, Some(seen) if seen < deadline))
+                .map(|(nonce, _)| *nonce);
+            if let Some(nonce) = expired {
+                for (_, hash) in sender.txs.split_off(&nonce) {
+                    transactions.remove(&hash);
+                    pruned += 1;
+                }
+            }
+            !sender.txs.is_empty()
+        });
+        pruned
+    }
+
+    /// Puts back transactions whose blocks were rolled back by a reorg. The
+    /// pooled transactions of the same senders are taken out and added again
+    /// after them, so their nonces stay consecutive. Transactions that no
+    /// longer fit, e.g. because the new branch included them, are dropped.
+    /// Returns how many transactions the pool gained.
+    pub fn reinject<F>(&mut self, mut txs: Vec<Transaction>, account: F) -> usize
+    where
+        F: Fn(&Address) -> Account,
+    {
+        let before = self.len();
+        {
+            let mut transactions = self.transactions.write().unwrap();
+            let mut senders = self.senders.write().unwrap();
+            let addresses: HashSet<Address> = txs.iter().filter_map(|tx| tx.sender()).collect();
+            for address in addresses {
+                if let Some(sender) = senders.remove(&address) {
+                    txs.extend(sender.txs.values().filter_map(|hash| transactions.remove(hash)));
+                }
+            }
+        }
+        // The sort is stable, so a rolled back transaction wins over a pooled
+        // one with the same nonce.
+        txs.sort_by_key(|tx| (tx.sender(), tx.nonce));
+
+        for tx in txs {
+            let sender = tx.sender().map(|address| account(&address)).unwrap_or_default();
+            if let Err(e) = self.add(tx, sender) {
+                log::debug!("not adding transaction back to the pool: {}", e);
+            }
+        }
+        self.len().saturating_sub(before)
+    }
+
+    /// Removes the given transactions once they are included in a block.
+    /// Their senders' account nonces move past them, which drops pooled
+    /// transactions reusing those nonces and promotes queued ones that now
+    /// follow on.
+    pub fn remove_transactions(&mut self, txs: &[Transaction]) {
+        let mut transactions = self.transactions.write().unwrap();
+        let mut senders = self.senders.write().unwrap();
+        let hasher = Hasher::new();
+        for tx in txs {
+            let hash = hasher.hash(tx).expect("could not hash");
+            transactions.remove(&hash);
+            if let Some(address) = tx.sender() {
+                if let Some(sender) = senders.get_mut(&address) {
+                    if sender.txs.get(&tx.nonce) == Some(&hash) {
+                        sender.txs.remove(&tx.nonce);
+                    }
+                    if tx.nonce >= sender.nonce {
+                        sender.set_nonce(tx.nonce + 1, &mut transactions);
+                    }
+                    if sender.txs.is_empty() {
+                        senders.remove(&address);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Up to `n` pending transactions in priority order. Transactions of a
+    /// sender always come in nonce order. The transactions stay in the pool.
+    pub fn take(&self, n: usize) -> Vec<Transaction> {
+        self.select(|txs, _| txs.len() < n)
+    }
+
+    /// Pending transactions in priority order whose gas limits add up to at
+    /// most `limit`. The transactions stay in the pool.
+    pub fn take_by_gas(&self, limit: u64) -> Vec<Transaction> {
+        let mut gas: u64 = 0;
+        self.select(|_, tx| match gas.checked_add(tx.gas_limit) {
+            Some(total) if total <= limit => {
+                gas = total;
+                true
+            }
+            _ => false,
+        })
+    }
+
+    // Walks the pending transactions in priority order. A transaction is
+    // selected if `accept` returns true, otherwise the remaining transactions
+    // of its sender are skipped, since they cannot run without it.
+    fn select<F: FnMut(&[Transaction], &Transaction) -> bool>(&self, mut accept: F) -> Vec<Transaction> {
         let transactions = self.transactions.read().unwrap();
-        let sorter = TxMapSorter::new(&transactions);
-        return sorter.transactions;
+        let senders = self.senders.read().unwrap();
+
+        // Every sender has at most one transaction in the heap, so the
+        // priority identifies it through the sender.
+        let mut queues: HashMap<Address, btree_map::Range<u64, Hash>> =
+            senders.iter().map(|(address, sender)| (*address, sender.pending())).collect();
+        let mut heads = HashMap::new();
+        let mut heap = BinaryHeap::new();
+        for (address, queue) in queues.iter_mut() {
+            if let Some((_, hash)) = queue.next() {
+                heads.insert(*address, hash);
+                heap.push(priority(&transactions[hash], *address));
+            }
+        }
+
+        let mut selected = vec![];
+        while let Some((_, _, Reverse(address))) = heap.pop() {
+            let tx = &transactions[heads[&address]];
+            if !accept(&selected, tx) {
+                continue;
+            }
+            selected.push(tx.clone());
+            if let Some((_, next)) = queues.get_mut(&address).and_then(|queue| queue.next()) {
+                heads.insert(address, next);
+                heap.push(priority(&transactions[next], address));
+            }
+        }
+        selected
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::core::transaction::{DEFAULT_CHAIN_ID, DEFAULT_GAS_LIMIT};
+    use crate::crypto::keypair::PrivateKey;
+
     use super::*;
 
+    // Account with the given nonce and funds for any transaction.
+    fn account(nonce: u64) -> Account {
+        Account { balance: u64::MAX, nonce }
+    }
+
+    fn signed_tx(key: &PrivateKey, data: &[u8], nonce: u64) -> Transaction {
+        let mut tx = Transaction::new(data.to_vec()).unwrap();
+        tx.nonce = nonce;
+        assert!(tx.sign(key).is_ok());
+        tx
+    }
+
     #[test]
     fn test_tx_pool() {
-        let p = TxPool::new();
+        let p = TxPool::new(DEFAULT_CHAIN_ID);
         assert_eq!(p.len(), 0);
     }
 
     #[test]
     fn test_tx_pool_add_tx() {
-        let mut p = TxPool::new();
-        let tx = Transaction::new(b"fooo".to_vec()).unwrap();
-        assert!(p.add(tx).is_ok());
+        let mut p = TxPool::new(DEFAULT_CHAIN_ID);
+        let key = PrivateKey::generate_key();
+        let tx = signed_tx(&key, b"fooo", 0);
+        assert!(p.add(tx.clone(), account(0)).is_ok());
         assert_eq!(p.len(), 1);
 
-        let _ = Transaction::new(b"fooo".to_vec());
+        assert!(matches!(p.add(tx, account(0)), Err(TxPoolError::AlreadyKnown(_))));
         assert_eq!(p.len(), 1);
 
-        let tx = Transaction::new(b"sway".to_vec()).unwrap();
-        assert!(p.add(tx).is_ok());
+        let tx = signed_tx(&key, b"sway", 1);
+        assert!(p.add(tx, account(0)).is_ok());
         assert_eq!(p.len(), 2);
 
         p.flush();
@@ -92,27 +489,273 @@ mod tests {
     }
 
     #[test]
-fn test_sort_transactions() {
-    let mut p = TxPool::new();
-    let tx_len = 1000;
-    let mut rng = rand::thread_rng();
+    fn test_tx_pool_rejects_replays() {
+        let mut p = TxPool::new(DEFAULT_CHAIN_ID);
+        let key = PrivateKey::generate_key();
+        assert!(p.add(signed_tx(&key, b"foo", 3), account(3)).is_ok());
 
+        assert!(matches!(
+            p.add(signed_tx(&key, b"bar", 2), account(3)),
+            Err(TxPoolError::NonceTooLow { expected: 3, got: 2, .. })
+        ));
+        assert!(matches!(
+            p.add(signed_tx(&key, b"bar", 3), account(3)),
+            Err(TxPoolError::ReplacementUnderpriced { nonce: 3, min_gas_price: 1, .. })
+        ));
+        let max = 3 + DEFAULT_MAX_PER_SENDER as u64 - 1;
+        assert!(matches!(
+            p.add(signed_tx(&key, b"bar", max + 1), account(3)),
+            Err(TxPoolError::NonceTooHigh { got, .. }) if got == max + 1
+        ));
 
-    for i in 0..tx_len {
-        let mut tx = Transaction::new(i.to_string().as_bytes().to_vec());
-        assert!(tx.is_ok());
-        let mut tx = tx.unwrap();
-        tx.set_seen(rng.gen::<i64>());
-        assert!(p.add(tx).is_ok());
+        let mut other_chain = Transaction::new(b"bar".to_vec()).unwrap();
+        other_chain.nonce = 4;
+        other_chain.chain_id = DEFAULT_CHAIN_ID + 1;
+        assert!(other_chain.sign(&key).is_ok());
+        assert!(matches!(p.add(other_chain, account(3)), Err(TxPoolError::InvalidChainId { .. })));
+
+        let unsigned = Transaction::new(b"bar".to_vec()).unwrap();
+        assert_eq!(p.add(unsigned, account(0)), Err(TxPoolError::MissingSender));
+
+        // Once a pooled transaction is removed, e.g. after it was included
+        // in a block, the sender continues from its account nonce.
+        let included = p.take(p.len());
+        p.remove_transactions(&included);
+        assert!(p.add(signed_tx(&key, b"bar", 4), account(4)).is_ok());
+        assert_eq!(p.len(), 1);
     }
 
-    assert_eq!(tx_len, p.len());
+    #[test]
+    fn test_tx_pool_rejects_unaffordable() {
+        let mut p = TxPool::new(DEFAULT_CHAIN_ID);
+        let key = PrivateKey::generate_key();
+
+        let mut greedy = priced_tx(&key, 0, 1);
+        greedy.gas_limit = u64::MAX;
+        assert!(greedy.sign(&key).is_ok());
+        assert!(matches!(p.add(greedy, account(0)), Err(TxPoolError::GasLimitTooHigh { .. })));
+
+        let mut starved = signed_tx(&key, b"data", 0);
+        starved.gas_limit = 1;
+        assert!(starved.sign(&key).is_ok());
+        assert!(matches!(p.add(starved, account(0)), Err(TxPoolError::IntrinsicGas { limit: 1, .. })));
 
-    let txx = p.get_transactions();
-    println!("{:?}", txx);
-    for i in 0..txx.len()-1 {
-        assert!(txx[i].seen() < txx[i+1].seen());
+        // The sender has to cover the whole gas limit, however the price
+        // overflows.
+        let poor = Account { balance: DEFAULT_GAS_LIMIT * 10 - 1, nonce: 0 };
+        assert!(matches!(
+            p.add(priced_tx(&key, 0, 10), poor),
+            Err(TxPoolError::InsufficientBalance { cost, .. }) if cost == DEFAULT_GAS_LIMIT * 10
+        ));
+        assert!(matches!(
+            p.add(priced_tx(&key, 0, u64::MAX), poor),
+            Err(TxPoolError::InsufficientBalance { cost: u64::MAX, .. })
+        ));
+        assert!(p.add(priced_tx(&key, 0, 9), poor).is_ok());
+        assert_eq!(p.len(), 1);
+    }
+
+    #[test]
+    fn test_pending_and_queued() {
+        let mut p = TxPool::new(DEFAULT_CHAIN_ID);
+        let key = PrivateKey::generate_key();
+        assert!(p.add(signed_tx(&key, b"a2", 2), account(0)).is_ok());
+        assert!(p.add(signed_tx(&key, b"a3", 3), account(0)).is_ok());
+        assert!(p.add(signed_tx(&key, b"a0", 0), account(0)).is_ok());
+        assert_eq!((p.pending_len(), p.queued_len()), (1, 2));
+
+        // Block producers only see the transactions that can run.
+        assert_eq!(p.take(4).iter().map(|tx| tx.nonce).collect::<Vec<_>>(), vec![0]);
+
+        // The missing nonce promotes the queued transactions.
+        assert!(p.add(signed_tx(&key, b"a1", 1), account(0)).is_ok());
+        assert_eq!((p.pending_len(), p.queued_len()), (4, 0));
+        assert_eq!(p.take(4).iter().map(|tx| tx.nonce).collect::<Vec<_>>(), vec![0, 1, 2, 3]);
+
+        // A block with transactions the pool has not seen moves the account
+        // nonce too.
+        let other = PrivateKey::generate_key();
+        assert!(p.add(signed_tx(&other, b"b1", 1), account(0)).is_ok());
+        assert_eq!(p.queued_len(), 1);
+        p.remove_transactions(&[signed_tx(&other, b"b0", 0)]);
+        assert_eq!(p.queued_len(), 0);
+        assert_eq!(p.pending_len(), 5);
+
+        // A block including a different transaction with a pooled nonce
+        // makes the pooled one stale.
+        p.remove_transactions(&[signed_tx(&key, b"other", 1)]);
+        assert_eq!(p.len(), 3);
+        assert_eq!(p.take(4).iter().map(|tx| tx.data.clone()).collect::<Vec<_>>().len(), 3);
+    }
+
+    #[test]
+    fn test_replace_by_fee() {
+        let mut p = TxPool::new(DEFAULT_CHAIN_ID);
+        let key = PrivateKey::generate_key();
+        let original = priced_tx(&key, 0, 100);
+        assert!(p.add(original.clone(), account(0)).is_ok());
+        assert!(p.add(priced_tx(&key, 2, 100), account(0)).is_ok());
+
+        assert!(matches!(
+            p.add(priced_tx(&key, 0, 109), account(0)),
+            Err(TxPoolError::ReplacementUnderpriced { nonce: 0, min_gas_price: 110, .. })
+        ));
+        assert!(p.add(priced_tx(&key, 0, 110), account(0)).is_ok());
+        assert_eq!(p.len(), 2);
+        assert!(!p.has(&Hasher::new().hash(&original).unwrap()));
+        assert_eq!(p.take(1)[0].gas_price, 110);
+
+        // Queued transactions can be replaced as well.
+        assert!(p.add(priced_tx(&key, 2, 200), account(0)).is_ok());
+        assert_eq!((p.pending_len(), p.queued_len()), (1, 1));
+
+        assert_eq!(replacement_price(u64::MAX / 5), u64::MAX / 5 + u64::MAX / 50);
+        assert_eq!(replacement_price(u64::MAX), u64::MAX);
+    }
+
+    #[test]
+    fn test_publishes_admitted() {
+        let mut p = TxPool::new(DEFAULT_CHAIN_ID);
+        let events = EventBus::new();
+        let sub = events.subscribe(8);
+        p.set_event_bus(events);
+        let key = PrivateKey::generate_key();
+
+        assert!(p.add(priced_tx(&key, 0, 100), account(0)).is_ok());
+        assert!(p.add(priced_tx(&key, 0, 100), account(0)).is_err());
+        assert!(p.add(priced_tx(&key, 0, 110), account(0)).is_ok());
+        let prices: Vec<u64> = sub
+            .try_iter()
+            .map(|event| match event {
+                ChainEvent::PendingTransaction(tx) => tx.gas_price,
+                other => panic!("unexpected event {:?}", other),
+            })
+            .collect();
+        assert_eq!(prices, vec![100, 110]);
+    }
+
+    fn priced_tx(key: &PrivateKey, nonce: u64, gas_price: u64) -> Transaction {
+        let mut tx = Transaction::new(vec![]).unwrap();
+        tx.nonce = nonce;
+        tx.gas_price = gas_price;
+        assert!(tx.sign(key).is_ok());
+        tx
     }
-}
-}
 
+    #[test]
+    fn test_take_by_priority() {
+        let mut p = TxPool::new(DEFAULT_CHAIN_ID);
+        let (alice, bob) = (PrivateKey::generate_key(), PrivateKey::generate_key());
+        assert!(p.add(priced_tx(&alice, 0, 1), account(0)).is_ok());
+        assert!(p.add(priced_tx(&alice, 1, 10), account(0)).is_ok());
+        assert!(p.add(priced_tx(&bob, 0, 5), account(0)).is_ok());
+        assert!(p.add(priced_tx(&bob, 1, 3), account(0)).is_ok());
+
+        // Alice's expensive transaction has to wait for her cheap one.
+        let prices: Vec<u64> = p.take(4).iter().map(|tx| tx.gas_price).collect();
+        assert_eq!(prices, vec![5, 3, 1, 10]);
+        assert_eq!(p.take(2).len(), 2);
+        assert_eq!(p.len(), 4);
+
+        // Skipping a transaction skips the rest of its sender too.
+        let prices: Vec<u64> = p
+            .take_by_gas(3 * DEFAULT_GAS_LIMIT)
+            .iter()
+            .map(|tx| tx.gas_price)
+            .collect();
+        assert_eq!(prices, vec![5, 3, 1]);
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut p = TxPool::with_capacity(DEFAULT_CHAIN_ID, 3, 2);
+        let (alice, bob, carol) = (PrivateKey::generate_key(), PrivateKey::generate_key(), PrivateKey::generate_key());
+        assert!(p.add(priced_tx(&alice, 0, 1), account(0)).is_ok());
+        assert!(p.add(priced_tx(&alice, 1, 4), account(0)).is_ok());
+        assert!(matches!(p.add(priced_tx(&alice, 2, 9), account(0)), Err(TxPoolError::SenderLimit { limit: 2, .. })));
+        assert!(p.add(priced_tx(&bob, 0, 2), account(0)).is_ok());
+
+        // Bob's transaction is the cheapest one that can go without leaving
+        // a nonce gap.
+        assert!(matches!(p.add(priced_tx(&carol, 0, 2), account(0)), Err(TxPoolError::PoolFull { min_gas_price: 2, .. })));
+        let evicted = priced_tx(&bob, 0, 2);
+        assert!(p.add(priced_tx(&carol, 0, 3), account(0)).is_ok());
+        assert_eq!(p.len(), 3);
+        assert!(!p.has(&Hasher::new().hash(&evicted).unwrap()));
+
+        let prices: Vec<u64> = p.take(3).iter().map(|tx| tx.gas_price).collect();
+        assert_eq!(prices, vec![3, 1, 4]);
+
+        // Queued transactions make room for pending ones first, whatever
+        // they pay.
+        p.remove_transactions(&[priced_tx(&alice, 0, 1)]);
+        let queued = priced_tx(&PrivateKey::generate_key(), 1, 50);
+        assert!(p.add(queued.clone(), account(0)).is_ok());
+        assert_eq!(p.queued_len(), 1);
+        assert!(p.add(priced_tx(&carol, 1, 1), account(0)).is_ok());
+        assert!(!p.has(&Hasher::new().hash(&queued).unwrap()));
+        assert_eq!((p.pending_len(), p.queued_len()), (3, 0));
+    }
+
+    #[test]
+    fn test_prune_expired() {
+        let mut p = TxPool::new(DEFAULT_CHAIN_ID);
+        p.ttl = Duration::from_nanos(100);
+        let (alice, bob) = (PrivateKey::generate_key(), PrivateKey::generate_key());
+        let seen = |mut tx: Transaction, seen: i64| {
+            tx.set_seen(seen);
+            tx
+        };
+        assert!(p.add(seen(signed_tx(&alice, b"a0", 0), 1_000), account(0)).is_ok());
+        assert!(p.add(seen(signed_tx(&alice, b"a1", 1), 1_050), account(0)).is_ok());
+        assert!(p.add(seen(signed_tx(&alice, b"a2", 2), 1_200), account(0)).is_ok());
+        assert!(p.add(seen(signed_tx(&bob, b"b0", 0), 1_150), account(0)).is_ok());
+
+        assert_eq!(p.prune_expired(1_100), 0);
+        // Alice's later transactions go with her expired first one.
+        assert_eq!(p.prune_expired(1_101), 3);
+        assert_eq!(p.len(), 1);
+        assert!(p.add(signed_tx(&alice, b"a0", 0), account(0)).is_ok());
+        assert_eq!(p.prune_expired(1_251), 1);
+        assert_eq!(p.len(), 1);
+    }
+
+    #[test]
+    fn test_reinject() {
+        let mut p = TxPool::new(DEFAULT_CHAIN_ID);
+        let (alice, bob) = (PrivateKey::generate_key(), PrivateKey::generate_key());
+        let (a0, a1, a2) = (signed_tx(&alice, b"a0", 0), signed_tx(&alice, b"a1", 1), signed_tx(&alice, b"a2", 2));
+
+        // a0 and a1 were included in a block, a2 waits on top of them.
+        assert!(p.add(a2.clone(), account(2)).is_ok());
+        assert!(p.add(signed_tx(&bob, b"b1", 1), account(1)).is_ok());
+
+        // The block is rolled back, so alice's nonce is 0 again.
+        let added = p.reinject(vec![a0.clone(), a1, signed_tx(&bob, b"b0", 0)], |address| {
+            if *address == bob.generate_public().address().unwrap() { account(1) } else { account(0) }
+        });
+        // b0 was included again on the new branch.
+        assert_eq!(added, 2);
+        assert_eq!(p.len(), 4);
+        let taken = p.take(4);
+        assert_eq!(taken.iter().filter(|tx| tx.sender() == a0.sender()).map(|tx| tx.nonce).collect::<Vec<_>>(), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn test_sort_transactions() {
+        let tx_len = 50;
+        let mut p = TxPool::with_capacity(DEFAULT_CHAIN_ID, tx_len + 1, 2);
+        for _ in 0..tx_len {
+            let tx = priced_tx(&PrivateKey::generate_key(), 0, rand::random::<u8>() as u64 + 1);
+            assert!(p.add(tx, account(0)).is_ok());
+        }
+        // Queued transactions are never taken, however well they pay.
+        assert!(p.add(priced_tx(&PrivateKey::generate_key(), 1, 1000), account(0)).is_ok());
+
+        let txx = p.take(p.len());
+        assert_eq!(txx.len(), tx_len);
+        for pair in txx.windows(2) {
+            assert!(pair[0].gas_price >= pair[1].gas_price);
+        }
+    }
+}
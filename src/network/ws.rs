@@ -0,0 +1,328 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, TryRecvError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::CloseFrame;
+use tungstenite::{Message, WebSocket};
+
+use crate::core::block::Block;
+use crate::core::events::{ChainEvent, EventBus};
+use crate::core::hasher::Hasher;
+
+use super::api::{answer, header_json, transaction_json, ApiError, Call};
+
+// Events buffered for a client before it is disconnected as too slow.
+const CLIENT_BUFFER: usize = 256;
+// How often clients are checked for requests and new events, and the
+// listener for new clients.
+const POLL: Duration = Duration::from_millis(50);
+// A client that does not take a notification within this time is dropped.
+const WRITE_TIMEOUT: Duration = Duration::from_secs(5);
+// Most subscriptions one client may hold at a time.
+const MAX_SUBSCRIPTIONS: usize = 32;
+// Most clients served at the same time, later ones are turned away.
+const MAX_CLIENTS: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Topic {
+    NewHeads,
+    PendingTransactions,
+    Reorgs,
+}
+
+impl Topic {
+    fn parse(name: &str) -> Option<Topic> {
+        match name {
+            "newHeads" => Some(Topic::NewHeads),
+            "pendingTransactions" => Some(Topic::PendingTransactions),
+            "reorgs" => Some(Topic::Reorgs),
+            _ => None,
+        }
+    }
+
+    // What subscribers of the topic are sent about `event`, if anything.
+    fn notification(&self, event: &ChainEvent) -> Option<Value> {
+        match (self, event) {
+            (Topic::NewHeads, ChainEvent::NewHead(header)) => Some(header_json(header)),
+            (Topic::PendingTransactions, ChainEvent::PendingTransaction(tx)) => Some(transaction_json(tx)),
+            (Topic::Reorgs, ChainEvent::Reorg(reorg)) => {
+                let hashes = |blocks: &[Block]| -> Vec<String> {
+                    blocks.iter().map(|b| b.clone().hash(Hasher::new()).to_string()).collect()
+                };
+                Some(json!({
+                    "commonAncestor": reorg.common_ancestor,
+                    "dropped": hashes(&reorg.dropped),
+                    "added": hashes(&reorg.added),
+                }))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// WebSocket endpoint streaming chain events. Clients send JSON-RPC calls
+/// `subscribe` with a topic (`newHeads`, `pendingTransactions` or `reorgs`),
+/// which returns a subscription id, and `unsubscribe` with that id. Events
+/// arrive as `subscription` notifications carrying the id and the result.
+pub struct WsServer {
+    listener: TcpListener,
+}
+
+impl WsServer {
+    pub fn bind(addr: &str) -> io::Result<WsServer> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+        Ok(WsServer { listener })
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serves clients, each on its own thread, until `stop` is set. Returns
+    /// once every client thread has exited.
+    pub fn run(&self, stop: &Arc<AtomicBool>, events: &EventBus) {
+        let mut clients: Vec<JoinHandle<()>> = vec![];
+        while !stop.load(Ordering::SeqCst) {
+            clients.retain(|client| !client.is_finished());
+            match self.listener.accept() {
+                Ok((_, addr)) if clients.len() >= MAX_CLIENTS => {
+                    log::warn!("turned away websocket client {}, too many clients", addr);
+                }
+                Ok((stream, addr)) => {
+                    let events = events.subscribe(CLIENT_BUFFER);
+                    let stop = stop.clone();
+                    clients.push(thread::spawn(move || {
+                        if let Err(e) = serve(stream, events, &stop) {
+                            log::warn!("dropped websocket client {}: {}", addr, e);
+                        }
+                    }));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(POLL),
+                Err(e) => {
+                    log::error!("websocket listener failed: {}", e);
+                    break;
+                }
+            }
+        }
+        for client in clients {
+            let _ = client.join();
+        }
+    }
+}
+
+fn serve(stream: TcpStream, events: Receiver<ChainEvent>, stop: &AtomicBool) -> Result<(), String> {
+    stream.set_nonblocking(false).map_err(|e| e.to_string())?;
+    // The handshake gets the same time as a write, only once it is done are
+    // reads cut short to poll for events.
+    stream.set_read_timeout(Some(WRITE_TIMEOUT)).map_err(|e| e.to_string())?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT)).map_err(|e| e.to_string())?;
+    let mut ws = tungstenite::accept(stream).map_err(|e| e.to_string())?;
+    ws.get_ref().set_read_timeout(Some(POLL)).map_err(|e| e.to_string())?;
+
+    let mut subscriptions: HashMap<u64, Topic> = HashMap::new();
+    let mut next_id = 1;
+    while !stop.load(Ordering::SeqCst) {
+        match ws.read() {
+            Ok(Message::Text(text)) => {
+                let response = answer(text.as_bytes(), &mut |call| call_subscription(&call, &mut subscriptions, &mut next_id));
+                ws.send(Message::text(response.to_string())).map_err(|e| e.to_string())?;
+            }
+            Ok(_) => (),
+            Err(tungstenite::Error::Io(e)) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => (),
+            Err(tungstenite::Error::ConnectionClosed) => return Ok(()),
+            Err(e) => return Err(e.to_string()),
+        }
+
+        loop {
+            let event = match events.try_recv() {
+                Ok(event) => event,
+                Err(TryRecvError::Empty) => break,
+                // The bus dropped us for not keeping up.
+                Err(TryRecvError::Disconnected) => {
+                    close(&mut ws, CloseCode::Policy, "too slow");
+                    return Err("client fell behind".to_owned());
+                }
+            };
+            for (id, topic) in &subscriptions {
+                if let Some(result) = topic.notification(&event) {
+                    let notification = json!({
+                        "jsonrpc": "2.0",
+                        "method": "subscription",
+                        "params": { "subscription": id, "result": result },
+                    });
+                    ws.send(Message::text(notification.to_string())).map_err(|e| e.to_string())?;
+                }
+            }
+        }
+    }
+    close(&mut ws, CloseCode::Away, "server shutting down");
+    Ok(())
+}
+
+fn call_subscription(call: &Call, subscriptions: &mut HashMap<u64, Topic>, next_id: &mut u64) -> Result<Value, ApiError> {
+    match call.method.as_str() {
+        "subscribe" => {
+            let name = call.str_param(0)?;
+            let topic = Topic::parse(name).ok_or_else(|| ApiError::InvalidParams(format!("unknown topic {}", name)))?;
+            if subscriptions.len() >= MAX_SUBSCRIPTIONS {
+                return Err(ApiError::Failed(format!("at most {} subscriptions per client", MAX_SUBSCRIPTIONS)));
+            }
+            let id = *next_id;
+            *next_id += 1;
+            subscriptions.insert(id, topic);
+            Ok(json!(id))
+        }
+        "unsubscribe" => Ok(json!(subscriptions.remove(&call.u64_param(0)?).is_some())),
+        _ => Err(ApiError::MethodNotFound(call.method.clone())),
+    }
+}
+
+fn close(ws: &mut WebSocket<TcpStream>, code: CloseCode, reason: &str) {
+    let _ = ws.close(Some(CloseFrame { code, reason: reason.into() }));
+    let _ = ws.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::core::transaction::Transaction;
+
+    use super::*;
+
+    fn start() -> (SocketAddr, EventBus, Arc<AtomicBool>, JoinHandle<()>) {
+        let server = WsServer::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let events = EventBus::new();
+        let handle = {
+            let (stop, events) = (stop.clone(), events.clone());
+            thread::spawn(move || server.run(&stop, &events))
+        };
+        (addr, events, stop, handle)
+    }
+
+    fn connect(addr: SocketAddr) -> WebSocket<TcpStream> {
+        let stream = TcpStream::connect(addr).unwrap();
+        tungstenite::client(format!("ws://{}", addr), stream).unwrap().0
+    }
+
+    fn call(ws: &mut WebSocket<TcpStream>, request: &str) -> Value {
+        ws.send(Message::text(request)).unwrap();
+        read_json(ws)
+    }
+
+    fn read_json(ws: &mut WebSocket<TcpStream>) -> Value {
+        loop {
+            if let Message::Text(text) = ws.read().unwrap() {
+                return serde_json::from_str(text.as_str()).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_subscriptions() {
+        let (addr, events, stop, handle) = start();
+        let mut ws = connect(addr);
+        let subscribe = r#"{"jsonrpc":"2.0","id":1,"method":"subscribe","params":["newHeads"]}"#;
+        assert_eq!(call(&mut ws, subscribe)["result"], json!(1));
+        let unknown = r#"{"jsonrpc":"2.0","id":2,"method":"subscribe","params":["blocks"]}"#;
+        assert_eq!(call(&mut ws, unknown)["error"]["code"], json!(-32602));
+
+        // Only events of subscribed topics are sent.
+        events.publish(ChainEvent::PendingTransaction(Box::new(Transaction::new(vec![]).unwrap())));
+        events.publish(ChainEvent::NewHead(Block::random_block(7).header));
+        let notification = read_json(&mut ws);
+        assert_eq!(notification["method"], json!("subscription"));
+        assert_eq!(notification["params"]["subscription"], json!(1));
+        assert_eq!(notification["params"]["result"]["height"], json!(7));
+
+        let unsubscribe = r#"{"jsonrpc":"2.0","id":3,"method":"unsubscribe","params":[1]}"#;
+        assert_eq!(call(&mut ws, unsubscribe)["result"], json!(true));
+
+        stop.store(true, Ordering::SeqCst);
+        handle.join().unwrap();
+        assert!(matches!(ws.read(), Ok(Message::Close(_))));
+    }
+
+    #[test]
+    fn test_limits() {
+        let (addr, _events, stop, handle) = start();
+
+        // The handshake is not held to the poll interval.
+        let stream = TcpStream::connect(addr).unwrap();
+        thread::sleep(POLL * 4);
+        let mut ws = tungstenite::client(format!("ws://{}", addr), stream).unwrap().0;
+
+        let subscribe = r#"{"jsonrpc":"2.0","id":1,"method":"subscribe","params":["newHeads"]}"#;
+        for _ in 0..MAX_SUBSCRIPTIONS {
+            assert!(call(&mut ws, subscribe)["result"].is_u64());
+        }
+        assert_eq!(call(&mut ws, subscribe)["error"]["code"], json!(-32000));
+        let unsubscribe = r#"{"jsonrpc":"2.0","id":2,"method":"unsubscribe","params":[1]}"#;
+        assert_eq!(call(&mut ws, unsubscribe)["result"], json!(true));
+        assert!(call(&mut ws, subscribe)["result"].is_u64());
+
+        stop.store(true, Ordering::SeqCst);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_max_clients() {
+        let (addr, _events, stop, handle) = start();
+        let mut clients: Vec<WebSocket<TcpStream>> = (0..MAX_CLIENTS).map(|_| connect(addr)).collect();
+
+        // One client too many is closed without a handshake.
+        let stream = TcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(WRITE_TIMEOUT)).unwrap();
+        assert!(tungstenite::client(format!("ws://{}", addr), stream).is_err());
+
+        // Once a client leaves, there is room again.
+        clients.pop().unwrap().close(None).unwrap();
+        let deadline = std::time::Instant::now() + WRITE_TIMEOUT;
+        let mut ws = loop {
+            let stream = TcpStream::connect(addr).unwrap();
+            match tungstenite::client(format!("ws://{}", addr), stream) {
+                Ok((ws, _)) => break ws,
+                Err(_) if std::time::Instant::now() < deadline => thread::sleep(POLL),
+                Err(e) => panic!("could not connect: {}", e),
+            }
+        };
+        let subscribe = r#"{"jsonrpc":"2.0","id":1,"method":"subscribe","params":["newHeads"]}"#;
+        assert_eq!(call(&mut ws, subscribe)["result"], json!(1));
+
+        stop.store(true, Ordering::SeqCst);
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_slow_client_is_dropped() {
+        let (addr, events, stop, handle) = start();
+        let mut ws = connect(addr);
+        let subscribe = r#"{"jsonrpc":"2.0","id":1,"method":"subscribe","params":["newHeads"]}"#;
+        assert_eq!(call(&mut ws, subscribe)["result"], json!(1));
+
+        // More events than the client buffer holds, published faster than
+        // the server forwards them.
+        for h in 0..CLIENT_BUFFER as u32 * 4 {
+            events.publish(ChainEvent::NewHead(Block::random_block(h).header));
+        }
+        let close = loop {
+            match ws.read() {
+                Ok(Message::Close(frame)) => break frame,
+                Ok(_) => (),
+                Err(e) => panic!("connection failed: {}", e),
+            }
+        };
+        assert_eq!(close.map(|frame| frame.code), Some(CloseCode::Policy));
+
+        stop.store(true, Ordering::SeqCst);
+        handle.join().unwrap();
+    }
+}
@@ -1,17 +1,13 @@
+use std::fmt;
 
+use serde_derive::{Deserialize, Serialize};
 
-#[derive(Clone, Copy, Eq, PartialEq)]
+#[derive(Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, Serialize, Deserialize)]
 pub struct Address([u8; 20]);
 
 impl Address {
-    pub fn to_string(&self) -> String {
-        hex::encode(self.0)
-    }
-
     pub fn from_bytes(b: &[u8]) -> Result<Self, String> {
-        println!("conv part");
         if b.len() != 20 {
-            println!("wrong len");
             return Err(format!("given bytes with length {} should be 20", b.len()));
         }
 
@@ -21,4 +17,16 @@ impl Address {
         Ok(Address(value))
     }
     
-}
\ No newline at end of file
+}
+
+impl fmt::Debug for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}